@@ -1,14 +1,19 @@
 use crate::{
-    arch::{mm::new_s2_memory_set, sysreg::write_sysreg},
+    arch::{
+        mm::new_s2_memory_set,
+        sysreg::{read_sysreg, write_sysreg},
+    },
     consts::{PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
     memory::{
-        addr::PHYS_VIRT_OFFSET, mm::PARKING_MEMORY_SET, GuestPhysAddr, HostPhysAddr, MemFlags,
-        MemoryRegion, VirtAddr, PARKING_INST_PAGE,
+        addr::PHYS_VIRT_OFFSET,
+        mm::{PARKING_MEMORY_SET, PARKING_VMID},
+        GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, VirtAddr, PARKING_INST_PAGE,
     },
     percpu::this_cpu_data,
 };
 use aarch64_cpu::registers::{
-    Readable, Writeable, ELR_EL2, HCR_EL2, MPIDR_EL1, SCTLR_EL1, SPSR_EL2, VTCR_EL2,
+    Readable, Writeable, CNTFRQ_EL0, CNTPCT_EL0, ELR_EL2, HCR_EL2, MPIDR_EL1, SCTLR_EL1, SPSR_EL2,
+    VTCR_EL2,
 };
 
 use super::{
@@ -85,6 +90,9 @@ impl ArchCpu {
                     },
                 ),
         );
+        // the guest sees its vcpu id in Aff0, keeping the U and MT bits
+        let vmpidr = (MPIDR_EL1.get() & !0xff00ffffff) | this_cpu_data().vcpu_id() as u64;
+        write_sysreg!(VMPIDR_EL2, vmpidr);
         HCR_EL2.write(
             HCR_EL2::RW::EL1IsAarch64
                 + HCR_EL2::TSC::EnableTrapEl1SmcToEl2
@@ -166,6 +174,15 @@ impl ArchCpu {
         drop(_lock);
 
         info!("cpu {} idle", self.cpuid);
+        self.park();
+        unsafe {
+            info!("cpu {} started from parking", self.cpuid);
+            vmreturn(self.guest_reg() as *mut _ as usize);
+        }
+    }
+
+    /// Load the parking context: the guest spins on wfi at address 0.
+    pub fn park(&mut self) {
         // reset current cpu -> pc = 0x0 (wfi)
         PARKING_MEMORY_SET.call_once(|| {
             let parking_code: [u8; 8] = [0x7f, 0x20, 0x03, 0xd5, 0xff, 0xff, 0xff, 0x17]; // 1: wfi; b 1b
@@ -174,6 +191,7 @@ impl ArchCpu {
             }
 
             let mut gpm = new_s2_memory_set();
+            gpm.set_vmid(PARKING_VMID);
             gpm.insert(MemoryRegion::new_with_offset_mapper(
                 0 as GuestPhysAddr,
                 unsafe { &PARKING_INST_PAGE as *const _ as HostPhysAddr - PHYS_VIRT_OFFSET },
@@ -186,8 +204,6 @@ impl ArchCpu {
         self.reset(0, this_cpu_data().dtb_ipa);
         unsafe {
             PARKING_MEMORY_SET.get().unwrap().activate();
-            info!("cpu {} started from parking", self.cpuid);
-            vmreturn(self.guest_reg() as *mut _ as usize);
         }
     }
 }
@@ -200,6 +216,24 @@ pub fn this_cpu_id() -> usize {
     mpidr_to_cpuid(MPIDR_EL1.get()) as _
 }
 
+/// Milliseconds since the system counter started.
+pub fn time_ms() -> u64 {
+    (CNTPCT_EL0.get() as u128 * 1000 / CNTFRQ_EL0.get() as u128) as _
+}
+
+/// Fire the EL2 physical timer on this cpu after `ms` milliseconds.
+pub fn hyp_timer_arm(ms: usize) {
+    let ticks = read_sysreg!(cntfrq_el0) * ms as u64 / 1000;
+    write_sysreg!(cnthp_tval_el2, ticks);
+    // enabled, not masked
+    write_sysreg!(cnthp_ctl_el2, 1);
+}
+
+/// Stop the EL2 physical timer, it keeps its interrupt asserted otherwise.
+pub fn hyp_timer_disarm() {
+    write_sysreg!(cnthp_ctl_el2, 0);
+}
+
 pub unsafe fn enable_mmu() {
     const MAIR_FLAG: usize = 0x004404ff; //10001000000010011111111
     const SCTLR_FLAG: usize = 0x30c51835; //110000110001010001100000110101
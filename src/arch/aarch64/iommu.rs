@@ -58,6 +58,7 @@ const STRTAB_STE_SIZE: usize = STRTAB_STE_DWORDS << 3;
 const STRTAB_STE_0_V: usize = 1;
 const STRTAB_STE_0_INVALID: usize = 0;
 const STRTAB_STE_0_CFG_OFF: usize = 1;
+const STRTAB_STE_0_CFG_ABORT: usize = 0;
 const STRTAB_STE_0_CFG_BYPASS: usize = 4;
 const STRTAB_STE_0_CFG_S2_TRANS: usize = 6;
 const STRTAB_STE_1_SHCFG_OFF: usize = 44;
@@ -196,6 +197,14 @@ impl LinearStreamTable {
         tab.0[1] = (STRTAB_STE_1_SHCFG_INCOMING << STRTAB_STE_1_SHCFG_OFF) as _;
     }
 
+    /// Valid, but every transaction of the stream is aborted.
+    fn init_abort_ste(&self, sid: usize) {
+        let tab = self.ste(sid);
+        tab.0 = [0; STRTAB_STE_DWORDS];
+        tab.0[0] = (STRTAB_STE_0_V | STRTAB_STE_0_CFG_ABORT << STRTAB_STE_0_CFG_OFF) as _;
+        tab.0[1] = (STRTAB_STE_1_SHCFG_INCOMING << STRTAB_STE_1_SHCFG_OFF) as _;
+    }
+
     fn write_ste(&self, sid: usize, vmid: usize, root_pt: usize) {
         info!(
             "write ste, sid: 0x{:x}, vmid: 0x{:x}, ste_addr:0x{:x}, root_pt: 0x{:x}",
@@ -539,6 +548,14 @@ impl Smmuv3 {
             .write_ste(sid, vmid, self.iommu_pt_list[vmid].root_paddr());
     }
 
+    // abort, the device must not reach memory of any zone once it is taken
+    // from its zone
+    fn clear_ste(&mut self, sid: usize) {
+        self.sync_ste(sid);
+        self.strtab.init_abort_ste(sid);
+        self.sync_ste(sid);
+    }
+
     // invalidate the ste
     fn sync_ste(&mut self, sid: usize) {
         let cmd = self.cmdq.build_cfgi_cmd(sid);
@@ -588,3 +605,9 @@ pub fn iommu_add_device(vmid: usize, sid: usize) {
     let mut smmu = SMMUV3.get().unwrap().lock();
     smmu.write_ste(sid as _, vmid as _);
 }
+
+/// block all DMA of the device
+pub fn iommu_remove_device(sid: usize) {
+    let mut smmu = SMMUV3.get().unwrap().lock();
+    smmu.clear_ste(sid);
+}
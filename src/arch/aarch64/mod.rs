@@ -9,6 +9,8 @@ pub mod s1pt;
 pub mod s2pt;
 pub mod sysreg;
 pub mod trap;
+#[cfg(feature = "vcpu_sched")]
+pub mod vcpu;
 pub mod zone;
 
 pub use s1pt::Stage1PageTable;
@@ -1,6 +1,6 @@
 use crate::error::{HvError, HvResult};
 use crate::memory::addr::is_aligned;
-use crate::memory::{Frame, MemFlags, MemoryRegion, PhysAddr, VirtAddr};
+use crate::memory::{Frame, MemFlags, MemoryRegion, PhysAddr, VirtAddr, PAGE_SIZE};
 use alloc::{sync::Arc, vec::Vec};
 use core::{fmt::Debug, marker::PhantomData, slice};
 use spin::Mutex;
@@ -86,9 +86,10 @@ pub trait GenericPTE: Debug + Clone {
 
 const ENTRY_COUNT: usize = 512;
 
+/// `vmid` tags the translations of a stage-2 table, stage-1 tables ignore it.
 pub trait PagingInstr {
-    unsafe fn activate(root_paddr: PhysAddr);
-    fn flush(vaddr: Option<usize>);
+    unsafe fn activate(root_paddr: PhysAddr, vmid: usize);
+    fn flush(vaddr: Option<usize>, vmid: usize);
 }
 
 /// A basic read-only page table for address query only.
@@ -116,7 +117,14 @@ pub trait GenericPageTable: GenericPageTableImmut {
         flags: MemFlags,
     ) -> PagingResult<PageSize>;
 
+    /// Change the flags of the pages in `region`, blocks reaching outside of
+    /// it are split first.
+    fn protect(&mut self, region: &MemoryRegion<Self::VA>) -> HvResult;
+
     fn clone(&self) -> Self;
+    fn set_vmid(&mut self, vmid: usize);
+    /// Number of frames holding this table, root included.
+    fn table_frames(&self) -> usize;
 
     unsafe fn activate(&self);
     fn flush(&self, vaddr: Option<Self::VA>);
@@ -268,6 +276,8 @@ struct HvPageTableUnlocked<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: HvPageTableImmut<VA, PTE>,
     /// Intermediate level table frames.
     intrm_tables: Vec<Frame>,
+    /// Tables an unmap took out, freed once no cached walk can reach them.
+    stale_tables: Vec<Frame>,
     /// Phantom data.
     _phantom: PhantomData<(VA, PTE, I)>,
 }
@@ -282,6 +292,7 @@ where
         Self {
             inner: HvPageTableImmut::new(level),
             intrm_tables: Vec::new(),
+            stale_tables: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -290,6 +301,7 @@ where
         Self {
             inner: HvPageTableImmut::from_root(root_paddr, level),
             intrm_tables: Vec::new(),
+            stale_tables: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -301,7 +313,82 @@ where
         Ok(paddr)
     }
 
-    fn _dealloc_intrm_table(&mut self, _paddr: PhysAddr) {}
+    fn dealloc_intrm_table(&mut self, paddr: PhysAddr) {
+        if let Some(idx) = self
+            .intrm_tables
+            .iter()
+            .position(|frame| frame.start_paddr() == paddr)
+        {
+            self.intrm_tables.swap_remove(idx);
+        }
+    }
+
+    /// Unlink the intermediate tables on the walk to `vaddr` that map nothing
+    /// any more, deepest first. The root table is kept.
+    fn free_empty_tables(&mut self, vaddr: usize) {
+        let indices = [p4_index(vaddr), p3_index(vaddr), p2_index(vaddr)];
+        let indices = &indices[4 - self.inner.level..];
+        let mut parents: Vec<&mut PTE> = Vec::with_capacity(indices.len());
+        let mut table = self.inner.root_paddr();
+        for &idx in indices.iter() {
+            let entry = &mut table_of_mut::<PTE>(table)[idx];
+            if next_table_mut(entry).is_err() {
+                break;
+            }
+            table = entry.addr();
+            parents.push(entry);
+        }
+        while let Some(entry) = parents.pop() {
+            if !table_of::<PTE>(entry.addr()).iter().all(|e| e.is_unused()) {
+                break;
+            }
+            let paddr = entry.addr();
+            entry.clear();
+            self.retire_intrm_table(paddr);
+        }
+    }
+
+    /// Unmap the pages of `region`, the tables left empty are retired.
+    fn unmap_region(&mut self, region: &MemoryRegion<VA>, vmid: usize) -> HvResult {
+        let mut vaddr = region.start.into();
+        let mut size = region.size;
+        while size > 0 {
+            self.split_to_fit(vaddr, size, vmid)?;
+            let (_, page_size) = self.unmap_page(vaddr.into()).map_err(|e| {
+                error!("failed to unmap page: {:#x?}, {:?}", vaddr, e);
+                e
+            })?;
+            assert!(page_size.is_aligned(vaddr));
+            assert!(page_size as usize <= size);
+            vaddr += page_size as usize;
+            size -= page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Keep the unlinked table `paddr` until `free_stale_tables`.
+    fn retire_intrm_table(&mut self, paddr: PhysAddr) {
+        if let Some(idx) = self
+            .intrm_tables
+            .iter()
+            .position(|frame| frame.start_paddr() == paddr)
+        {
+            let frame = self.intrm_tables.swap_remove(idx);
+            self.stale_tables.push(frame);
+        }
+    }
+
+    /// Flush the translations of `vmid`, then free the retired tables.
+    fn free_stale_tables(&mut self, vmid: usize) {
+        if !self.stale_tables.is_empty() {
+            I::flush(None, vmid);
+            self.stale_tables.clear();
+        }
+    }
+
+    fn table_frames(&self) -> usize {
+        self.inner.root.size() / PAGE_SIZE + self.intrm_tables.len()
+    }
 
     fn get_entry_mut_or_create(&mut self, page: Page<VA>) -> PagingResult<&mut PTE> {
         let vaddr: usize = page.vaddr.into();
@@ -351,6 +438,7 @@ where
         }
         let paddr = entry.addr();
         entry.clear();
+        self.free_empty_tables(vaddr.into());
         Ok((paddr, size))
     }
 
@@ -360,6 +448,108 @@ where
         entry.set_flags(flags, size.is_huge());
         Ok(size)
     }
+
+    /// Replace the block mapping `vaddr` by a table of the next smaller
+    /// pages with the same flags.
+    fn split_block(&mut self, vaddr: usize, vmid: usize) -> PagingResult {
+        let (base, flags, size) = {
+            let (entry, size) = self.inner.get_entry_mut(vaddr.into())?;
+            if !entry.is_present() {
+                return Err(PagingError::NotMapped);
+            }
+            (entry.addr(), entry.flags(), size)
+        };
+        let sub_size = match size {
+            PageSize::Size1G => PageSize::Size2M,
+            PageSize::Size2M => PageSize::Size4K,
+            PageSize::Size4K => return Ok(()),
+        };
+        let table = self
+            .alloc_intrm_table()
+            .map_err(|_| PagingError::NoMemory)?;
+        for (i, entry) in table_of_mut::<PTE>(table).iter_mut().enumerate() {
+            entry.set_addr(base + i * sub_size as usize);
+            entry.set_flags(flags, sub_size.is_huge());
+        }
+        let (entry, _) = self.inner.get_entry_mut(vaddr.into())?;
+        // break-before-make, the block and the table must never be cached together
+        entry.clear();
+        I::flush(Some(size.align_down(vaddr)), vmid);
+        entry.set_table(table);
+        Ok(())
+    }
+
+    /// Split blocks until the entry mapping `vaddr` starts there and ends
+    /// within `size` bytes.
+    fn split_to_fit(&mut self, vaddr: usize, size: usize, vmid: usize) -> PagingResult {
+        loop {
+            let (_, page_size) = self.inner.get_entry_mut(vaddr.into())?;
+            if page_size.is_aligned(vaddr) && page_size as usize <= size {
+                return Ok(());
+            }
+            self.split_block(vaddr, vmid)?;
+        }
+    }
+
+    /// The entry for the `size` block around `vaddr`, if the tables above it
+    /// exist.
+    fn block_entry<'a>(&self, vaddr: usize, size: PageSize) -> Option<&'a mut PTE> {
+        let p3 = if self.inner.level == 4 {
+            next_table_mut(&table_of::<PTE>(self.inner.root_paddr())[p4_index(vaddr)]).ok()?
+        } else {
+            table_of_mut::<PTE>(self.inner.root_paddr())
+        };
+        let p3e = &mut p3[p3_index(vaddr)];
+        if size == PageSize::Size1G {
+            return Some(p3e);
+        }
+        let p2 = next_table_mut(p3e).ok()?;
+        Some(&mut p2[p2_index(vaddr)])
+    }
+
+    /// Fold the tables around `vaddr` back into a 2M and then a 1G block
+    /// when all their entries map one aligned physical range with the same
+    /// flags.
+    fn try_merge(&mut self, vaddr: usize, vmid: usize) {
+        for (size, sub_size) in [
+            (PageSize::Size2M, PageSize::Size4K),
+            (PageSize::Size1G, PageSize::Size2M),
+        ] {
+            let entry = match self.block_entry(vaddr, size) {
+                Some(entry) if entry.is_present() && !entry.is_huge() => entry,
+                _ => return,
+            };
+            let table = table_of::<PTE>(entry.addr());
+            let base = table[0].addr();
+            let flags = table[0].flags();
+            let mergeable = size.is_aligned(base)
+                && table.iter().enumerate().all(|(i, sub)| {
+                    sub.is_present()
+                        && (sub_size == PageSize::Size4K || sub.is_huge())
+                        && sub.addr() == base + i * sub_size as usize
+                        && sub.flags().bits() == flags.bits()
+                });
+            if !mergeable {
+                return;
+            }
+            let table_paddr = entry.addr();
+            // break-before-make, drop the small pages before the block appears
+            entry.clear();
+            I::flush(None, vmid);
+            entry.set_addr(base);
+            entry.set_flags(flags, true);
+            self.dealloc_intrm_table(table_paddr);
+        }
+    }
+
+    /// `try_merge` every 2M block overlapping `[start, end)`.
+    fn merge_range(&mut self, start: usize, end: usize, vmid: usize) {
+        let mut vaddr = PageSize::Size2M.align_down(start);
+        while vaddr < end {
+            self.try_merge(vaddr, vmid);
+            vaddr += PageSize::Size2M as usize;
+        }
+    }
 }
 
 /// A extended level-3/4 page table implements `GenericPageTable`. It use locks to avoid data
@@ -368,6 +558,7 @@ pub struct HvPageTable<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: HvPageTableUnlocked<VA, PTE, I>,
     /// Make sure all accesses to the page table and its clonees is exclusive.
     clonee_lock: Arc<Mutex<()>>,
+    vmid: usize,
 }
 
 impl<VA, PTE, I> HvPageTable<VA, PTE, I>
@@ -407,6 +598,7 @@ where
         Self {
             inner: HvPageTableUnlocked::from_root(root_paddr, level),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -438,6 +630,7 @@ where
         Self {
             inner: HvPageTableUnlocked::new(level),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -486,6 +679,12 @@ where
             vaddr += page_size as usize;
             size -= page_size as usize;
         }
+        if !region.flags.contains(MemFlags::NO_HUGEPAGES) {
+            // the new pages may complete a table next to them
+            let start = region.start.into();
+            self.inner
+                .merge_range(start, start + region.size, self.vmid);
+        }
         Ok(())
     }
 
@@ -496,23 +695,10 @@ where
             region
         );
         let _lock = self.clonee_lock.lock();
-        let mut vaddr = region.start.into();
-        let mut size = region.size;
-        while size > 0 {
-            let (_, page_size) = self.inner.unmap_page(vaddr.into()).map_err(|e| {
-                error!("failed to unmap page: {:#x?}, {:?}", vaddr, e);
-                e
-            })?;
-            if !page_size.is_aligned(vaddr) {
-                error!("error vaddr={:#x?}", vaddr);
-                loop {}
-            }
-            assert!(page_size.is_aligned(vaddr));
-            assert!(page_size as usize <= size);
-            vaddr += page_size as usize;
-            size -= page_size as usize;
-        }
-        Ok(())
+        let res = self.inner.unmap_region(region, self.vmid);
+        // break-before-make, the emptied tables are freed after the flush
+        self.inner.free_stale_tables(self.vmid);
+        res
     }
 
     fn update(&mut self, vaddr: VA, paddr: PhysAddr, flags: MemFlags) -> PagingResult<PageSize> {
@@ -520,19 +706,52 @@ where
         self.inner.update(vaddr, paddr, flags)
     }
 
+    fn protect(&mut self, region: &MemoryRegion<VA>) -> HvResult {
+        assert!(is_aligned(region.start.into()));
+        assert!(is_aligned(region.size));
+        let _lock = self.clonee_lock.lock();
+        let start = region.start.into();
+        let end = start + region.size;
+        let mut vaddr = start;
+        while vaddr < end {
+            self.inner.split_to_fit(vaddr, end - vaddr, self.vmid)?;
+            let (entry, page_size) = self.inner.inner.get_entry_mut(vaddr.into())?;
+            if !entry.is_present() {
+                return Err(PagingError::NotMapped.into());
+            }
+            entry.set_flags(region.flags, page_size.is_huge());
+            I::flush(Some(vaddr), self.vmid);
+            vaddr += page_size as usize;
+        }
+        if !region.flags.contains(MemFlags::NO_HUGEPAGES) {
+            self.inner.merge_range(start, end, self.vmid);
+        }
+        Ok(())
+    }
+
     fn clone(&self) -> Self {
         let mut pt = Self::clone_from(self);
         // clone with lock to avoid data racing between it and its clonees.
         pt.clonee_lock = self.clonee_lock.clone();
+        pt.vmid = self.vmid;
         pt
     }
 
+    fn set_vmid(&mut self, vmid: usize) {
+        self.vmid = vmid;
+    }
+
+    fn table_frames(&self) -> usize {
+        let _lock = self.clonee_lock.lock();
+        self.inner.table_frames()
+    }
+
     unsafe fn activate(&self) {
-        I::activate(self.root_paddr())
+        I::activate(self.root_paddr(), self.vmid)
     }
 
     fn flush(&self, vaddr: Option<Self::VA>) {
-        I::flush(vaddr.map(Into::into))
+        I::flush(vaddr.map(Into::into), self.vmid)
     }
 }
 
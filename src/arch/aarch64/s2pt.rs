@@ -9,6 +9,9 @@ use crate::memory::MemFlags;
 
 use super::paging::{GenericPTE, HvPageTable, PagingInstr};
 
+/// 8-bit VMIDs, VTCR_EL2.VS is left clear.
+pub const MAX_VMID_NUM: usize = 1 << 8;
+
 bitflags::bitflags! {
     /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
     #[derive(Clone, Copy, Debug)]
@@ -198,17 +201,43 @@ impl fmt::Debug for PageTableEntry {
 
 pub struct S2PTInstr;
 
+const VTTBR_VMID_SHIFT: u64 = 48;
+
 impl PagingInstr for S2PTInstr {
-    unsafe fn activate(root_paddr: HostPhysAddr) {
-        debug!("activating stage 2 page table at {:#x}", root_paddr);
-        VTTBR_EL2.set_baddr(root_paddr as _);
+    unsafe fn activate(root_paddr: HostPhysAddr, vmid: usize) {
+        debug!(
+            "activating stage 2 page table at {:#x}, vmid {}",
+            root_paddr, vmid
+        );
+        // translations are tagged with the vmid, so nothing to invalidate here
+        VTTBR_EL2.set((vmid as u64) << VTTBR_VMID_SHIFT | root_paddr as u64);
         core::arch::asm!("isb");
-        core::arch::asm!("tlbi vmalls12e1is");
-        core::arch::asm!("dsb nsh");
     }
 
-    fn flush(_vaddr: Option<usize>) {
-        // do nothing
+    /// Invalidate the stage-2 translation of `vaddr`, or all translations
+    /// of `vmid` if `None`, on all cpus of the inner shareable domain.
+    fn flush(vaddr: Option<usize>, vmid: usize) {
+        // TLB maintenance from EL2 acts on the vmid in VTTBR_EL2, borrow it
+        // for the zone whose entries are dropped
+        let vttbr = VTTBR_EL2.get();
+        let vmid_mask = (MAX_VMID_NUM as u64 - 1) << VTTBR_VMID_SHIFT;
+        VTTBR_EL2.set(vttbr & !vmid_mask | (vmid as u64) << VTTBR_VMID_SHIFT);
+        unsafe {
+            core::arch::asm!("dsb ishst", "isb");
+            match vaddr {
+                // the combined stage-1 entries can't be told apart by ipa
+                Some(ipa) => core::arch::asm!(
+                    "tlbi ipas2e1is, {0}",
+                    "dsb ish",
+                    "tlbi vmalle1is",
+                    in(reg) ipa >> 12,
+                ),
+                None => core::arch::asm!("tlbi vmalls12e1is"),
+            }
+            core::arch::asm!("dsb ish");
+        }
+        VTTBR_EL2.set(vttbr);
+        unsafe { core::arch::asm!("isb") };
     }
 }
 
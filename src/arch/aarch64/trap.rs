@@ -8,12 +8,17 @@ use crate::{
         cpu::mpidr_to_cpuid,
         sysreg::{read_sysreg, write_sysreg},
     },
+    consts::INVALID_ADDRESS,
     device::irqchip::gic_handle_irq,
-    event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP},
+    event::{send_zone_event, IPI_EVENT_WAKEUP},
     hypercall::{HyperCall, SGI_IPI_ID},
     memory::{mmio_handle_access, MMIOAccess},
-    percpu::{get_cpu_data, this_cpu_data, this_zone, PerCpu},
-    zone::{is_this_root_zone, remove_zone},
+    notify::{
+        notify_root, ZONE_CRASH_REBOOT_FAILED, ZONE_CRASH_UNHANDLED_TRAP, ZONE_EVENT_CPU_OFFLINE,
+        ZONE_EVENT_EXITED,
+    },
+    percpu::{get_cpu_data, this_cpu_data, this_zone, zone_cpu_data, PerCpu},
+    zone::{is_this_root_zone, this_zone_id, zone_fault, zone_reboot, zone_shutdown},
 };
 
 global_asm!(
@@ -43,6 +48,12 @@ const PSCI_TOS_NOT_PRESENT_MP: u64 = 2;
 const ARM_SMCCC_VERSION_1_0: u64 = 0x10000;
 const ARM_SMCCC_NOT_SUPPORTED: i64 = -1;
 
+const PSCI_RET_INVALID_PARAMETERS: i64 = -2;
+const PSCI_RET_ALREADY_ON: i64 = -4;
+const PSCI_RET_INVALID_ADDRESS: i64 = -9;
+/// StateType bit of the original power_state format, set for powerdown states
+const PSCI_POWER_STATE_TYPE_POWER_DOWN: u64 = 1 << 16;
+
 extern "C" {
     fn _hyp_trap_vector();
 }
@@ -63,12 +74,15 @@ pub mod PsciFnId {
     pub const PSCI_AFFINITY_INFO_32: u64 = 0x84000004;
     pub const PSCI_MIG_INFO_TYPE: u64 = 0x84000006;
     pub const PSCI_SYSTEM_OFF: u64 = 0x84000008;
+    pub const PSCI_SYSTEM_RESET: u64 = 0x84000009;
     pub const PSCI_FEATURES: u64 = 0x8400000a;
+    pub const PSCI_SYSTEM_RESET2_32: u64 = 0x84000012;
 
     pub const PSCI_CPU_SUSPEND_64: u64 = 0xc4000001;
     pub const PSCI_CPU_OFF_64: u64 = 0xc4000002;
     pub const PSCI_CPU_ON_64: u64 = 0xc4000003;
     pub const PSCI_AFFINITY_INFO_64: u64 = 0xc4000004;
+    pub const PSCI_SYSTEM_RESET2_64: u64 = 0xc4000012;
 }
 #[allow(non_snake_case)]
 pub mod SMCccFnId {
@@ -95,6 +109,8 @@ pub fn arch_handle_exit(regs: &mut GeneralRegisters) -> ! {
         ExceptionType::EXIT_REASON_EL2_IRQ => irqchip_handle_irq2(),
         _ => arch_dump_exit(regs.exit_reason),
     }
+    #[cfg(feature = "vcpu_sched")]
+    crate::scheduler::schedule(regs);
     unsafe { vmreturn(regs as *const _ as usize) }
 }
 
@@ -129,12 +145,74 @@ fn arch_handle_trap_el1(regs: &mut GeneralRegisters) {
                 ESR_EL2.read(ESR_EL2::EC)
             );
             error!("esr_el2: iss {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
-            loop {}
-            // ret = TrapReturn::TrapUnhandled;
+            inject_guest_fault(GuestFault::Undefined);
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GuestFault {
+    Undefined,
+    InstrAbort,
+    DataAbort,
+}
+
+const ESR_ELX_EC_SHIFT: u64 = 26;
+const ESR_ELX_IL: u64 = 1 << 25;
+const ESR_ELX_EC_UNKNOWN: u64 = 0x00;
+const ESR_ELX_EC_IABT_LOW: u64 = 0x20;
+const ESR_ELX_EC_IABT_CUR: u64 = 0x21;
+const ESR_ELX_EC_DABT_LOW: u64 = 0x24;
+const ESR_ELX_EC_DABT_CUR: u64 = 0x25;
+const ESR_ELX_FSC_EXTABT: u64 = 0x10;
+
+const PSR_MODE_MASK: u64 = 0x1f;
+const PSR_MODE_EL1T: u64 = 0x04;
+const PSR_MODE_EL1H: u64 = 0x05;
+const PSR_MODE_32BIT: u64 = 0x10;
+/// EL1h with D, A, I and F masked, what the cpu sets on exception entry
+const PSR_EL1H_DAIF_MASKED: u64 = 0x3c5;
+
+/// Reflect a fault into the guest like the hardware would take it at EL1,
+/// the guest's own handlers then deal with it (e.g. Linux kills the task or
+/// oopses). Abort syndromes report a synchronous external abort. AArch32
+/// guests can't take it this way, their zone is stopped instead.
+fn inject_guest_fault(fault: GuestFault) {
+    let spsr = SPSR_EL2.get();
+    if spsr & PSR_MODE_32BIT != 0 {
+        zone_fault(ZONE_CRASH_UNHANDLED_TRAP);
+    }
+    let mode = spsr & PSR_MODE_MASK;
+    let from_el1 = mode == PSR_MODE_EL1T || mode == PSR_MODE_EL1H;
+    let (ec, iss) = match fault {
+        GuestFault::Undefined => (ESR_ELX_EC_UNKNOWN, 0),
+        GuestFault::InstrAbort if from_el1 => (ESR_ELX_EC_IABT_CUR, ESR_ELX_FSC_EXTABT),
+        GuestFault::InstrAbort => (ESR_ELX_EC_IABT_LOW, ESR_ELX_FSC_EXTABT),
+        GuestFault::DataAbort if from_el1 => (ESR_ELX_EC_DABT_CUR, ESR_ELX_FSC_EXTABT),
+        GuestFault::DataAbort => (ESR_ELX_EC_DABT_LOW, ESR_ELX_FSC_EXTABT),
+    };
+    warn!(
+        "inject {:?} into cpu {} at {:#x}",
+        fault,
+        this_cpu_data().id,
+        ELR_EL2.get()
+    );
+    write_sysreg!(ESR_EL1, ec << ESR_ELX_EC_SHIFT | ESR_ELX_IL | iss);
+    if fault != GuestFault::Undefined {
+        write_sysreg!(FAR_EL1, FAR_EL2.get());
+    }
+    write_sysreg!(ELR_EL1, ELR_EL2.get());
+    write_sysreg!(SPSR_EL1, spsr);
+    // offset of the synchronous vector for the exception's origin
+    let vector = match mode {
+        PSR_MODE_EL1T => 0x000,
+        PSR_MODE_EL1H => 0x200,
+        _ => 0x400,
+    };
+    ELR_EL2.set(read_sysreg!(VBAR_EL1) + vector);
+    SPSR_EL2.set(PSR_EL1H_DAIF_MASKED);
+}
+
 fn arch_handle_trap_el2(_regs: &mut GeneralRegisters) {
     let elr = ELR_EL2.get();
     let esr = ESR_EL2.get();
@@ -179,9 +257,7 @@ fn handle_iabt(_regs: &mut GeneralRegisters) {
     address |= hdfar & 0xfff;
     error!("error ins access {} at {:#x?}!", op, address);
     error!("esr_el2: iss {:#x?}", iss);
-    loop {}
-    //TODO finish dabt handle
-    // arch_skip_instruction(frame);
+    inject_guest_fault(GuestFault::InstrAbort);
 }
 fn handle_dabt(regs: &mut GeneralRegisters) {
     let iss = ESR_EL2.read(ESR_EL2::ISS);
@@ -219,7 +295,10 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
             }
         }
         Err(e) => {
-            panic!("mmio_handle_access: {:#x?}", e);
+            error!("mmio_handle_access: {:#x?}", e);
+            // the faulting instruction is not skipped, the guest sees the abort on it
+            inject_guest_fault(GuestFault::DataAbort);
+            return;
         }
     }
     //TODO finish dabt handle
@@ -238,12 +317,45 @@ fn handle_sysreg(regs: &mut GeneralRegisters) {
         warn!("skip send sgi {:#x?}", sgi_id);
     } else {
         trace!("send sgi {:#x?}", sgi_id);
-        write_sysreg!(icc_sgi1r_el1, val);
+        let val = sgi1r_to_physical(val);
+        if val & ICC_SGI1R_TARGET_LIST_MASK != 0 {
+            write_sysreg!(icc_sgi1r_el1, val);
+        }
     }
 
     arch_skip_instruction(regs); //skip sgi write
 }
 
+const ICC_SGI1R_TARGET_LIST_MASK: u64 = 0xffff;
+const ICC_SGI1R_AFF_MASK: u64 = 0xff << 48 | 0xff << 32 | 0xff << 16;
+const ICC_SGI1R_IRM: u64 = 1 << 40;
+
+/// The guest addresses SGIs by vcpu id (Aff0 of its virtual MPIDR), rewrite
+/// the target list to the physical cpus of the current zone.
+fn sgi1r_to_physical(val: u64) -> u64 {
+    let zone = this_zone();
+    let zone_r = zone.read();
+    let this_cpu = this_cpu_data().id;
+    let mut target_list = 0;
+    if val & ICC_SGI1R_IRM != 0 {
+        // "all but self" must not reach cpus of other zones
+        for cpu in zone_r.cpu_set.iter_except(this_cpu) {
+            target_list |= 1 << cpu;
+        }
+    } else if val & ICC_SGI1R_AFF_MASK == 0 {
+        for vcpu in 0..16 {
+            if val & (1 << vcpu) == 0 {
+                continue;
+            }
+            match zone_r.vcpu_to_cpu(vcpu) {
+                Some(cpu) => target_list |= 1 << cpu,
+                None => warn!("skip sgi to vcpu {} outside the zone", vcpu),
+            }
+        }
+    }
+    val & !(ICC_SGI1R_TARGET_LIST_MASK | ICC_SGI1R_AFF_MASK | ICC_SGI1R_IRM) | target_list
+}
+
 fn handle_hvc(regs: &mut GeneralRegisters) {
     /*
     if ESR_EL2.read(ESR_EL2::ISS) != 0x4a48 {
@@ -304,77 +416,140 @@ fn psci_emulate_features_info(code: u64) -> u64 {
         | PsciFnId::PSCI_AFFINITY_INFO_32
         | PsciFnId::PSCI_AFFINITY_INFO_64
         | PsciFnId::PSCI_FEATURES
+        | PsciFnId::PSCI_SYSTEM_OFF
+        | PsciFnId::PSCI_SYSTEM_RESET
+        | PsciFnId::PSCI_SYSTEM_RESET2_32
+        | PsciFnId::PSCI_SYSTEM_RESET2_64
         | SMCccFnId::SMCCC_VERSION => 0,
         _ => !0,
     }
 }
 
+/// Translate the virtual MPIDR a guest passes to PSCI into the physical cpu
+/// id, only vcpus of the current zone are valid targets.
+fn psci_target_cpu(mpidr: u64) -> Option<usize> {
+    let vcpu = mpidr_to_cpuid(mpidr) as usize;
+    let cpu = this_zone().read().vcpu_to_cpu(vcpu);
+    if cpu.is_none() {
+        warn!("psci: mpidr {:#x} is not in the current zone", mpidr);
+    }
+    cpu
+}
+
 fn psci_emulate_cpu_on(regs: &mut GeneralRegisters) -> u64 {
-    // Todo: Check if `cpu` is in the cpuset of current zone
-    let cpu = mpidr_to_cpuid(regs.usr[1]);
+    let cpu = match psci_target_cpu(regs.usr[1]) {
+        Some(cpu) => cpu,
+        None => return PSCI_RET_INVALID_PARAMETERS as _,
+    };
+    if unsafe { this_zone().read().gpm.page_table_query(regs.usr[2] as _) }.is_err() {
+        return PSCI_RET_INVALID_ADDRESS as _;
+    }
     info!("psci: try to wake up cpu {}", cpu);
 
-    let target_data = get_cpu_data(cpu as _);
-    let _lock = target_data.ctrl_lock.lock();
-
-    if !target_data.arch_cpu.power_on {
+    let zone_id = this_zone_id();
+    let _lock = get_cpu_data(cpu).ctrl_lock.lock();
+    {
+        let mut target_data = zone_cpu_data(cpu, zone_id);
+        if !target_data.in_this_zone() {
+            return PSCI_RET_INVALID_PARAMETERS as _;
+        }
+        if target_data.arch_cpu.power_on {
+            error!("psci: cpu {} already on", cpu);
+            return PSCI_RET_ALREADY_ON as _;
+        }
         target_data.cpu_on_entry = regs.usr[2] as _;
         target_data.arch_cpu.power_on = true;
-        send_event(cpu as _, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
-    } else {
-        error!("psci: cpu {} already on", cpu);
-        return u64::MAX - 3;
-    };
+    }
+    send_zone_event(cpu, zone_id, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
     drop(_lock);
 
     0
 }
 
-fn handle_psci_smc(
+fn psci_emulate_cpu_suspend(
     regs: &mut GeneralRegisters,
-    code: u64,
-    arg0: u64,
-    _arg1: u64,
-    _arg2: u64,
+    power_state: u64,
+    entry: u64,
+    context_id: u64,
 ) -> u64 {
+    let powerdown = power_state & PSCI_POWER_STATE_TYPE_POWER_DOWN != 0;
+    if powerdown && unsafe { this_zone().read().gpm.page_table_query(entry as _) }.is_err() {
+        return PSCI_RET_INVALID_ADDRESS as _;
+    }
+    wfi();
+    gic_handle_irq();
+    if !powerdown {
+        return 0;
+    }
+    // the cpu context is lost in a powerdown state, so the guest
+    // continues at `entry` like a warm boot, with context_id in x0
+    let cpu_data = this_cpu_data();
+    debug!(
+        "psci: cpu {} resume from powerdown@{:#x}",
+        cpu_data.id, entry
+    );
+    cpu_data.arch_cpu.reset(entry as _, context_id as _);
+    unsafe { vmreturn(regs as *mut _ as usize) }
+}
+
+fn handle_psci_smc(regs: &mut GeneralRegisters, code: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
     match code {
         PsciFnId::PSCI_VERSION => PSCI_VERSION_1_1,
         PsciFnId::PSCI_CPU_SUSPEND_32 | PsciFnId::PSCI_CPU_SUSPEND_64 => {
-            wfi();
-            gic_handle_irq();
-            0
+            psci_emulate_cpu_suspend(regs, arg0, arg1, arg2)
         }
         PsciFnId::PSCI_CPU_OFF_32 | PsciFnId::PSCI_CPU_OFF_64 => {
-            todo!();
+            let cpu_data = this_cpu_data();
+            info!("psci: cpu {} off", cpu_data.id);
+            let _lock = cpu_data.ctrl_lock.lock();
+            cpu_data.cpu_on_entry = INVALID_ADDRESS;
+            drop(_lock);
+            notify_root(this_zone_id(), ZONE_EVENT_CPU_OFFLINE, cpu_data.id as _);
+            // park the cpu with power_on = false, a later CPU_ON restarts it
+            cpu_data.arch_cpu.idle();
         }
         PsciFnId::PSCI_AFFINITY_INFO_32 | PsciFnId::PSCI_AFFINITY_INFO_64 => {
-            !get_cpu_data(arg0 as _).arch_cpu.power_on as _
+            // only affinity level 0 is emulated
+            if arg1 != 0 {
+                return PSCI_RET_INVALID_PARAMETERS as _;
+            }
+            match psci_target_cpu(arg0) {
+                Some(cpu) => {
+                    let _lock = get_cpu_data(cpu).ctrl_lock.lock();
+                    !zone_cpu_data(cpu, this_zone_id()).arch_cpu.power_on as _
+                }
+                None => PSCI_RET_INVALID_PARAMETERS as _,
+            }
         }
         PsciFnId::PSCI_MIG_INFO_TYPE => PSCI_TOS_NOT_PRESENT_MP,
         PsciFnId::PSCI_FEATURES => psci_emulate_features_info(regs.usr[1]),
         PsciFnId::PSCI_CPU_ON_32 | PsciFnId::PSCI_CPU_ON_64 => psci_emulate_cpu_on(regs),
         PsciFnId::PSCI_SYSTEM_OFF => {
-            let zone = this_zone();
-            let zone_id = zone.read().id;
+            let zone_id = this_zone_id();
             let is_root = is_this_root_zone();
-
-            for cpu_id in zone.read().cpu_set.iter_except(this_cpu_data().id) {
-                let target_cpu = get_cpu_data(cpu_id);
-                let _lock = target_cpu.ctrl_lock.lock();
-                target_cpu.zone = None;
-                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
-            }
-
-            this_cpu_data().zone = None;
-            drop(zone);
-            remove_zone(zone_id);
+            zone_shutdown(this_zone());
 
             if is_root {
                 psci::system_off().unwrap();
             }
+            notify_root(zone_id, ZONE_EVENT_EXITED, 0);
 
             this_cpu_data().arch_cpu.idle();
         }
+        PsciFnId::PSCI_SYSTEM_RESET
+        | PsciFnId::PSCI_SYSTEM_RESET2_32
+        | PsciFnId::PSCI_SYSTEM_RESET2_64 => {
+            // only the root zone may reset the machine, other zones are
+            // restarted on their own
+            if is_this_root_zone() {
+                psci::system_reset().unwrap();
+            }
+            if let Err(e) = zone_reboot(this_zone(), false) {
+                error!("psci: zone reboot failed: {:?}", e);
+                zone_fault(ZONE_CRASH_REBOOT_FAILED);
+            }
+            unreachable!();
+        }
 
         _ => {
             warn!("unsupported smc standard service {:#x?}", code);
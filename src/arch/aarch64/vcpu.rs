@@ -0,0 +1,180 @@
+//! Guest context of a descheduled vcpu.
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::{Readable, Writeable, ELR_EL2, SPSR_EL2, VTTBR_EL2};
+
+use super::cpu::GeneralRegisters;
+use super::sysreg::{read_sysreg, write_sysreg};
+use crate::device::irqchip::gicv3::VgicCpuState;
+
+macro_rules! guest_sysregs {
+    ($($reg:ident),* $(,)?) => {
+        /// EL1/EL0 system registers owned by the guest.
+        #[derive(Debug, Default, Clone)]
+        struct GuestSysregs {
+            $($reg: u64,)*
+        }
+
+        impl GuestSysregs {
+            fn save(&mut self) {
+                $(self.$reg = read_sysreg!($reg);)*
+            }
+
+            fn restore(&self) {
+                $(write_sysreg!($reg, self.$reg);)*
+            }
+        }
+    };
+}
+
+// the compare value is restored before the control register, so that an
+// enabled timer never fires with the previous vcpu's deadline
+guest_sysregs!(
+    sp_el0,
+    sp_el1,
+    elr_el1,
+    spsr_el1,
+    sctlr_el1,
+    actlr_el1,
+    cpacr_el1,
+    ttbr0_el1,
+    ttbr1_el1,
+    tcr_el1,
+    esr_el1,
+    far_el1,
+    afsr0_el1,
+    afsr1_el1,
+    mair_el1,
+    amair_el1,
+    vbar_el1,
+    contextidr_el1,
+    tpidr_el0,
+    tpidrro_el0,
+    tpidr_el1,
+    csselr_el1,
+    par_el1,
+    mdscr_el1,
+    pmcr_el0,
+    cntkctl_el1,
+    cntvoff_el2,
+    cntv_cval_el0,
+    cntv_ctl_el0,
+    cntp_cval_el0,
+    cntp_ctl_el0,
+    vmpidr_el2,
+);
+
+/// FP/SIMD registers of the guest.
+#[repr(C, align(16))]
+#[derive(Debug, Default, Clone)]
+struct FpContext {
+    q: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl FpContext {
+    fn save(&mut self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "stp q0, q1, [{0}, #0x0]",
+                "stp q2, q3, [{0}, #0x20]",
+                "stp q4, q5, [{0}, #0x40]",
+                "stp q6, q7, [{0}, #0x60]",
+                "stp q8, q9, [{0}, #0x80]",
+                "stp q10, q11, [{0}, #0xa0]",
+                "stp q12, q13, [{0}, #0xc0]",
+                "stp q14, q15, [{0}, #0xe0]",
+                "stp q16, q17, [{0}, #0x100]",
+                "stp q18, q19, [{0}, #0x120]",
+                "stp q20, q21, [{0}, #0x140]",
+                "stp q22, q23, [{0}, #0x160]",
+                "stp q24, q25, [{0}, #0x180]",
+                "stp q26, q27, [{0}, #0x1a0]",
+                "stp q28, q29, [{0}, #0x1c0]",
+                "stp q30, q31, [{0}, #0x1e0]",
+                "mrs {1}, fpcr",
+                "mrs {2}, fpsr",
+                in(reg) self.q.as_mut_ptr(),
+                out(reg) self.fpcr,
+                out(reg) self.fpsr,
+                options(nostack),
+            );
+        }
+    }
+
+    fn restore(&self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "ldp q0, q1, [{0}, #0x0]",
+                "ldp q2, q3, [{0}, #0x20]",
+                "ldp q4, q5, [{0}, #0x40]",
+                "ldp q6, q7, [{0}, #0x60]",
+                "ldp q8, q9, [{0}, #0x80]",
+                "ldp q10, q11, [{0}, #0xa0]",
+                "ldp q12, q13, [{0}, #0xc0]",
+                "ldp q14, q15, [{0}, #0xe0]",
+                "ldp q16, q17, [{0}, #0x100]",
+                "ldp q18, q19, [{0}, #0x120]",
+                "ldp q20, q21, [{0}, #0x140]",
+                "ldp q22, q23, [{0}, #0x160]",
+                "ldp q24, q25, [{0}, #0x180]",
+                "ldp q26, q27, [{0}, #0x1a0]",
+                "ldp q28, q29, [{0}, #0x1c0]",
+                "ldp q30, q31, [{0}, #0x1e0]",
+                "msr fpcr, {1}",
+                "msr fpsr, {2}",
+                in(reg) self.q.as_ptr(),
+                in(reg) self.fpcr,
+                in(reg) self.fpsr,
+                options(nostack),
+            );
+        }
+    }
+}
+
+/// Everything a vcpu needs to continue on a physical cpu after another
+/// vcpu has been running there.
+#[derive(Debug, Default)]
+pub struct VcpuContext {
+    gpr: [u64; 31],
+    elr_el2: u64,
+    spsr_el2: u64,
+    vttbr_el2: u64,
+    sysregs: GuestSysregs,
+    fp: FpContext,
+    pub vgic: VgicCpuState,
+}
+
+impl VcpuContext {
+    /// Save the guest running on this cpu, `regs` is its trap frame.
+    pub fn save(&mut self, regs: &GeneralRegisters) {
+        self.gpr = regs.usr;
+        self.elr_el2 = ELR_EL2.get();
+        self.spsr_el2 = SPSR_EL2.get();
+        self.vttbr_el2 = VTTBR_EL2.get();
+        self.sysregs.save();
+        self.fp.save();
+        self.vgic.save();
+    }
+
+    /// Load the saved guest into this cpu, it continues from `regs` once the
+    /// trap returns.
+    pub fn restore(&mut self, regs: &mut GeneralRegisters) {
+        regs.usr = self.gpr;
+        ELR_EL2.set(self.elr_el2);
+        SPSR_EL2.set(self.spsr_el2);
+        self.sysregs.restore();
+        self.fp.restore();
+        // the saved VTTBR_EL2 carries the zone's vmid, the previous guest's
+        // TLB entries can stay
+        VTTBR_EL2.set(self.vttbr_el2);
+        unsafe { asm!("isb") };
+        self.vgic.restore();
+    }
+}
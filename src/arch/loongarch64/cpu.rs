@@ -54,6 +54,8 @@ impl ArchCpu {
             self.init(this_cpu_data().cpu_on_entry, this_cpu_data().id, 0);
             self.init = true;
         }
+        // the guest reads its vcpu id from the guest CPUID csr
+        self.ctx.gcsr_cpuid = this_cpu_data().vcpu_id();
         // set x[] to all 0
         for i in 0..32 {
             self.ctx.x[i] = 0;
@@ -110,6 +112,12 @@ impl ArchCpu {
         panic!("loongarch64: ArchCpu::run: unreachable");
     }
     pub fn idle(&mut self) -> ! {
+        let cpu_data = this_cpu_data();
+        let _lock = cpu_data.ctrl_lock.lock();
+        self.power_on = false;
+        drop(_lock);
+        // let the next run() start from cpu_on_entry again
+        self.init = false;
         let ctx_addr = &mut self.ctx as *mut ZoneContext;
         unsafe {
             asm!(
@@ -132,6 +140,20 @@ pub fn this_cpu_id() -> usize {
     cpuid::read().core_id()
 }
 
+/// Milliseconds since the stable counter started.
+pub fn time_ms() -> u64 {
+    (super::trap::ktime_get() as u128 * 1000 / loongArch64::time::get_timer_freq() as u128) as _
+}
+
+/// Fire the hypervisor's timer on this cpu once after `ms` milliseconds.
+pub fn hyp_timer_arm(ms: usize) {
+    use loongArch64::register::{ecfg, tcfg};
+    tcfg::set_periodic(false);
+    tcfg::set_init_val(super::trap::get_ms_counter(ms));
+    tcfg::set_en(true);
+    ecfg::set_lie(ecfg::read().lie() | ecfg::LineBasedInterrupt::TIMER);
+}
+
 pub fn cpu_start(cpuid: usize, start_addr: usize, opaque: usize) {
     let start_addr = start_addr & 0x0000_ffff_ffff_ffff;
     let ipi: &MMIODerefWrapper<IpiRegisters> = match cpuid {
@@ -1,7 +1,7 @@
 use crate::error::{HvError, HvResult};
 use crate::memory::addr::is_aligned;
 use crate::memory::mapper::Mapper;
-use crate::memory::{Frame, MemFlags, MemoryRegion, PhysAddr, VirtAddr};
+use crate::memory::{Frame, MemFlags, MemoryRegion, PhysAddr, VirtAddr, PAGE_SIZE};
 use alloc::{sync::Arc, vec::Vec};
 use core::{fmt::Debug, marker::PhantomData, slice};
 use spin::Mutex;
@@ -97,9 +97,10 @@ pub trait GenericPTE: Debug + Clone {
 
 const ENTRY_COUNT: usize = 512;
 
+/// `vmid` tags the translations of a stage-2 table, stage-1 tables ignore it.
 pub trait PagingInstr {
-    unsafe fn activate(root_paddr: PhysAddr);
-    fn flush(vaddr: Option<usize>);
+    unsafe fn activate(root_paddr: PhysAddr, vmid: usize);
+    fn flush(vaddr: Option<usize>, vmid: usize);
 }
 
 /// A basic read-only page table for address query only.
@@ -124,7 +125,14 @@ pub trait GenericPageTable: GenericPageTableImmut {
         flags: MemFlags,
     ) -> PagingResult<PageSize>;
 
+    /// Change the flags of the pages in `region`, blocks reaching outside of
+    /// it are split first.
+    fn protect(&mut self, region: &MemoryRegion<Self::VA>) -> HvResult;
+
     fn clone(&self) -> Self;
+    fn set_vmid(&mut self, vmid: usize);
+    /// Number of frames holding this table, root included.
+    fn table_frames(&self) -> usize;
 
     unsafe fn activate(&self);
     fn flush(&self, vaddr: Option<Self::VA>);
@@ -257,6 +265,8 @@ struct Level4PageTableUnlocked<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: Level4PageTableImmut<VA, PTE>,
     /// Intermediate level table frames.
     intrm_tables: Vec<Frame>,
+    /// Tables an unmap took out, freed once no cached walk can reach them.
+    stale_tables: Vec<Frame>,
     /// Phantom data.
     _phantom: PhantomData<(VA, PTE, I)>,
 }
@@ -271,6 +281,7 @@ where
         Self {
             inner: Level4PageTableImmut::new(),
             intrm_tables: Vec::new(),
+            stale_tables: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -279,6 +290,7 @@ where
         Self {
             inner: Level4PageTableImmut::from_root(root_paddr),
             intrm_tables: Vec::new(),
+            stale_tables: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -290,7 +302,70 @@ where
         Ok(paddr)
     }
 
-    fn _dealloc_intrm_table(&mut self, _paddr: PhysAddr) {}
+    /// Unlink the intermediate tables on the walk to `vaddr` that map nothing
+    /// any more, deepest first. The root table is kept.
+    fn free_empty_tables(&mut self, vaddr: usize) {
+        let indices = [p4_index(vaddr), p3_index(vaddr), p2_index(vaddr)];
+        let mut parents: Vec<&mut PTE> = Vec::with_capacity(indices.len());
+        let mut table = self.inner.root_paddr();
+        for &idx in indices.iter() {
+            let entry = &mut table_of_mut::<PTE>(table)[idx];
+            if next_table_mut(entry).is_err() {
+                break;
+            }
+            table = entry.addr();
+            parents.push(entry);
+        }
+        while let Some(entry) = parents.pop() {
+            if !table_of::<PTE>(entry.addr()).iter().all(|e| e.is_unused()) {
+                break;
+            }
+            let paddr = entry.addr();
+            entry.clear();
+            self.retire_intrm_table(paddr);
+        }
+    }
+
+    /// Unmap the pages of `region`, the tables left empty are retired.
+    fn unmap_region(&mut self, region: &MemoryRegion<VA>) -> HvResult {
+        let mut vaddr = region.start.into();
+        let mut size = region.size;
+        while size > 0 {
+            let (_, page_size) = self.unmap_page(vaddr.into()).map_err(|e| {
+                error!("failed to unmap page: {:#x?}, {:?}", vaddr, e);
+                e
+            })?;
+            assert!(page_size.is_aligned(vaddr));
+            assert!(page_size as usize <= size);
+            vaddr += page_size as usize;
+            size -= page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Keep the unlinked table `paddr` until `free_stale_tables`.
+    fn retire_intrm_table(&mut self, paddr: PhysAddr) {
+        if let Some(idx) = self
+            .intrm_tables
+            .iter()
+            .position(|frame| frame.start_paddr() == paddr)
+        {
+            let frame = self.intrm_tables.swap_remove(idx);
+            self.stale_tables.push(frame);
+        }
+    }
+
+    /// Flush the translations of `vmid`, then free the retired tables.
+    fn free_stale_tables(&mut self, vmid: usize) {
+        if !self.stale_tables.is_empty() {
+            I::flush(None, vmid);
+            self.stale_tables.clear();
+        }
+    }
+
+    fn table_frames(&self) -> usize {
+        self.inner.root.size() / PAGE_SIZE + self.intrm_tables.len()
+    }
 
     /// Get the mutable entry for the given virtual address. If the entry is not present, create
     fn get_entry_mut_or_create(&mut self, page: Page<VA>) -> PagingResult<&mut PTE> {
@@ -377,6 +452,7 @@ where
         }
         let paddr = entry.addr();
         entry.clear();
+        self.free_empty_tables(vaddr.into());
         Ok((paddr, size))
     }
 
@@ -394,6 +470,7 @@ pub struct Level4PageTable<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: Level4PageTableUnlocked<VA, PTE, I>,
     /// Make sure all accesses to the page table and its clonees is exclusive.
     clonee_lock: Arc<Mutex<()>>,
+    vmid: usize,
 }
 
 impl<VA, PTE, I> Level4PageTable<VA, PTE, I>
@@ -433,6 +510,7 @@ where
         Self {
             inner: Level4PageTableUnlocked::from_root(root_paddr),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -456,6 +534,7 @@ where
         Self {
             inner: Level4PageTableUnlocked::new(),
             clonee_lock: Arc::new(Mutex::new(())),
+            vmid: 0,
         }
     }
 
@@ -507,23 +586,10 @@ where
             region
         );
         let _lock = self.clonee_lock.lock();
-        let mut vaddr = region.start.into();
-        let mut size = region.size;
-        while size > 0 {
-            let (_, page_size) = self.inner.unmap_page(vaddr.into()).map_err(|e| {
-                error!("failed to unmap page: {:#x?}, {:?}", vaddr, e);
-                e
-            })?;
-            if !page_size.is_aligned(vaddr) {
-                error!("error vaddr={:#x?}", vaddr);
-                loop {}
-            }
-            assert!(page_size.is_aligned(vaddr));
-            assert!(page_size as usize <= size);
-            vaddr += page_size as usize;
-            size -= page_size as usize;
-        }
-        Ok(())
+        let res = self.inner.unmap_region(region);
+        // break-before-make, the emptied tables are freed after the flush
+        self.inner.free_stale_tables(self.vmid);
+        res
     }
 
     fn update(&mut self, vaddr: VA, paddr: PhysAddr, flags: MemFlags) -> PagingResult<PageSize> {
@@ -531,19 +597,48 @@ where
         self.inner.update(vaddr, paddr, flags)
     }
 
+    fn protect(&mut self, region: &MemoryRegion<VA>) -> HvResult {
+        assert!(is_aligned(region.start.into()));
+        assert!(is_aligned(region.size));
+        let _lock = self.clonee_lock.lock();
+        // only 4K pages are mapped, so there are no blocks to split or merge
+        let mut vaddr = region.start.into();
+        let end = vaddr + region.size;
+        while vaddr < end {
+            let (entry, page_size) = self.inner.inner.get_entry_mut(vaddr.into())?;
+            if !entry.is_present() {
+                return Err(PagingError::NotMapped.into());
+            }
+            entry.set_flags(region.flags, false);
+            I::flush(Some(vaddr), self.vmid);
+            vaddr += page_size as usize;
+        }
+        Ok(())
+    }
+
     fn clone(&self) -> Self {
         let mut pt = Self::clone_from(self);
         // clone with lock to avoid data racing between it and its clonees.
         pt.clonee_lock = self.clonee_lock.clone();
+        pt.vmid = self.vmid;
         pt
     }
 
+    fn set_vmid(&mut self, vmid: usize) {
+        self.vmid = vmid;
+    }
+
+    fn table_frames(&self) -> usize {
+        let _lock = self.clonee_lock.lock();
+        self.inner.table_frames()
+    }
+
     unsafe fn activate(&self) {
-        I::activate(self.root_paddr())
+        I::activate(self.root_paddr(), self.vmid)
     }
 
     fn flush(&self, vaddr: Option<Self::VA>) {
-        I::flush(vaddr.map(Into::into))
+        I::flush(vaddr.map(Into::into), self.vmid)
     }
 }
 
@@ -114,7 +114,7 @@ impl PageTableEntry {
 pub struct S1PTInstr;
 
 impl PagingInstr for S1PTInstr {
-    unsafe fn activate(root_pa: HostPhysAddr) {
+    unsafe fn activate(root_pa: HostPhysAddr, _vmid: usize) {
         info!("loongarch64: S1PTInstr::activate: root_pa: {:#x?}", root_pa);
         extern "C" {
             fn tlb_refill_handler();
@@ -132,7 +132,7 @@ impl PagingInstr for S1PTInstr {
             tlbrentry::read().addr()
         );
     }
-    fn flush(vaddr: Option<usize>) {
+    fn flush(vaddr: Option<usize>, _vmid: usize) {
         warn!("loongarch64: S1PTInstr::flush: vaddr: {:#x?}", vaddr);
     }
 }
@@ -1,12 +1,21 @@
 use super::paging::PagingInstr;
 use crate::arch::paging::GenericPTE;
 use crate::arch::paging::Level4PageTable;
+use crate::consts::PAGE_SIZE;
 use crate::memory::GuestPhysAddr;
 use crate::memory::HostPhysAddr;
 use crate::memory::MemFlags;
 use crate::memory::PhysAddr;
 use loongArch64::register::MemoryAccessType;
 
+/// GSTAT.GID is 8 bits wide and GID 0 tags the hypervisor's own entries,
+/// a zone runs with GID `vmid + 1`.
+pub const MAX_VMID_NUM: usize = (1 << 8) - 1;
+
+pub const fn vmid_to_gid(vmid: usize) -> usize {
+    vmid + 1
+}
+
 bitflags::bitflags! {
     /// Memory attribute fields in the LoongArch64 translation table format descriptors.
     #[derive(Clone, Copy, Debug)]
@@ -148,7 +157,7 @@ impl PageTableEntry {
 pub struct S2PTInstr;
 
 impl PagingInstr for S2PTInstr {
-    unsafe fn activate(root_pa: HostPhysAddr) {
+    unsafe fn activate(root_pa: HostPhysAddr, _vmid: usize) {
         info!("loongarch64: S2PTInstr::activate: root_pa: {:#x?}", root_pa);
         super::paging::set_pwcl_pwch();
         extern "C" {
@@ -166,8 +175,23 @@ impl PagingInstr for S2PTInstr {
             tlbrentry::read().addr()
         );
     }
-    fn flush(vaddr: Option<usize>) {
-        warn!("loongarch64: S2PTInstr::flush: vaddr: {:#x?}", vaddr);
+    /// Drop the GPA to HPA entries of `vaddr`, or all entries of the zone's
+    /// GID if `None`.
+    fn flush(vaddr: Option<usize>, vmid: usize) {
+        // invtlb takes the GID where GSTAT holds it, in bits 23:16
+        let gid = vmid_to_gid(vmid) << 16;
+        unsafe {
+            match vaddr {
+                // INVTLB_GID_ADDR, entries hold an even/odd page pair
+                Some(gpa) => core::arch::asm!(
+                    "invtlb 0x16, {0}, {1}",
+                    in(reg) gid,
+                    in(reg) gpa & !(2 * PAGE_SIZE - 1),
+                ),
+                // INVTLB_GID_ALL
+                None => core::arch::asm!("invtlb 0x15, {0}, $r0", in(reg) gid),
+            }
+        }
     }
 }
 
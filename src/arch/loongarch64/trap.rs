@@ -7,8 +7,9 @@ use crate::hypercall::SGI_IPI_ID;
 use crate::memory::addr;
 use crate::memory::mmio_handle_access;
 use crate::memory::MMIOAccess;
-use crate::percpu::this_cpu_data;
-use crate::zone::Zone;
+use crate::notify::{ZONE_CRASH_BAD_ACCESS, ZONE_CRASH_BAD_INSTRUCTION, ZONE_CRASH_UNHANDLED_TRAP};
+use crate::percpu::{this_cpu_data, this_zone};
+use crate::zone::{zone_fault, Zone};
 
 use super::register::*;
 use super::zone::ZoneContext;
@@ -350,7 +351,10 @@ fn handle_exception(
                         is_write = false;
                         is_u = true;
                     }
-                    _ => panic!("unhandled type"),
+                    _ => {
+                        error!("unhandled load/store type: {:#x}", ins);
+                        zone_fault(ZONE_CRASH_BAD_INSTRUCTION);
+                    }
                 }
                 size = match sz {
                     0b00 => 1,
@@ -415,7 +419,10 @@ fn handle_exception(
                         is_write = false;
                         is_u = true;
                     }
-                    _ => panic!("unhandled type"),
+                    _ => {
+                        error!("unhandled load/store type: {:#x}", ins);
+                        zone_fault(ZONE_CRASH_BAD_INSTRUCTION);
+                    }
                 }
                 size = match sz {
                     0b00 => 1,
@@ -425,7 +432,8 @@ fn handle_exception(
                     _ => panic!("unhandled size"),
                 };
             } else {
-                panic!("unhandled instruction: {:#b}/{:#x}", ins, ins);
+                error!("unhandled instruction: {:#b}/{:#x}", ins, ins);
+                zone_fault(ZONE_CRASH_BAD_INSTRUCTION);
             }
 
             let mut mmio_access = MMIOAccess {
@@ -476,14 +484,16 @@ fn handle_exception(
                         "mmio access failed, error = {:?}, this is a real page fault",
                         e
                     );
-                    panic!("unhandled exception: {}: ecode={:#x}, esubcode={:#x}, era={:#x}, is={:#x}, badi={:#x}, badv={:#x}",
-                    ecode2str(ecode,esubcode), ecode, esubcode, era, is, badi, badv)
+                    error!("unhandled exception: {}: ecode={:#x}, esubcode={:#x}, era={:#x}, is={:#x}, badi={:#x}, badv={:#x}",
+                    ecode2str(ecode,esubcode), ecode, esubcode, era, is, badi, badv);
+                    zone_fault(ZONE_CRASH_BAD_ACCESS);
                 }
             }
         }
         _ => {
-            panic!("unhandled exception: {}: ecode={:#x}, esubcode={:#x}, era={:#x}, is={:#x}, badi={:#x}, badv={:#x}",  
-            ecode2str(ecode,esubcode), ecode, esubcode, era, is, badi, badv)
+            error!("unhandled exception: {}: ecode={:#x}, esubcode={:#x}, era={:#x}, is={:#x}, badi={:#x}, badv={:#x}",
+            ecode2str(ecode,esubcode), ecode, esubcode, era, is, badi, badv);
+            zone_fault(ZONE_CRASH_UNHANDLED_TRAP);
         }
     }
 }
@@ -505,9 +515,8 @@ pub fn _vcpu_return(ctx: usize) {
         trace!("loongarch64: _vcpu_return: no zone found for cpu {}, maybe this is a kernel exception return", this_cpu_id());
         vm_id = 0;
     } else {
-        // since LVZ use GID=0 for hypervisor TLB, we cannot use zone id 0 here
-        // so we add it by 1 - wheatfox
-        vm_id = z.unwrap().read().id + 1;
+        // since LVZ use GID=0 for hypervisor TLB, the zone's vmid is shifted by 1
+        vm_id = super::s2pt::vmid_to_gid(z.unwrap().read().vmid);
     }
     gstat::set_gid(vm_id);
     gstat::set_pgm(true);
@@ -1183,6 +1192,7 @@ fn handle_interrupt(is: usize) {
         _ if is & TIMER_BIT != 0 => {
             use loongArch64::register;
             register::ticlr::clear_timer_interrupt();
+            crate::device::watchdog::check_expiry();
         }
         _ => {
             info!("not handled interrupt");
@@ -1304,6 +1314,31 @@ fn emulate_idle(ins: usize, ctx: &mut ZoneContext) {
     // }
 }
 
+const IOCSR_IPI_SEND: usize = 0x1040;
+const IOCSR_MAIL_SEND: usize = 0x1048;
+const IOCSR_SEND_CPU_SHIFT: usize = 16;
+const IOCSR_SEND_CPU_MASK: usize = 0x3ff << IOCSR_SEND_CPU_SHIFT;
+
+/// IPI_SEND and MAIL_SEND name the target by cpu id, and the guest uses the
+/// vcpu ids it reads from gcsr_cpuid. Returns None if the target is not a
+/// vcpu of the current zone.
+fn iocsr_send_to_physical(addr: usize, val: usize) -> Option<usize> {
+    if addr != IOCSR_IPI_SEND && addr != IOCSR_MAIL_SEND {
+        return Some(val);
+    }
+    let vcpu = (val & IOCSR_SEND_CPU_MASK) >> IOCSR_SEND_CPU_SHIFT;
+    match this_zone().read().vcpu_to_cpu(vcpu) {
+        Some(cpu) => Some((val & !IOCSR_SEND_CPU_MASK) | cpu << IOCSR_SEND_CPU_SHIFT),
+        None => {
+            warn!(
+                "loongarch64: drop iocsr send to vcpu {} outside the zone",
+                vcpu
+            );
+            None
+        }
+    }
+}
+
 fn emulate_iocsr(ins: usize, ctx: &mut ZoneContext) {
     // iocsrrd.b rd, rj     0000011001 001000000000 rj[9:5] rd[4:0]
     // iocsrrd.h rd, rj     0000011001 001000000001 rj[9:5] rd[4:0]
@@ -1375,15 +1410,19 @@ fn emulate_iocsr(ins: usize, ctx: &mut ZoneContext) {
         6 => {
             // iocsrwr.w
             // iocsrwr.w(GPR[rd], GPR[rj])
-            unsafe {
-                asm!("iocsrwr.w {}, {}", in(reg) ctx.x[rd], in(reg) ctx.x[rj]);
+            if let Some(val) = iocsr_send_to_physical(ctx.x[rj], ctx.x[rd]) {
+                unsafe {
+                    asm!("iocsrwr.w {}, {}", in(reg) val, in(reg) ctx.x[rj]);
+                }
             }
         }
         7 => {
             // iocsrwr.d
             // iocsrwr.d(GPR[rd], GPR[rj])
-            unsafe {
-                asm!("iocsrwr.d {}, {}", in(reg) ctx.x[rd], in(reg) ctx.x[rj]);
+            if let Some(val) = iocsr_send_to_physical(ctx.x[rj], ctx.x[rd]) {
+                unsafe {
+                    asm!("iocsrwr.d {}, {}", in(reg) val, in(reg) ctx.x[rj]);
+                }
             }
         }
         _ => {
@@ -1546,7 +1585,8 @@ fn emulate_instruction(era: usize, ins: usize, ctx: &mut ZoneContext) {
         }
     }
 
-    panic!("Unexpected opcode encountered, ins = {:#x}", ins);
+    error!("Unexpected opcode encountered, ins = {:#x}", ins);
+    zone_fault(ZONE_CRASH_BAD_INSTRUCTION);
 }
 
 /* TLB REFILL HANDLER */
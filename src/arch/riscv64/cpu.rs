@@ -1,4 +1,5 @@
 use super::csr::*;
+use super::sbi::set_timer;
 use crate::arch::Stage2PageTable;
 use crate::percpu::this_cpu_data;
 use crate::{
@@ -6,10 +7,14 @@ use crate::{
     consts::{PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
     memory::PhysAddr,
     memory::{
-        addr::PHYS_VIRT_OFFSET, mm::PARKING_MEMORY_SET, GuestPhysAddr, HostPhysAddr, MemFlags,
-        MemoryRegion, MemorySet, VirtAddr, PARKING_INST_PAGE,
+        addr::PHYS_VIRT_OFFSET,
+        mm::{PARKING_MEMORY_SET, PARKING_VMID},
+        GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, MemorySet, VirtAddr,
+        PARKING_INST_PAGE,
     },
 };
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv::register::sie;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -24,6 +29,12 @@ pub struct ArchCpu {
     pub power_on: bool,
     pub init: bool,
     pub sstc: bool,
+    /// in HART_SUSPEND, waiting for an interrupt
+    pub suspended: bool,
+    /// deadline the guest set with SBI set_timer, in timer ticks
+    pub guest_timer: usize,
+    /// deadline of the hypervisor's own timer, see `hyp_timer_arm`
+    pub hyp_timer: usize,
 }
 
 impl ArchCpu {
@@ -39,12 +50,26 @@ impl ArchCpu {
             power_on: false,
             init: false,
             sstc: false,
+            suspended: false,
+            guest_timer: usize::MAX,
+            hyp_timer: usize::MAX,
         };
         ret
     }
     pub fn get_cpuid(&self) -> usize {
         self.cpuid
     }
+    /// Program the supervisor timer, which the guest shares with the
+    /// hypervisor without sstc, with the earlier of the two deadlines.
+    pub fn program_timer(&self) {
+        let next = self.guest_timer.min(self.hyp_timer);
+        if next == usize::MAX {
+            unsafe { sie::clear_stimer() };
+            return;
+        }
+        set_timer(next);
+        unsafe { sie::set_stimer() };
+    }
     pub fn stack_top(&self) -> VirtAddr {
         PER_CPU_ARRAY_PTR as VirtAddr + (self.get_cpuid() + 1) as usize * PER_CPU_SIZE - 8
     }
@@ -62,7 +87,7 @@ impl ArchCpu {
         }
         self.sstatus = 1 << 8 | 1 << 63 | 3 << 13 | 3 << 15; //SPP
         self.stack_top = self.stack_top() as usize;
-        self.x[10] = cpu_id; //cpu id
+        self.x[10] = cpu_id; //hart id seen by the guest
         self.x[11] = dtb; //dtb addr
                           // trace!("stack_top: {:#x}", self.stack_top);
 
@@ -111,7 +136,7 @@ impl ArchCpu {
         if !self.init {
             self.init(
                 this_cpu_data().cpu_on_entry,
-                this_cpu_data().id,
+                this_cpu_data().vcpu_id(),
                 this_cpu_data().dtb_ipa, //dtb_ipa
             );
             self.init = true;
@@ -130,7 +155,13 @@ impl ArchCpu {
             fn vcpu_arch_entry() -> !;
         }
         assert!(this_cpu_id() == self.cpuid);
-        self.init(0, this_cpu_data().id, this_cpu_data().dtb_ipa);
+        let cpu_data = this_cpu_data();
+        let _lock = cpu_data.ctrl_lock.lock();
+        self.power_on = false;
+        drop(_lock);
+        self.init(0, this_cpu_data().vcpu_id(), this_cpu_data().dtb_ipa);
+        // let the next run() start from cpu_on_entry again
+        self.init = false;
         // reset current cpu -> pc = 0x0 (wfi)
         PARKING_MEMORY_SET.call_once(|| {
             let parking_code: [u8; 4] = [0x73, 0x00, 0x50, 0x10]; // 1: wfi; b 1b
@@ -139,6 +170,7 @@ impl ArchCpu {
             }
 
             let mut gpm = new_s2_memory_set();
+            gpm.set_vmid(PARKING_VMID);
             gpm.insert(MemoryRegion::new_with_offset_mapper(
                 0 as GuestPhysAddr,
                 unsafe { &PARKING_INST_PAGE as *const _ as HostPhysAddr - PHYS_VIRT_OFFSET },
@@ -184,6 +216,34 @@ pub fn this_cpu_id() -> usize {
     this_cpu_arch().get_cpuid()
 }
 
+/// The common `timebase-frequency`, used unless the host device tree tells.
+pub const DEFAULT_TIMEBASE_FREQ: u64 = 10_000_000;
+
+static TIMEBASE_FREQ: AtomicU64 = AtomicU64::new(DEFAULT_TIMEBASE_FREQ);
+
+/// Use the `timebase-frequency` of the host device tree.
+pub fn set_timebase_freq(freq: u64) {
+    if freq != 0 {
+        TIMEBASE_FREQ.store(freq, Ordering::Relaxed);
+    }
+}
+
+fn timebase_freq() -> u64 {
+    TIMEBASE_FREQ.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer started.
+pub fn time_ms() -> u64 {
+    (riscv::register::time::read() as u128 * 1000 / timebase_freq() as u128) as _
+}
+
+/// Fire the hypervisor's timer on this cpu after `ms` milliseconds.
+pub fn hyp_timer_arm(ms: usize) {
+    let cpu = this_cpu_arch();
+    cpu.hyp_timer = riscv::register::time::read() + ms * timebase_freq() as usize / 1000;
+    cpu.program_timer();
+}
+
 pub fn cpu_start(cpuid: usize, start_addr: usize, opaque: usize) {
     if let Some(e) = sbi_rt::hart_start(cpuid, start_addr, opaque).err() {
         panic!("cpu_start error: {:#x?}", e);
@@ -1,10 +1,11 @@
 #![allow(unused)]
 use super::{
-    csr::{write_csr, CSR_HGATP},
+    csr::{read_csr, write_csr, CSR_HGATP},
     paging::{GenericPTE, Level3PageTable, PagingInstr},
 };
 use bit_field::BitField;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use numeric_enum_macro::numeric_enum;
 use tock_registers::interfaces::Writeable;
 
@@ -12,6 +13,40 @@ use crate::memory::{
     addr::{HostPhysAddr, PhysAddr},
     MemFlags,
 };
+
+/// hgatp.VMID is up to 14 bits wide on rv64, `vmid_probe` finds how many
+/// are implemented.
+pub const MAX_VMID_NUM: usize = 1 << 14;
+
+const HGATP_VMID: core::ops::Range<usize> = 44..58;
+
+/// Whether the harts implement any VMID bits, set by `vmid_probe`.
+static VMID_TAGGED: AtomicBool = AtomicBool::new(false);
+
+/// Write all ones to hgatp.VMID and read back the bits that stick, returns
+/// the number of VMIDs zones may use. Without VMID bits the VMIDs only tell
+/// zones apart in software: they all run with hardware VMID 0 and the whole
+/// G-stage TLB is flushed on each activate.
+pub fn vmid_probe() -> usize {
+    let mut probe = 0usize;
+    probe.set_bits(HGATP_VMID, MAX_VMID_NUM - 1);
+    write_csr!(CSR_HGATP, probe);
+    let vmid_bits = usize::BITS - read_csr!(CSR_HGATP).get_bits(HGATP_VMID).leading_zeros();
+    write_csr!(CSR_HGATP, 0);
+    hfence_gvma_all();
+    info!("riscv64: hgatp.VMID has {} bits", vmid_bits);
+    VMID_TAGGED.store(vmid_bits > 0, Ordering::Relaxed);
+    if vmid_bits == 0 {
+        MAX_VMID_NUM
+    } else {
+        1 << vmid_bits
+    }
+}
+
+fn hfence_gvma_all() {
+    unsafe { core::arch::asm!(".insn r 0x73, 0x0, 0x31, x0, x0, x0") };
+}
+
 // |Reserved|  PPN  |RSW |Attr|
 // |  63-54 | 53-10 |9-8 |7-0 |
 
@@ -152,23 +187,45 @@ impl fmt::Debug for PageTableEntry {
 pub struct S2PTInstr;
 
 impl PagingInstr for S2PTInstr {
-    unsafe fn activate(root_paddr: HostPhysAddr) {
+    unsafe fn activate(root_paddr: HostPhysAddr, vmid: usize) {
         println!("guest stage2 PT activate");
         unsafe {
             let mut bits = 0usize;
             let mode: usize = 8; //Mode::Sv39x4
-            let vmid: usize = 0;
             bits.set_bits(60..64, mode as usize);
-            bits.set_bits(44..58, vmid);
+            let tagged = VMID_TAGGED.load(Ordering::Relaxed);
+            bits.set_bits(HGATP_VMID, if tagged { vmid } else { 0 });
             bits.set_bits(0..44, root_paddr >> 12);
             println!("HGATP: {:#x?}", bits);
             write_csr!(CSR_HGATP, bits);
+            if !tagged {
+                // the previous table's translations are not told apart
+                hfence_gvma_all();
+            }
             //core::arch::asm!("hsfence.vvma");//not supported in rust
         }
     }
 
-    fn flush(_vaddr: Option<usize>) {
-        // do nothing
+    /// `hfence.gvma` for `vaddr`, or for the whole of `vmid` if `None`.
+    fn flush(vaddr: Option<usize>, vmid: usize) {
+        if !VMID_TAGGED.load(Ordering::Relaxed) {
+            // every zone runs with hardware VMID 0
+            return hfence_gvma_all();
+        }
+        unsafe {
+            match vaddr {
+                // hfence.gvma rs1, rs2 takes the guest physical address >> 2
+                Some(gpa) => core::arch::asm!(
+                    ".insn r 0x73, 0x0, 0x31, x0, {0}, {1}",
+                    in(reg) gpa >> 2,
+                    in(reg) vmid,
+                ),
+                None => core::arch::asm!(
+                    ".insn r 0x73, 0x0, 0x31, x0, x0, {0}",
+                    in(reg) vmid,
+                ),
+            }
+        }
     }
 }
 
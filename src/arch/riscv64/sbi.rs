@@ -1,13 +1,20 @@
 //! SBI call wrappers
 
 #![allow(unused)]
+use crate::consts::INVALID_ADDRESS;
 use crate::hypercall::HyperCall;
 use crate::percpu::{get_cpu_data, this_cpu_data};
 
 use super::cpu::ArchCpu;
 use crate::arch::csr::*;
 use crate::event::{send_event, IPI_EVENT_WAKEUP};
-use riscv::register::{hvip, sie};
+use crate::notify::{
+    notify_root, ZONE_CRASH_GUEST_REPORTED, ZONE_CRASH_REBOOT_FAILED, ZONE_EVENT_CPU_OFFLINE,
+    ZONE_EVENT_CRASHED, ZONE_EVENT_EXITED,
+};
+use crate::percpu::this_zone;
+use crate::zone::{is_this_root_zone, this_zone_id, zone_fault, zone_reboot, zone_shutdown};
+use riscv::register::hvip;
 #[allow(non_snake_case)]
 pub mod SBI_EID {
     pub const BASE_EXTID: usize = 0x10;
@@ -16,6 +23,9 @@ pub mod SBI_EID {
     pub const SEND_IPI: usize = 0x735049;
     pub const RFENCE: usize = 0x52464E43;
     pub const PMU: usize = 0x504D55;
+    pub const SRST: usize = 0x53525354;
+    pub const SUSP: usize = 0x53555350;
+    pub const LEGACY_SHUTDOWN: usize = 0x08;
     pub const HVISOR: usize = 0x114514;
 }
 pub const SBI_SUCCESS: i64 = 0;
@@ -25,6 +35,23 @@ pub const SBI_ERR_INVALID_PARAM: i64 = -3;
 pub const SBI_ERR_DENIED: i64 = -4;
 pub const SBI_ERR_INVALID_ADDRESS: i64 = -5;
 pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+pub const SBI_HSM_HART_START: usize = 0;
+pub const SBI_HSM_HART_STOP: usize = 1;
+pub const SBI_HSM_HART_GET_STATUS: usize = 2;
+pub const SBI_HSM_HART_SUSPEND: usize = 3;
+
+pub const SBI_HSM_STATE_STARTED: i64 = 0;
+pub const SBI_HSM_STATE_STOPPED: i64 = 1;
+pub const SBI_HSM_STATE_SUSPENDED: i64 = 4;
+
+pub const SBI_HSM_SUSPEND_RETENTIVE: u32 = 0x0000_0000;
+pub const SBI_HSM_SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
+
+pub const SBI_SRST_RESET_TYPE_SHUTDOWN: usize = 0;
+pub const SBI_SRST_RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const SBI_SRST_RESET_TYPE_WARM_REBOOT: usize = 2;
+pub const SBI_SRST_RESET_REASON_SYSFAIL: usize = 1;
 pub struct SbiRet {
     error: i64,
     value: i64,
@@ -96,8 +123,8 @@ pub fn sbi_vs_handler(current_cpu: &mut ArchCpu) {
             sbi_ret = sbi_call_5(
                 eid,
                 fid,
-                current_cpu.x[10],
-                current_cpu.x[11],
+                hart_mask_to_physical(current_cpu.x[10], current_cpu.x[11]),
+                0,
                 current_cpu.x[12],
                 current_cpu.x[13],
                 current_cpu.x[14],
@@ -108,8 +135,8 @@ pub fn sbi_vs_handler(current_cpu: &mut ArchCpu) {
             sbi_ret = sbi_call_5(
                 eid,
                 fid,
-                current_cpu.x[10],
-                current_cpu.x[11],
+                hart_mask_to_physical(current_cpu.x[10], current_cpu.x[11]),
+                0,
                 current_cpu.x[12],
                 current_cpu.x[13],
                 current_cpu.x[14],
@@ -127,6 +154,23 @@ pub fn sbi_vs_handler(current_cpu: &mut ArchCpu) {
                 current_cpu.x[14],
             );
         }
+        SBI_EID::SRST => {
+            info!("SBI_EID::SRST on CPU {}", current_cpu.cpuid);
+            sbi_ret = sbi_srst_handler(fid, current_cpu);
+        }
+        // the legacy shutdown and system suspend would take the whole
+        // machine down, only the root zone may reach the firmware with them
+        SBI_EID::LEGACY_SHUTDOWN if !is_this_root_zone() => {
+            info!("SBI_EID::LEGACY_SHUTDOWN on CPU {}", current_cpu.cpuid);
+            sbi_zone_shutdown(current_cpu, false);
+        }
+        SBI_EID::SUSP if !is_this_root_zone() => {
+            info!("SBI_EID::SUSP on CPU {}", current_cpu.cpuid);
+            sbi_ret = SbiRet {
+                error: SBI_ERR_NOT_SUPPORTED,
+                value: 0,
+            };
+        }
         SBI_EID::HVISOR => {
             trace!("SBI_EID::HVISOR,fid:{:#x}", fid);
             sbi_ret = sbi_hvisor_handler(current_cpu);
@@ -187,13 +231,10 @@ pub fn sbi_time_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
     if current_cpu.sstc {
         write_csr!(CSR_VSTIMECMP, stime);
     } else {
-        set_timer(stime);
-        unsafe {
-            // clear guest timer interrupt pending
-            hvip::clear_vstip();
-            // enable timer interrupt
-            sie::set_stimer();
-        }
+        current_cpu.guest_timer = stime;
+        // clear guest timer interrupt pending
+        unsafe { hvip::clear_vstip() };
+        current_cpu.program_timer();
     }
     //debug!("SBI_SET_TIMER stime: {:#x}", stime);
     return sbi_ret;
@@ -204,43 +245,253 @@ pub fn sbi_hsm_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
         value: 0,
     };
     match fid {
-        0 => {
+        SBI_HSM_HART_START => {
             // hsm start
             sbi_ret = sbi_hsm_start_handler(current_cpu);
         }
+        SBI_HSM_HART_STOP => {
+            sbi_hsm_stop_handler(current_cpu);
+        }
+        SBI_HSM_HART_GET_STATUS => {
+            sbi_ret = sbi_hsm_status_handler(current_cpu);
+        }
+        SBI_HSM_HART_SUSPEND => {
+            sbi_ret = sbi_hsm_suspend_handler(current_cpu);
+        }
         _ => {
             error!("Unsupported HSM function {:#x}", fid);
+            sbi_ret.error = SBI_ERR_NOT_SUPPORTED;
         }
     }
     sbi_ret
 }
+
+/// Translate the virtual hart id a guest passes to HSM into the physical cpu
+/// id, only harts of the current zone are valid targets.
+fn hsm_target_cpu(hartid: usize) -> Option<usize> {
+    let cpu = this_zone().read().vcpu_to_cpu(hartid);
+    if cpu.is_none() {
+        warn!("sbi: hart {} is not in the current zone", hartid);
+    }
+    cpu
+}
+
+/// Translate a guest (hart_mask, hart_mask_base) pair over virtual hart ids
+/// into a physical hart mask with base 0. Harts outside the zone are dropped.
+fn hart_mask_to_physical(hart_mask: usize, hart_mask_base: usize) -> usize {
+    let zone = this_zone();
+    let zone_r = zone.read();
+    if hart_mask_base == usize::MAX {
+        // all harts of the zone
+        return zone_r.cpu_set.bitmap as usize;
+    }
+    let mut mask = 0;
+    for bit in 0..usize::BITS as usize {
+        if hart_mask & (1 << bit) == 0 {
+            continue;
+        }
+        let hart = match hart_mask_base.checked_add(bit) {
+            Some(hart) => hart,
+            None => {
+                warn!("sbi: skip hart {:#x} + {}", hart_mask_base, bit);
+                continue;
+            }
+        };
+        match zone_r.vcpu_to_cpu(hart) {
+            Some(cpu) => mask |= 1 << cpu,
+            None => warn!("sbi: skip hart {} outside the zone", hart),
+        }
+    }
+    mask
+}
+
+/// Whether `addr` is mapped in the stage-2 page table of the current zone.
+fn guest_addr_valid(addr: usize) -> bool {
+    unsafe { this_zone().read().gpm.page_table_query(addr) }.is_ok()
+}
+
 pub fn sbi_hsm_start_handler(current_cpu: &mut ArchCpu) -> SbiRet {
     let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0,
     };
-    let cpuid = current_cpu.x[10];
+    let cpuid = match hsm_target_cpu(current_cpu.x[10]) {
+        Some(cpuid) => cpuid,
+        None => {
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+            return sbi_ret;
+        }
+    };
 
     if (cpuid == current_cpu.cpuid) {
         sbi_ret.error = SBI_ERR_ALREADY_AVAILABLE;
     } else {
         //TODO:add sbi conext in archcpu
-        let cpuid = current_cpu.x[10];
         let start_addr = current_cpu.x[11];
         let opaque = current_cpu.x[12];
 
+        if !guest_addr_valid(start_addr) {
+            sbi_ret.error = SBI_ERR_INVALID_ADDRESS;
+            return sbi_ret;
+        }
         info!("sbi: try to wake up cpu {} run@{:#x}", cpuid, start_addr);
         let target_cpu = get_cpu_data(cpuid);
-        //todo add power_on check
         let _lock = target_cpu.ctrl_lock.lock();
+        if !target_cpu.in_this_zone() {
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+            return sbi_ret;
+        }
+        if target_cpu.arch_cpu.power_on {
+            sbi_ret.error = SBI_ERR_ALREADY_AVAILABLE;
+            return sbi_ret;
+        }
         target_cpu.cpu_on_entry = start_addr;
         target_cpu.dtb_ipa = opaque;
+        target_cpu.arch_cpu.power_on = true;
         send_event(cpuid, 0, IPI_EVENT_WAKEUP);
 
         drop(_lock);
     }
     sbi_ret
 }
+
+/// Park the calling hart with `power_on = false`, a later HART_START restarts it.
+pub fn sbi_hsm_stop_handler(current_cpu: &mut ArchCpu) -> ! {
+    info!("sbi: cpu {} stop", current_cpu.cpuid);
+    let cpu_data = this_cpu_data();
+    let _lock = cpu_data.ctrl_lock.lock();
+    cpu_data.cpu_on_entry = INVALID_ADDRESS;
+    drop(_lock);
+    notify_root(
+        this_zone_id(),
+        ZONE_EVENT_CPU_OFFLINE,
+        current_cpu.cpuid as _,
+    );
+    current_cpu.idle();
+}
+
+pub fn sbi_hsm_status_handler(current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    let cpuid = match hsm_target_cpu(current_cpu.x[10]) {
+        Some(cpuid) => cpuid,
+        None => {
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+            return sbi_ret;
+        }
+    };
+    let target_cpu = get_cpu_data(cpuid);
+    let _lock = target_cpu.ctrl_lock.lock();
+    sbi_ret.value = if !target_cpu.arch_cpu.power_on {
+        SBI_HSM_STATE_STOPPED
+    } else if target_cpu.arch_cpu.suspended {
+        SBI_HSM_STATE_SUSPENDED
+    } else {
+        SBI_HSM_STATE_STARTED
+    };
+    sbi_ret
+}
+
+pub fn sbi_hsm_suspend_handler(current_cpu: &mut ArchCpu) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    let suspend_type = current_cpu.x[10] as u32;
+    let resume_addr = current_cpu.x[11];
+    let opaque = current_cpu.x[12];
+    let non_retentive = match suspend_type {
+        SBI_HSM_SUSPEND_RETENTIVE => false,
+        SBI_HSM_SUSPEND_NON_RETENTIVE => true,
+        // platform specific types keep the retentive bit layout
+        0x1000_0000..=0x7fff_ffff | 0x9000_0000..=0xffff_ffff => suspend_type & (1 << 31) != 0,
+        _ => {
+            sbi_ret.error = SBI_ERR_INVALID_PARAM;
+            return sbi_ret;
+        }
+    };
+    if non_retentive && !guest_addr_valid(resume_addr) {
+        sbi_ret.error = SBI_ERR_INVALID_ADDRESS;
+        return sbi_ret;
+    }
+
+    current_cpu.suspended = true;
+    unsafe { core::arch::asm!("wfi") };
+    current_cpu.suspended = false;
+
+    if non_retentive {
+        // the hart state is lost, continue at resume_addr like a fresh start
+        // with a0 = hartid and a1 = opaque
+        debug!(
+            "sbi: cpu {} resume from non-retentive suspend@{:#x}",
+            current_cpu.cpuid, resume_addr
+        );
+        let cpu_data = this_cpu_data();
+        cpu_data.cpu_on_entry = resume_addr;
+        cpu_data.dtb_ipa = opaque;
+        current_cpu.init = false;
+        current_cpu.run();
+    }
+    sbi_ret
+}
+
+/// Only the root zone may reset the machine, a reset from any other zone
+/// restarts (or removes, for a shutdown) just that zone.
+pub fn sbi_srst_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let reset_type = current_cpu.x[10];
+    if is_this_root_zone() {
+        return sbi_call_5(
+            SBI_EID::SRST,
+            fid,
+            current_cpu.x[10],
+            current_cpu.x[11],
+            current_cpu.x[12],
+            current_cpu.x[13],
+            current_cpu.x[14],
+        );
+    }
+    if fid != 0 {
+        return SbiRet {
+            error: SBI_ERR_NOT_SUPPORTED,
+            value: 0,
+        };
+    }
+    match reset_type {
+        SBI_SRST_RESET_TYPE_SHUTDOWN => {
+            info!("sbi: zone shutdown requested on CPU {}", current_cpu.cpuid);
+            let crashed = current_cpu.x[11] == SBI_SRST_RESET_REASON_SYSFAIL;
+            sbi_zone_shutdown(current_cpu, crashed);
+        }
+        SBI_SRST_RESET_TYPE_COLD_REBOOT | SBI_SRST_RESET_TYPE_WARM_REBOOT => {
+            info!("sbi: zone reboot requested on CPU {}", current_cpu.cpuid);
+            if let Err(e) = zone_reboot(this_zone(), false) {
+                error!("sbi: zone reboot failed: {:?}", e);
+                zone_fault(ZONE_CRASH_REBOOT_FAILED);
+            }
+            unreachable!();
+        }
+        _ => SbiRet {
+            error: SBI_ERR_INVALID_PARAM,
+            value: 0,
+        },
+    }
+}
+
+/// Remove the (non-root) zone running on this cpu at its own request, as for
+/// PSCI SYSTEM_OFF, and park the cpu.
+fn sbi_zone_shutdown(current_cpu: &mut ArchCpu, crashed: bool) -> ! {
+    let zone_id = this_zone_id();
+    zone_shutdown(this_zone());
+    if crashed {
+        notify_root(zone_id, ZONE_EVENT_CRASHED, ZONE_CRASH_GUEST_REPORTED);
+    } else {
+        notify_root(zone_id, ZONE_EVENT_EXITED, 0);
+    }
+    current_cpu.idle();
+}
+
 pub fn sbi_hvisor_handler(current_cpu: &mut ArchCpu) -> SbiRet {
     let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
@@ -8,11 +8,13 @@ use crate::device::irqchip::aia::aplic::{host_aplic, vaplic_emul_handler};
 use crate::device::irqchip::plic::{host_plic, vplic_global_emul_handler, vplic_hart_emul_handler};
 use crate::event::check_events;
 use crate::memory::{GuestPhysAddr, HostPhysAddr};
+use crate::notify::{ZONE_CRASH_BAD_ACCESS, ZONE_CRASH_BAD_INSTRUCTION, ZONE_CRASH_UNHANDLED_TRAP};
 use crate::platform::qemu_riscv64::*;
+use crate::zone::{is_this_root_zone, zone_fault};
 use core::arch::{asm, global_asm};
+use riscv::register::hvip;
 use riscv::register::mtvec::TrapMode;
 use riscv::register::stvec;
-use riscv::register::{hvip, sie};
 use riscv_decode::Instruction;
 extern "C" {
     fn _hyp_trap_vector();
@@ -83,10 +85,22 @@ pub fn sync_exception_handler(current_cpu: &mut ArchCpu) {
             warn!("trap ins: {:#x}  {:?}", raw_inst, inst);
             // current_cpu.sepc += 4;
             error!("unhandled trap");
-            current_cpu.idle();
+            guest_fault(current_cpu, ZONE_CRASH_UNHANDLED_TRAP);
         }
     }
 }
+/// Stop the faulting zone on this cpu. The root zone can't be stopped and
+/// reported to itself, its cpu just idles as it always did.
+fn guest_fault(current_cpu: &mut ArchCpu, reason: u64) -> ! {
+    if is_this_root_zone() {
+        error!(
+            "root zone fault on cpu {}, reason {}",
+            current_cpu.cpuid, reason
+        );
+        current_cpu.idle();
+    }
+    zone_fault(reason);
+}
 pub fn guest_page_fault_handler(current_cpu: &mut ArchCpu) {
     let addr: HostPhysAddr = read_csr!(CSR_HTVAL) << 2;
     trace!("guest page fault at {:#x}", addr);
@@ -126,10 +140,11 @@ pub fn guest_page_fault_handler(current_cpu: &mut ArchCpu) {
                 current_cpu.sepc += ins_size;
             } else {
                 error!("Invalid instruction at {:#x}", current_cpu.sepc);
-                panic!();
+                guest_fault(current_cpu, ZONE_CRASH_BAD_INSTRUCTION);
             }
         } else {
-            panic!("CPU {} unmaped memmory at {:#x}", current_cpu.cpuid, addr);
+            error!("CPU {} unmaped memmory at {:#x}", current_cpu.cpuid, addr);
+            guest_fault(current_cpu, ZONE_CRASH_BAD_ACCESS);
         }
     }
     #[cfg(feature = "aia")]
@@ -160,9 +175,11 @@ pub fn guest_page_fault_handler(current_cpu: &mut ArchCpu) {
                 current_cpu.sepc += ins_size;
             } else {
                 error!("Invalid instruction at {:#x}", current_cpu.sepc);
+                guest_fault(current_cpu, ZONE_CRASH_BAD_INSTRUCTION);
             }
         } else {
-            panic!("CPU {} unmaped memmory at {:#x}", current_cpu.cpuid, addr);
+            error!("CPU {} unmaped memmory at {:#x}", current_cpu.cpuid, addr);
+            guest_fault(current_cpu, ZONE_CRASH_BAD_ACCESS);
         }
     }
 }
@@ -212,12 +229,21 @@ pub fn interrupts_arch_handle(current_cpu: &mut ArchCpu) {
     match trap_code & 0xfff {
         InterruptType::STI => {
             trace!("STI on CPU{}", current_cpu.cpuid);
-            unsafe {
-                hvip::set_vstip();
-                sie::clear_stimer();
+            let now = riscv::register::time::read();
+            if now >= current_cpu.guest_timer {
+                current_cpu.guest_timer = usize::MAX;
+                unsafe { hvip::set_vstip() };
             }
+            let hyp_expired = now >= current_cpu.hyp_timer;
+            if hyp_expired {
+                current_cpu.hyp_timer = usize::MAX;
+            }
+            current_cpu.program_timer();
             trace!("sip{:#x}", read_csr!(CSR_SIP));
             trace!("sie {:#x}", read_csr!(CSR_SIE));
+            if hyp_expired {
+                crate::device::watchdog::check_expiry();
+            }
         }
         InterruptType::SSI => {
             trace!("SSI on CPU {}", current_cpu.cpuid);
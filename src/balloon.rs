@@ -0,0 +1,257 @@
+//! Memory ballooning: a zone gives guest RAM back while it runs and the root
+//! zone may use it until it is handed back.
+//!
+//! The guest talks to a virtio-balloon device whose backend lives in the root
+//! zone, the backend then moves the pages with `HvZoneBalloonInflate` and
+//! `HvZoneBalloonDeflate`.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use crate::config::MEM_TYPE_RAM;
+use crate::consts::PAGE_SIZE;
+use crate::error::HvResult;
+use crate::memory::addr::phys_to_virt;
+use crate::memory::{MemFlags, MemoryRegion};
+use crate::zone::{root_zone, HvZoneMemOp, Zone, ZONE_MEM_EXECUTE, ZONE_MEM_READ, ZONE_MEM_WRITE};
+
+#[cfg(test)]
+mod tests;
+
+/// Guest RAM taken out of a zone by inflating its balloon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BalloonRange {
+    ipa: u64,
+    hpa: u64,
+    size: u64,
+    /// mapped into the root zone by the inflate, which the root zone did not
+    /// map already
+    root_mapped: bool,
+}
+
+// zone id -> ranges out of the zone
+static BALLOONS: Mutex<BTreeMap<usize, Vec<BalloonRange>>> = Mutex::new(BTreeMap::new());
+
+fn record_range(zone_id: usize, range: BalloonRange) {
+    BALLOONS.lock().entry(zone_id).or_default().push(range);
+}
+
+/// Take `[ipa, ipa + size)` out of the ranges of zone `zone_id`, the rest of
+/// the range it lies in stays ballooned.
+fn take_range(zone_id: usize, ipa: u64, size: u64) -> HvResult<BalloonRange> {
+    let mut balloons = BALLOONS.lock();
+    let ranges = match balloons.get_mut(&zone_id) {
+        Some(ranges) => ranges,
+        None => return hv_result_err!(EINVAL, format!("zone {} has no balloon", zone_id)),
+    };
+    let end = ipa + size;
+    let idx = match ranges
+        .iter()
+        .position(|r| r.ipa <= ipa && end <= r.ipa + r.size)
+    {
+        Some(idx) => idx,
+        None => return hv_result_err!(EINVAL, format!("{:#x}..{:#x} is not ballooned", ipa, end)),
+    };
+    let range = ranges.swap_remove(idx);
+    let range_end = range.ipa + range.size;
+    if range.ipa < ipa {
+        ranges.push(BalloonRange {
+            size: ipa - range.ipa,
+            ..range
+        });
+    }
+    if end < range_end {
+        ranges.push(BalloonRange {
+            ipa: end,
+            hpa: range.hpa + (end - range.ipa),
+            size: range_end - end,
+            ..range
+        });
+    }
+    if ranges.is_empty() {
+        balloons.remove(&zone_id);
+    }
+    Ok(BalloonRange {
+        ipa,
+        hpa: range.hpa + (ipa - range.ipa),
+        size,
+        ..range
+    })
+}
+
+/// Whether host page `paddr` of zone `zone_id` is out in the balloon.
+pub fn is_ballooned(zone_id: usize, paddr: usize) -> bool {
+    let paddr = paddr as u64;
+    BALLOONS.lock().get(&zone_id).map_or(false, |ranges| {
+        ranges
+            .iter()
+            .any(|r| r.hpa <= paddr && paddr < r.hpa + r.size)
+    })
+}
+
+fn zero_pages(hpa: u64, size: u64) {
+    for paddr in (hpa as usize..(hpa + size) as usize).step_by(PAGE_SIZE) {
+        let vaddr = phys_to_virt(paddr);
+        #[cfg(target_arch = "loongarch64")]
+        let vaddr = vaddr | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize;
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE) };
+    }
+}
+
+/// Take guest RAM `[op.virtual_start, op.virtual_start + op.size)` out of
+/// `zone` and let the root zone use it.
+pub fn balloon_inflate(zone: &RwLock<Zone>, op: &HvZoneMemOp) -> HvResult {
+    let (zone_id, hpa) = {
+        let mut zone = zone.write();
+        let (ipa, size) = (op.virtual_start, op.size);
+        // the range must be backed by a single RAM region of the zone
+        let hpa = match zone.mem_regions.iter().find(|region| {
+            region.mem_type == MEM_TYPE_RAM
+                && region.virtual_start <= ipa
+                && ipa
+                    .checked_add(size)
+                    .map_or(false, |end| end <= region.virtual_start + region.size)
+        }) {
+            Some(region) => region.physical_start + (ipa - region.virtual_start),
+            None => {
+                return hv_result_err!(EINVAL, format!("{:#x?} is not RAM of zone {}", op, zone.id))
+            }
+        };
+        zone.unmap_ipa(op)?;
+        (zone.id, hpa)
+    };
+
+    // the root zone usually maps all RAM already
+    let root = root_zone();
+    let mut root = root.write();
+    let root_mapped = unsafe { root.gpm.page_table_query(hpa as _) }.is_err();
+    if root_mapped {
+        if let Err(e) = root.gpm.insert(MemoryRegion::new_with_offset_mapper(
+            hpa as _,
+            hpa as _,
+            op.size as _,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+        )) {
+            drop(root);
+            // give the range back, the guest did not lose it
+            zone.write()
+                .map_ipa(&ram_op(hpa, op.virtual_start, op.size))?;
+            return Err(e);
+        }
+    }
+    record_range(
+        zone_id,
+        BalloonRange {
+            ipa: op.virtual_start,
+            hpa,
+            size: op.size,
+            root_mapped,
+        },
+    );
+    Ok(())
+}
+
+/// Hand ballooned guest RAM `[op.virtual_start, op.virtual_start + op.size)`
+/// back to `zone`, zeroed. The root zone must have stopped using it.
+pub fn balloon_deflate(zone: &RwLock<Zone>, op: &HvZoneMemOp) -> HvResult {
+    let zone_id = zone.read().id;
+    let range = take_range(zone_id, op.virtual_start, op.size)?;
+    return_range(zone, zone_id, range)
+}
+
+/// Hand all ballooned guest RAM back to `zone`, zeroed, before it restarts.
+pub fn balloon_zone_deflate(zone: &RwLock<Zone>) -> HvResult {
+    let zone_id = zone.read().id;
+    loop {
+        let range = {
+            let mut balloons = BALLOONS.lock();
+            let range = balloons.get_mut(&zone_id).and_then(|ranges| ranges.pop());
+            if balloons
+                .get(&zone_id)
+                .map_or(false, |ranges| ranges.is_empty())
+            {
+                balloons.remove(&zone_id);
+            }
+            range
+        };
+        match range {
+            Some(range) => return_range(zone, zone_id, range)?,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Take `range` from the root zone and map it into `zone` again, it is
+/// recorded again if that fails.
+fn return_range(zone: &RwLock<Zone>, zone_id: usize, range: BalloonRange) -> HvResult {
+    if range.root_mapped {
+        if let Err(e) = root_zone()
+            .write()
+            .gpm
+            .unmap_range(range.hpa as _, range.size as _)
+        {
+            record_range(zone_id, range);
+            return Err(e);
+        }
+    }
+    // nothing the root zone wrote may reach the guest
+    zero_pages(range.hpa, range.size);
+    if let Err(e) = zone
+        .write()
+        .map_ipa(&ram_op(range.hpa, range.ipa, range.size))
+    {
+        // the range stays out of the zone, but no longer in the root zone
+        record_range(
+            zone_id,
+            BalloonRange {
+                root_mapped: false,
+                ..range
+            },
+        );
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn ram_op(hpa: u64, ipa: u64, size: u64) -> HvZoneMemOp {
+    HvZoneMemOp {
+        physical_start: hpa,
+        virtual_start: ipa,
+        size,
+        flags: ZONE_MEM_READ | ZONE_MEM_WRITE | ZONE_MEM_EXECUTE,
+    }
+}
+
+/// Forget the balloon of a removed zone. Pages the inflate mapped into the
+/// root zone are unmapped from it before the zone's RAM is freed, RAM that
+/// can't be unmapped is leaked rather than given back to the pool.
+pub fn balloon_zone_remove(zone: &mut Zone) {
+    let ranges = match BALLOONS.lock().remove(&zone.id) {
+        Some(ranges) => ranges,
+        None => return,
+    };
+    warn!(
+        "zone {} removed with {:#x} bytes ballooned",
+        zone.id,
+        ranges.iter().map(|r| r.size).sum::<u64>()
+    );
+    for range in ranges.iter().filter(|r| r.root_mapped) {
+        let res = root_zone()
+            .write()
+            .gpm
+            .unmap_range(range.hpa as _, range.size as _);
+        if let Err(e) = res {
+            error!(
+                "{:#x?} of zone {} stays in the root zone: {:?}",
+                range, zone.id, e
+            );
+            let (leaked, kept) = zone
+                .guest_ram
+                .drain(..)
+                .partition::<Vec<_>, _>(|ram| ram.overlaps(range.hpa as _, range.size as _));
+            zone.guest_ram = kept;
+            leaked.into_iter().for_each(core::mem::forget);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use super::*;
+use crate::config::CONFIG_NAME_MAXLEN;
+use crate::error::HvErrorNum;
+
+#[test_case]
+fn test_balloon_ranges() {
+    let zone_id = 45;
+    record_range(
+        zone_id,
+        BalloonRange {
+            ipa: 0x4000_0000,
+            hpa: 0x5000_0000,
+            size: 0x4000,
+            root_mapped: false,
+        },
+    );
+    assert!(is_ballooned(zone_id, 0x5000_3000));
+    assert!(!is_ballooned(zone_id, 0x5000_4000));
+    assert!(!is_ballooned(zone_id + 1, 0x5000_3000));
+
+    let range = take_range(zone_id, 0x4000_1000, 0x2000).unwrap();
+    assert_eq!(range.hpa, 0x5000_1000);
+    assert_eq!(range.size, 0x2000);
+    assert!(is_ballooned(zone_id, 0x5000_0000));
+    assert!(!is_ballooned(zone_id, 0x5000_2000));
+    assert!(is_ballooned(zone_id, 0x5000_3000));
+    assert_eq!(
+        take_range(zone_id, 0x4000_0000, 0x2000).unwrap_err().code(),
+        -(HvErrorNum::EINVAL as isize)
+    );
+
+    balloon_zone_remove(&mut Zone::new(zone_id, &[0; CONFIG_NAME_MAXLEN]));
+    assert!(!is_ballooned(zone_id, 0x5000_0000));
+}
@@ -1,7 +1,8 @@
 use alloc::vec::Vec;
+use core::mem::size_of;
 use spin::Once;
 
-use crate::{arch::zone::HvArchZoneConfig, platform};
+use crate::{arch::zone::HvArchZoneConfig, error::HvResult, platform};
 
 #[cfg(test)]
 mod tests;
@@ -9,9 +10,17 @@ mod tests;
 pub const MEM_TYPE_RAM: u32 = 0;
 pub const MEM_TYPE_IO: u32 = 1;
 pub const MEM_TYPE_VIRTIO: u32 = 2;
+/// `physical_start` of a RAM region asking hvisor to allocate it from the
+/// guest memory pool. The zone's kernel and dtb are then staged by the root
+/// zone at their load paddrs and copied to their load ipas.
+pub const MEM_PHYS_AUTO: u64 = u64::MAX;
 
+// limits of the fixed `HvZoneConfig` layout, the tagged format has none
+// except for the ivc regions
 pub const CONFIG_MAX_MEMORY_REGIONS: usize = 16;
 pub const CONFIG_MAX_INTERRUPTS: usize = 32;
+/// `zone_id` asking hvisor to pick a free id, which `hv_zone_start` returns.
+pub const ZONE_ID_AUTO: u32 = u32::MAX;
 pub const CONFIG_NAME_MAXLEN: usize = 32;
 pub const CONFIG_MAX_IVC_CONGIGS: usize = 2;
 pub const CONFIG_MAX_PCI_DEV: usize = 16;
@@ -166,15 +175,334 @@ impl HvZoneConfig {
     }
 }
 
-pub static mut HV_ROOT_ZONE_CONFIG: Once<HvZoneConfig> = Once::new();
+/// "HVZC", the first word of a tagged zone config.
+pub const ZONE_CONFIG_MAGIC: u32 = 0x435a_5648;
+pub const ZONE_CONFIG_VERSION: u32 = 1;
+pub const ZONE_CONFIG_MAX_SIZE: usize = 64 * 1024;
+/// Records start 8-byte aligned.
+pub const CONFIG_RECORD_ALIGN: usize = 8;
 
-pub fn init() {
-    unsafe { HV_ROOT_ZONE_CONFIG.call_once(|| platform::platform_root_zone_config()) };
+/// An unknown record with this bit set makes the config invalid, other
+/// unknown records are skipped.
+pub const CONFIG_TAG_CRITICAL: u32 = 1 << 31;
+/// `u32`
+pub const CONFIG_TAG_ZONE_ID: u32 = 1;
+/// up to `CONFIG_NAME_MAXLEN` bytes
+pub const CONFIG_TAG_NAME: u32 = 2;
+/// `u64` bitmap of physical cpus
+pub const CONFIG_TAG_CPUS: u32 = 3;
+/// `HvConfigMemoryRegion`s, the record may be repeated
+pub const CONFIG_TAG_MEMORY_REGIONS: u32 = 4;
+/// `u32` irq numbers, the record may be repeated
+pub const CONFIG_TAG_INTERRUPTS: u32 = 5;
+/// `HvIvcConfig`s, the record may be repeated
+pub const CONFIG_TAG_IVC: u32 = 6;
+/// `HvZoneBootConfig`
+pub const CONFIG_TAG_BOOT: u32 = 7;
+/// `HvArchZoneConfig`
+pub const CONFIG_TAG_ARCH: u32 = 8;
+/// `HvPciConfig`
+pub const CONFIG_TAG_PCI: u32 = 9;
+/// `u64` BDFs of the assigned pci devices, the record may be repeated
+pub const CONFIG_TAG_PCI_DEVS: u32 = 10;
+
+/// Start of a tagged zone config, followed by the records.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneConfigHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// of the whole config, header included
+    pub size: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigRecord {
+    pub tag: u32,
+    /// of the payload following the record header
+    pub len: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HvZoneBootConfig {
+    pub entry_point: u64,
+    pub kernel_load_paddr: u64,
+    pub kernel_size: u64,
+    pub dtb_load_paddr: u64,
+    pub dtb_size: u64,
+    /// only used with `MEM_PHYS_AUTO` RAM
+    pub kernel_load_ipa: u64,
+    pub dtb_load_ipa: u64,
+}
+
+/// Copy a `T` out of the start of `bytes`. A shorter payload from a tool that
+/// predates fields appended to `T` leaves them zeroed.
+///
+/// Safety: `T` must be plain data that is valid when zeroed.
+unsafe fn read_pod<T>(bytes: &[u8]) -> T {
+    let mut value = core::mem::zeroed::<T>();
+    let len = bytes.len().min(size_of::<T>());
+    core::ptr::copy_nonoverlapping(bytes.as_ptr(), &mut value as *mut T as *mut u8, len);
+    value
+}
+
+/// Decode the `T`s of an array record.
+///
+/// Safety: as for `read_pod`.
+unsafe fn read_pod_array<T>(tag: u32, payload: &[u8]) -> HvResult<Vec<T>> {
+    if payload.len() % size_of::<T>() != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!("zone config record {:#x} has a partial entry", tag)
+        );
+    }
+    Ok(payload
+        .chunks_exact(size_of::<T>())
+        .map(|entry| read_pod(entry))
+        .collect())
+}
+
+/// Decode a fixed-size scalar record.
+fn read_scalar<T>(tag: u32, payload: &[u8]) -> HvResult<T> {
+    if payload.len() != size_of::<T>() {
+        return hv_result_err!(
+            EINVAL,
+            format!("zone config record {:#x} has a bad size", tag)
+        );
+    }
+    Ok(unsafe { read_pod(payload) })
+}
+
+/// A zone's configuration as the hypervisor uses it, decoded from the fixed
+/// `HvZoneConfig` layout or from the tagged format.
+///
+/// The tagged format is a `HvZoneConfigHeader` followed by records, each a
+/// `HvConfigRecord` and its payload. Fields added later become new records
+/// or grow a struct payload at its end, so tools built against an older
+/// layout keep working.
+#[derive(Debug, Clone)]
+pub struct ZoneConfig {
+    pub zone_id: u32,
+    cpus: u64,
+    memory_regions: Vec<HvConfigMemoryRegion>,
+    interrupts: Vec<u32>,
+    ivc_configs: Vec<HvIvcConfig>,
+    pub entry_point: u64,
+    pub kernel_load_paddr: u64,
+    pub kernel_size: u64,
+    pub dtb_load_paddr: u64,
+    pub dtb_size: u64,
+    /// where staged images go when hvisor allocates the zone's RAM, which
+    /// only the tagged format can ask for
+    pub kernel_load_ipa: u64,
+    pub dtb_load_ipa: u64,
+    pub name: [u8; CONFIG_NAME_MAXLEN],
+    pub arch_config: HvArchZoneConfig,
+    pub pci_config: HvPciConfig,
+    alloc_pci_devs: Vec<u64>,
+}
+
+impl ZoneConfig {
+    /// Decode a config the root zone passed, in either format.
+    pub fn from_bytes(buf: &[u8]) -> HvResult<Self> {
+        if buf.len() >= size_of::<u32>() && unsafe { read_pod::<u32>(buf) } == ZONE_CONFIG_MAGIC {
+            Self::parse(buf)
+        } else if buf.len() == size_of::<HvZoneConfig>() {
+            Self::from_legacy(unsafe { &read_pod::<HvZoneConfig>(buf) })
+        } else {
+            hv_result_err!(EINVAL, "Invalid config!")
+        }
+    }
+
+    pub fn from_legacy(config: &HvZoneConfig) -> HvResult<Self> {
+        if config.num_memory_regions as usize > CONFIG_MAX_MEMORY_REGIONS
+            || config.num_interrupts as usize > CONFIG_MAX_INTERRUPTS
+            || config.num_ivc_configs as usize > CONFIG_MAX_IVC_CONGIGS
+            || config.num_pci_devs as usize > CONFIG_MAX_PCI_DEV
+        {
+            return hv_result_err!(EINVAL, "zone config counts exceed the arrays");
+        }
+        Ok(Self {
+            zone_id: config.zone_id,
+            cpus: config.cpus,
+            memory_regions: config.memory_regions().to_vec(),
+            interrupts: config.interrupts().to_vec(),
+            ivc_configs: config.ivc_config().to_vec(),
+            entry_point: config.entry_point,
+            kernel_load_paddr: config.kernel_load_paddr,
+            kernel_size: config.kernel_size,
+            dtb_load_paddr: config.dtb_load_paddr,
+            dtb_size: config.dtb_size,
+            kernel_load_ipa: 0,
+            dtb_load_ipa: 0,
+            name: config.name,
+            arch_config: config.arch_config.clone(),
+            pci_config: config.pci_config,
+            alloc_pci_devs: config.alloc_pci_devs[..config.num_pci_devs as usize].to_vec(),
+        })
+    }
+
+    /// Decode a tagged config.
+    pub fn parse(buf: &[u8]) -> HvResult<Self> {
+        if buf.len() < size_of::<HvZoneConfigHeader>() {
+            return hv_result_err!(EINVAL, "zone config too small");
+        }
+        let header: HvZoneConfigHeader = unsafe { read_pod(buf) };
+        if header.magic != ZONE_CONFIG_MAGIC {
+            return hv_result_err!(EINVAL, "bad zone config magic");
+        }
+        if header.version != ZONE_CONFIG_VERSION {
+            return hv_result_err!(
+                EINVAL,
+                format!("unsupported zone config version {}", header.version)
+            );
+        }
+        if header.size as usize != buf.len() {
+            return hv_result_err!(EINVAL, "zone config size mismatch");
+        }
+
+        let mut zone_id = None;
+        let mut cpus = None;
+        let mut boot: Option<HvZoneBootConfig> = None;
+        let mut arch_config: Option<HvArchZoneConfig> = None;
+        let mut name = [0; CONFIG_NAME_MAXLEN];
+        let mut memory_regions = Vec::new();
+        let mut interrupts = Vec::new();
+        let mut ivc_configs = Vec::new();
+        let mut pci_config = HvPciConfig::new_empty();
+        let mut alloc_pci_devs = Vec::new();
+
+        let mut offset = size_of::<HvZoneConfigHeader>();
+        while offset < buf.len() {
+            if buf.len() - offset < size_of::<HvConfigRecord>() {
+                return hv_result_err!(EINVAL, "truncated zone config record");
+            }
+            let record: HvConfigRecord = unsafe { read_pod(&buf[offset..]) };
+            let start = offset + size_of::<HvConfigRecord>();
+            let end = start + record.len as usize;
+            if end > buf.len() {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("zone config record {:#x} overruns the config", record.tag)
+                );
+            }
+            let payload = &buf[start..end];
+            let tag = record.tag;
+            match tag & !CONFIG_TAG_CRITICAL {
+                CONFIG_TAG_ZONE_ID => zone_id = Some(read_scalar::<u32>(tag, payload)?),
+                CONFIG_TAG_NAME => {
+                    if payload.len() > CONFIG_NAME_MAXLEN {
+                        return hv_result_err!(EINVAL, "zone name too long");
+                    }
+                    name = [0; CONFIG_NAME_MAXLEN];
+                    name[..payload.len()].copy_from_slice(payload);
+                }
+                CONFIG_TAG_CPUS => cpus = Some(read_scalar::<u64>(tag, payload)?),
+                CONFIG_TAG_MEMORY_REGIONS => {
+                    memory_regions.extend(unsafe { read_pod_array(tag, payload)? })
+                }
+                CONFIG_TAG_INTERRUPTS => {
+                    interrupts.extend(unsafe { read_pod_array::<u32>(tag, payload)? })
+                }
+                CONFIG_TAG_IVC => {
+                    ivc_configs.extend(unsafe { read_pod_array(tag, payload)? });
+                    // guests learn about their ivc regions through a fixed table
+                    if ivc_configs.len() > CONFIG_MAX_IVC_CONGIGS {
+                        return hv_result_err!(E2BIG, "too many ivc configs");
+                    }
+                }
+                CONFIG_TAG_BOOT => boot = Some(unsafe { read_pod(payload) }),
+                CONFIG_TAG_ARCH => arch_config = Some(unsafe { read_pod(payload) }),
+                CONFIG_TAG_PCI => pci_config = unsafe { read_pod(payload) },
+                CONFIG_TAG_PCI_DEVS => {
+                    alloc_pci_devs.extend(unsafe { read_pod_array::<u64>(tag, payload)? })
+                }
+                _ if tag & CONFIG_TAG_CRITICAL != 0 => {
+                    return hv_result_err!(
+                        EINVAL,
+                        format!("unknown critical zone config record {:#x}", tag)
+                    );
+                }
+                _ => warn!("skipping unknown zone config record {:#x}", tag),
+            }
+            offset = (end + CONFIG_RECORD_ALIGN - 1) & !(CONFIG_RECORD_ALIGN - 1);
+        }
+
+        let (zone_id, cpus, boot, arch_config) = match (zone_id, cpus, boot, arch_config) {
+            (Some(zone_id), Some(cpus), Some(boot), Some(arch_config)) => {
+                (zone_id, cpus, boot, arch_config)
+            }
+            _ => {
+                return hv_result_err!(
+                    EINVAL,
+                    "zone config lacks the zone id, cpus, boot or arch record"
+                )
+            }
+        };
+        Ok(Self {
+            zone_id,
+            cpus,
+            memory_regions,
+            interrupts,
+            ivc_configs,
+            entry_point: boot.entry_point,
+            kernel_load_paddr: boot.kernel_load_paddr,
+            kernel_size: boot.kernel_size,
+            dtb_load_paddr: boot.dtb_load_paddr,
+            dtb_size: boot.dtb_size,
+            kernel_load_ipa: boot.kernel_load_ipa,
+            dtb_load_ipa: boot.dtb_load_ipa,
+            name,
+            arch_config,
+            pci_config,
+            alloc_pci_devs,
+        })
+    }
+
+    pub fn memory_regions(&self) -> &[HvConfigMemoryRegion] {
+        &self.memory_regions
+    }
+
+    pub fn memory_regions_mut(&mut self) -> &mut [HvConfigMemoryRegion] {
+        &mut self.memory_regions
+    }
+
+    pub fn interrupts(&self) -> &[u32] {
+        &self.interrupts
+    }
+
+    pub fn cpus(&self) -> Vec<u64> {
+        (0..64u64).filter(|i| (self.cpus >> i) & 1 == 1).collect()
+    }
+
+    pub fn ivc_config(&self) -> &[HvIvcConfig] {
+        &self.ivc_configs
+    }
+
+    pub fn alloc_pci_devs(&self) -> &[u64] {
+        &self.alloc_pci_devs
+    }
 }
 
-pub fn root_zone_config() -> &'static HvZoneConfig {
-    init();
-    unsafe { HV_ROOT_ZONE_CONFIG.get().unwrap() }
+pub static mut HV_ROOT_ZONE_CONFIG: Once<ZoneConfig> = Once::new();
+
+pub fn init(host_dtb: usize) {
+    unsafe {
+        HV_ROOT_ZONE_CONFIG.call_once(|| {
+            ZoneConfig::from_legacy(&platform::platform_root_zone_config(host_dtb)).unwrap()
+        })
+    };
+}
+
+pub fn root_zone_config() -> &'static ZoneConfig {
+    unsafe {
+        HV_ROOT_ZONE_CONFIG
+            .get()
+            .expect("Uninitialized root zone config!")
+    }
 }
 
 pub const IVC_PROTOCOL_USER: u32 = 0x0;
@@ -4,3 +4,120 @@ use super::*;
 fn test_simple_config() {
     // TODO: rewrite test with new gicv2 and gicv3 config system
 }
+
+struct ConfigBuilder {
+    buf: Vec<u8>,
+}
+
+impl ConfigBuilder {
+    fn new() -> Self {
+        let header = HvZoneConfigHeader {
+            magic: ZONE_CONFIG_MAGIC,
+            version: ZONE_CONFIG_VERSION,
+            size: 0,
+            reserved: 0,
+        };
+        let mut builder = Self { buf: Vec::new() };
+        builder.push_bytes(&header);
+        builder
+    }
+
+    fn push_bytes<T>(&mut self, value: &T) {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn record<T>(mut self, tag: u32, payload: &[T]) -> Self {
+        let len = payload.len() * size_of::<T>();
+        self.push_bytes(&HvConfigRecord { tag, len: len as _ });
+        payload.iter().for_each(|value| self.push_bytes(value));
+        while self.buf.len() % CONFIG_RECORD_ALIGN != 0 {
+            self.buf.push(0);
+        }
+        self
+    }
+
+    fn minimal() -> Self {
+        let boot = HvZoneBootConfig {
+            entry_point: 0x8020_0000,
+            ..Default::default()
+        };
+        let arch: HvArchZoneConfig = unsafe { core::mem::zeroed() };
+        Self::new()
+            .record(CONFIG_TAG_ZONE_ID, &[1u32])
+            .record(CONFIG_TAG_CPUS, &[0b110u64])
+            .record(CONFIG_TAG_BOOT, &[boot])
+            .record(CONFIG_TAG_ARCH, &[arch])
+    }
+
+    fn build(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[8..12].copy_from_slice(&size.to_ne_bytes());
+        self.buf
+    }
+}
+
+#[test_case]
+fn test_tagged_config() {
+    let region = HvConfigMemoryRegion {
+        mem_type: MEM_TYPE_RAM,
+        physical_start: 0x9000_0000,
+        virtual_start: 0x9000_0000,
+        size: 0x100_0000,
+    };
+    let buf = ConfigBuilder::minimal()
+        .record(CONFIG_TAG_NAME, b"linux2")
+        .record(CONFIG_TAG_MEMORY_REGIONS, &[region; 20])
+        .record(CONFIG_TAG_INTERRUPTS, &[33u32, 34, 35])
+        .record(CONFIG_TAG_INTERRUPTS, &[36u32])
+        .build();
+    let config = ZoneConfig::from_bytes(&buf).unwrap();
+    assert_eq!(config.zone_id, 1);
+    assert_eq!(config.cpus(), [1, 2]);
+    assert_eq!(config.entry_point, 0x8020_0000);
+    assert_eq!(&config.name[..7], b"linux2\0");
+    // no limit on the number of regions in this format
+    assert_eq!(config.memory_regions().len(), 20);
+    assert_eq!(config.interrupts(), [33, 34, 35, 36]);
+    assert!(config.ivc_config().is_empty());
+}
+
+#[test_case]
+fn test_tagged_config_unknown_records() {
+    let buf = ConfigBuilder::minimal().record(0x7fff, &[0u8; 3]).build();
+    assert!(ZoneConfig::from_bytes(&buf).is_ok());
+
+    let buf = ConfigBuilder::minimal()
+        .record(CONFIG_TAG_CRITICAL | 0x7fff, &[0u8; 3])
+        .build();
+    assert!(ZoneConfig::from_bytes(&buf).is_err());
+}
+
+#[test_case]
+fn test_tagged_config_malformed() {
+    // missing the boot and arch records
+    let buf = ConfigBuilder::new()
+        .record(CONFIG_TAG_ZONE_ID, &[1u32])
+        .record(CONFIG_TAG_CPUS, &[1u64])
+        .build();
+    assert!(ZoneConfig::from_bytes(&buf).is_err());
+
+    // a record running past the end
+    let mut buf = ConfigBuilder::minimal()
+        .record(CONFIG_TAG_INTERRUPTS, &[33u32, 34])
+        .build();
+    buf.truncate(buf.len() - 4);
+    let size = buf.len() as u32;
+    buf[8..12].copy_from_slice(&size.to_ne_bytes());
+    assert!(ZoneConfig::from_bytes(&buf).is_err());
+
+    // a partial array entry
+    let buf = ConfigBuilder::minimal()
+        .record(CONFIG_TAG_INTERRUPTS, &[0u8; 6])
+        .build();
+    assert!(ZoneConfig::from_bytes(&buf).is_err());
+
+    // neither format
+    assert!(ZoneConfig::from_bytes(&[0u8; 16]).is_err());
+}
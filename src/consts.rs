@@ -1,4 +1,6 @@
-use crate::memory::addr::VirtAddr;
+use core::ops::Range;
+
+use crate::memory::addr::{virt_to_phys, PhysAddr, VirtAddr};
 pub use crate::memory::PAGE_SIZE;
 
 /// Size of the hypervisor heap.
@@ -22,6 +24,10 @@ pub const MAX_CPU_NUM: usize = 4;
 
 pub const MAX_ZONE_NUM: usize = 3;
 
+pub fn hv_start() -> VirtAddr {
+    skernel as _
+}
+
 pub fn core_end() -> VirtAddr {
     __core_end as _
 }
@@ -34,6 +40,18 @@ pub fn hv_end() -> VirtAddr {
     mem_pool_start() + HV_MEM_POOL_SIZE
 }
 
+/// Host physical memory hvisor occupies: image, per-cpu data and frame pool.
+pub fn hv_phys_range() -> Range<PhysAddr> {
+    let (start, end) = (hv_start(), hv_end());
+    #[cfg(target_arch = "loongarch64")]
+    let (start, end) = {
+        let prefix = crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize;
+        (start & !prefix, end & !prefix)
+    };
+    virt_to_phys(start)..virt_to_phys(end)
+}
+
 extern "C" {
+    fn skernel();
     fn __core_end();
 }
@@ -423,17 +423,26 @@ pub fn vaplic_emul_handler(current_cpu: &mut ArchCpu, addr: GuestPhysAddr, inst:
         // target
         match inst {
             Instruction::Sw(i) => {
-                let first_cpu = this_cpu_data()
+                let value = current_cpu.x[i.rs2() as usize] as u32;
+                let irq = ((offset - APLIC_TARGET_BASE) / 4) as u32 + 1;
+                // the guest targets its virtual hart ids
+                let vcpu = ((value >> 18) & 0x3F) as usize;
+                let hart = match this_cpu_data()
                     .zone
                     .as_ref()
                     .unwrap()
                     .read()
-                    .cpu_set
-                    .first_cpu()
-                    .unwrap();
-                let value = current_cpu.x[i.rs2() as usize] as u32;
-                let irq = ((offset - APLIC_TARGET_BASE) / 4) as u32 + 1;
-                let hart = ((value >> 18) & 0x3F) + first_cpu as u32;
+                    .vcpu_to_cpu(vcpu)
+                {
+                    Some(cpu) => cpu as u32,
+                    None => {
+                        warn!(
+                            "APLIC target of irq {} to hart {} outside the zone",
+                            irq, vcpu
+                        );
+                        return;
+                    }
+                };
                 if host_aplic.read().get_msimode() {
                     let guest = ((value >> 12) & 0x3F) + 1;
                     let eiid = value & 0xFFF;
@@ -468,4 +477,26 @@ impl Zone {
     pub fn arch_irqchip_reset(&self) {
         //TODO
     }
+
+    /// Retarget the active sources aimed at physical hart `from` to hart `to`.
+    pub fn arch_irqchip_reroute(&self, from: usize, to: usize) {
+        let aplic = host_aplic().read();
+        let msimode = aplic.get_msimode();
+        for irq in 1..((APLIC_IDC_BASE - APLIC_TARGET_BASE) / 4 + 1) as u32 {
+            // an inactive source has no target
+            if aplic.get_sourcecfg(irq) == 0 {
+                continue;
+            }
+            let (hart, guest, eiid) = aplic.get_target_info(irq);
+            if hart as usize != from {
+                continue;
+            }
+            if msimode {
+                aplic.set_target_msi(irq, to as _, guest, eiid);
+            } else {
+                // the low bits hold the priority in direct mode
+                aplic.set_target_direct(irq, to as _, eiid);
+            }
+        }
+    }
 }
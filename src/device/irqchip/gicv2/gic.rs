@@ -1,4 +1,4 @@
-use crate::arch::cpu::this_cpu_id;
+use crate::arch::cpu::{hyp_timer_disarm, this_cpu_id};
 use crate::device::irqchip::gicv2::gicc::GICC;
 use crate::device::irqchip::gicv2::gicd::GICV2_SGIS_NUM;
 use crate::device::irqchip::gicv2::gich::{
@@ -17,6 +17,8 @@ use spin::{Mutex, Once};
 
 pub const MAX_CPU_NUM: usize = 8;
 pub const MAINTENACE_INTERRUPT: u64 = 25;
+/// EL2 physical timer PPI, never forwarded to a guest.
+pub const HYP_TIMER_IRQ: u64 = 26;
 
 pub fn gicv2_handle_irq() {
     if let Some(irq_id) = get_pending_irq() {
@@ -33,6 +35,11 @@ pub fn gicv2_handle_irq() {
             deactivate_irq(irq_id);
         } else if irq_id == MAINTENACE_INTERRUPT as usize {
             handle_maintenace_interrupt();
+        } else if irq_id == HYP_TIMER_IRQ as usize {
+            // only a running watchdog re-arms the timer
+            hyp_timer_disarm();
+            deactivate_irq(irq_id);
+            crate::device::watchdog::check_expiry();
         } else {
             deactivate_irq(irq_id);
             inject_irq(irq_id, false);
@@ -53,7 +60,7 @@ pub fn get_pending_irq() -> Option<usize> {
 // deactivate irq: GIC doesn't care CPU ID.
 pub fn deactivate_irq(irq_id: usize) {
     GICC.set_eoir(irq_id as u32);
-    if irq_id < GICV2_SGIS_NUM {
+    if irq_id < GICV2_SGIS_NUM || irq_id == HYP_TIMER_IRQ as usize {
         GICC.set_dir(irq_id as u32);
     }
 }
@@ -141,6 +141,10 @@ impl GicDistributer {
         self.ITARGETSR[index].get()
     }
 
+    pub fn set_itargetsr(&self, index: usize, value: u32) {
+        self.ITARGETSR[index].set(value);
+    }
+
     pub fn set_sgir(&self, value: u32) {
         self.SGIR.set(value);
     }
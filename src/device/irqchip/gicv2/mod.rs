@@ -1,3 +1,4 @@
+use crate::arch::cpu::hyp_timer_disarm;
 use crate::device::irqchip::gicv2::gic::MAX_CPU_NUM;
 /// The outer layer is defined using gicv2.
 /// author: ForeverYolo
@@ -59,6 +60,8 @@ pub fn primary_init_early() {
 
 pub fn percpu_init() {
     GICC.init();
+    hyp_timer_disarm();
+    GICD.set_isenabler(0, 1 << gic::HYP_TIMER_IRQ);
 }
 
 pub fn primary_init_late() {
@@ -67,7 +70,28 @@ pub fn primary_init_late() {
 
 impl Zone {
     pub fn arch_irqchip_reset(&self) {
-        // todo
-        panic!("todo: arch_irqchip_reset")
+        for (idx, &mask) in self.irq_bitmap.iter().enumerate() {
+            if idx == 0 {
+                continue;
+            }
+            GICD.set_icenabler(idx, mask);
+            GICD.set_icactiver(idx, mask);
+        }
+    }
+
+    /// Route the zone's SPIs that target physical cpu `from` to `to`.
+    pub fn arch_irqchip_reroute(&self, from: usize, to: usize) {
+        for irq in 32..self.irq_bitmap.len() * 32 {
+            if !self.irq_in_zone(irq as _) {
+                continue;
+            }
+            // one target byte per irq
+            let shift = (irq % 4) * 8;
+            let targets = GICD.get_itargetsr(irq / 4);
+            if targets & (1 << (from + shift)) != 0 {
+                let targets = targets & !(1 << (from + shift)) | 1 << (to + shift);
+                GICD.set_itargetsr(irq / 4, targets);
+            }
+        }
     }
 }
@@ -1,4 +1,5 @@
 use crate::arch::zone::HvArchZoneConfig;
+use crate::device::irqchip::gicv2::gic::HYP_TIMER_IRQ;
 use crate::device::irqchip::gicv2::gicd::{
     get_max_int_num, GICD, GICD_CTRL_REG_OFFSET, GICD_ICACTIVER_REG_OFFSET,
     GICD_ICENABLER_REG_OFFSET, GICD_ICFGR_REG_OFFSET, GICD_ICPENDR_REG_OFFSET,
@@ -52,8 +53,9 @@ impl Zone {
 
     // store the interrupt number in the irq_bitmap.
     pub fn irq_bitmap_init(&mut self, irqs: &[u32]) {
-        // Enable each cpu's sgi and ppi access permission
-        self.irq_bitmap[0] = 0xffff_ffff;
+        // Enable each cpu's sgi and ppi access permission, except for the
+        // hypervisor's own timer
+        self.irq_bitmap[0] = 0xffff_ffff & !(1 << HYP_TIMER_IRQ);
         for irq in irqs {
             self.insert_irq_to_bitmap(*irq);
         }
@@ -79,6 +79,26 @@ pub fn enable_ipi() {
     }
 }
 
+/// Enable a private interrupt handled by the hypervisor itself on this cpu.
+pub fn enable_ppi(irq_id: usize) {
+    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
+
+    unsafe {
+        let gicr_igroupr0 = (base + GICR_IGROUPR) as *mut u32;
+        gicr_igroupr0.write_volatile(gicr_igroupr0.read_volatile() | (1 << irq_id));
+
+        let gicr_ipriorityr0 = (base + GICR_IPRIORITYR) as *mut u32;
+        let offset = irq_id % 4 * 8;
+        let mask = ((1 << 8) - 1) << offset;
+        let p = gicr_ipriorityr0.add(irq_id / 4);
+        let prio = p.read_volatile();
+        p.write_volatile((prio & !mask) | (0x01 << offset));
+
+        let gicr_isenabler0 = (base + GICR_ISENABLER) as *mut u32;
+        gicr_isenabler0.write_volatile(1 << irq_id);
+    }
+}
+
 pub struct LpiPropTable {
     phy_addr: usize,
     frame: Frame,
@@ -116,6 +136,12 @@ impl LpiPropTable {
         self.baser_list[zone_id]
     }
 
+    fn reset_prop_baser(&mut self, zone_id: usize) {
+        if let Some(baser) = self.baser_list.get_mut(zone_id) {
+            *baser = 0;
+        }
+    }
+
     fn enable_one_lpi(&self, lpi: usize) {
         let addr = self.phy_addr + lpi;
         let val: u8 = 0b1;
@@ -142,6 +168,13 @@ pub fn read_prop_baser() -> usize {
     lpt.read_prop_baser(this_zone_id())
 }
 
+/// Forget the property table `zone_id` registered.
+pub fn lpi_zone_remove(zone_id: usize) {
+    if let Some(lpt) = LPT.get() {
+        lpt.lock().reset_prop_baser(zone_id);
+    }
+}
+
 pub fn enable_one_lpi(lpi: usize) {
     let lpt = LPT.get().unwrap().lock();
     lpt.enable_one_lpi(lpi);
@@ -134,6 +134,16 @@ impl Cmdq {
         self.cbaser_list[zone_id]
     }
 
+    fn reset_zone(&mut self, zone_id: usize) {
+        if zone_id >= MAX_ZONE_NUM {
+            return;
+        }
+        self.phy_base_list[zone_id] = 0;
+        self.cbaser_list[zone_id] = 0;
+        self.creadr_list[zone_id] = 0;
+        self.cwriter_list[zone_id] = 0;
+    }
+
     fn set_cwriter(&mut self, zone_id: usize, value: usize) {
         assert!(zone_id < MAX_ZONE_NUM, "Invalid zone id!");
         if value == 0 {
@@ -291,6 +301,13 @@ pub fn gits_init() {
     CT.call_once(|| Mutex::new(CollectionTable::new()));
 }
 
+/// Forget the command queue `zone_id` registered.
+pub fn gits_zone_remove(zone_id: usize) {
+    if let Some(cmdq) = CMDQ.get() {
+        cmdq.lock().reset_zone(zone_id);
+    }
+}
+
 pub fn set_cbaser(value: usize) {
     let mut cmdq = CMDQ.get().unwrap().lock();
     cmdq.set_cbaser(this_zone_id(), value);
@@ -82,7 +82,7 @@ pub mod gits;
 pub mod vgic;
 
 use core::arch::asm;
-use core::ptr::write_volatile;
+use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::AtomicU64;
 
 use alloc::collections::btree_map::BTreeMap;
@@ -92,10 +92,11 @@ use gicr::{init_lpi_prop, GICR_ISENABLER, GICR_SGI_BASE};
 use gits::gits_init;
 use spin::{Mutex, Once};
 
-use self::gicd::{enable_gic_are_ns, GICD_ICACTIVER, GICD_ICENABLER};
+use self::gicd::{enable_gic_are_ns, GICD_ICACTIVER, GICD_ICENABLER, GICD_IROUTER};
 use self::gicr::enable_ipi;
+use self::vgic::{GICD_IROUTER_AFF_MASK, GICD_IROUTER_IRM};
 use crate::arch::aarch64::sysreg::{read_sysreg, smc_arg1, write_sysreg};
-use crate::arch::cpu::this_cpu_id;
+use crate::arch::cpu::{hyp_timer_disarm, this_cpu_id};
 use crate::config::root_zone_config;
 use crate::consts::MAX_CPU_NUM;
 
@@ -103,6 +104,9 @@ use crate::event::check_events;
 use crate::hypercall::SGI_IPI_ID;
 use crate::zone::Zone;
 
+/// EL2 physical timer PPI, never forwarded to a guest.
+pub const HYP_TIMER_IRQ: usize = 26;
+
 const ICH_HCR_UIE: u64 = 1 << 1;
 //TODO: add Distributor init
 pub fn gicc_init() {
@@ -154,6 +158,23 @@ const TIMER_INTERRUPT_PRINT_TIMES: u64 = 50;
 
 pub fn gicv3_handle_irq_el1() {
     while let Some(irq_id) = pending_irq() {
+        if irq_id == HYP_TIMER_IRQ {
+            // the scheduler re-arms the timer on every tick, otherwise only
+            // a running watchdog does
+            #[cfg(not(feature = "vcpu_sched"))]
+            hyp_timer_disarm();
+            deactivate_irq(irq_id);
+            #[cfg(feature = "vcpu_sched")]
+            crate::scheduler::timer_tick();
+            crate::device::watchdog::check_expiry();
+            continue;
+        }
+        // an spi of a zone that is waiting in the run queue
+        #[cfg(feature = "vcpu_sched")]
+        if is_spi(irq_id as _) && crate::scheduler::defer_irq(irq_id) {
+            deactivate_irq(irq_id);
+            continue;
+        }
         if irq_id < 8 {
             deactivate_irq(irq_id);
             let mut ipi_handled = false;
@@ -209,7 +230,7 @@ fn pending_irq() -> Option<usize> {
 
 fn deactivate_irq(irq_id: usize) {
     write_sysreg!(icc_eoir1_el1, irq_id as u64);
-    if irq_id < 16 || irq_id == 25 {
+    if irq_id < 16 || irq_id == 25 || irq_id == HYP_TIMER_IRQ {
         write_sysreg!(icc_dir_el1, irq_id as u64);
     }
 }
@@ -304,6 +325,102 @@ impl PendingIrqs {
             _ => None,
         }
     }
+
+    #[cfg(feature = "vcpu_sched")]
+    fn take_all(&self) -> VecDeque<(usize, bool)> {
+        match self.inner.get(this_cpu_id()) {
+            Some(pending_irqs) => core::mem::take(&mut *pending_irqs.lock()),
+            _ => VecDeque::new(),
+        }
+    }
+}
+
+const ICH_LR_HW: u64 = 1 << 61;
+const ICH_LR_PINTID_SHIFT: u64 = 32;
+const ICH_LR_PINTID_MASK: u64 = 0x1fff << ICH_LR_PINTID_SHIFT;
+
+/// Virtual cpu interface state of a vcpu that is not running.
+#[cfg(feature = "vcpu_sched")]
+#[derive(Debug, Default)]
+pub struct VgicCpuState {
+    lrs: [u64; 16],
+    vmcr: u64,
+    ap1r: [u64; 4],
+    /// virtual interrupts waiting for a list register, including the ones
+    /// raised while the vcpu was descheduled
+    pending: VecDeque<(usize, bool)>,
+}
+
+#[cfg(feature = "vcpu_sched")]
+impl VgicCpuState {
+    /// Move the virtual cpu interface of this cpu into `self`, leaving it empty.
+    pub fn save(&mut self) {
+        let vtr = read_sysreg!(ich_vtr_el2) as usize;
+        let lr_num = (vtr & 0xf) + 1;
+        let elsr = read_sysreg!(ich_elrsr_el2);
+        for i in 0..lr_num {
+            let mut lr = read_lr(i);
+            if elsr & (1 << i) != 0 {
+                lr = 0;
+            }
+            // A private hardware interrupt (the virtual timer) stays active on
+            // this cpu until the guest deactivates it, which would block it for
+            // the next vcpu. Deactivate it now and keep a purely virtual copy.
+            let pintid = (lr & ICH_LR_PINTID_MASK) >> ICH_LR_PINTID_SHIFT;
+            if lr & ICH_LR_HW != 0 && pintid < 32 {
+                write_sysreg!(icc_dir_el1, pintid);
+                lr &= !(ICH_LR_HW | ICH_LR_PINTID_MASK);
+            }
+            self.lrs[i] = lr;
+            write_lr(i, 0);
+        }
+        self.vmcr = read_sysreg!(ich_vmcr_el2);
+        let num_priority_bits = (vtr >> 29) + 1;
+        self.ap1r[0] = read_sysreg!(ICH_AP1R0_EL2);
+        write_sysreg!(ICH_AP1R0_EL2, 0);
+        if num_priority_bits >= 6 {
+            self.ap1r[1] = read_sysreg!(ICH_AP1R1_EL2);
+            write_sysreg!(ICH_AP1R1_EL2, 0);
+        }
+        if num_priority_bits > 6 {
+            self.ap1r[2] = read_sysreg!(ICH_AP1R2_EL2);
+            self.ap1r[3] = read_sysreg!(ICH_AP1R3_EL2);
+            write_sysreg!(ICH_AP1R2_EL2, 0);
+            write_sysreg!(ICH_AP1R3_EL2, 0);
+        }
+        self.pending
+            .extend(PENDING_VIRQS.get().unwrap().take_all().into_iter());
+        enable_maintenace_interrupt(false);
+    }
+
+    /// Load the saved state into the (empty) virtual cpu interface of this cpu.
+    pub fn restore(&mut self) {
+        let vtr = read_sysreg!(ich_vtr_el2) as usize;
+        let lr_num = (vtr & 0xf) + 1;
+        for i in 0..lr_num {
+            write_lr(i, self.lrs[i]);
+        }
+        write_sysreg!(ich_vmcr_el2, self.vmcr);
+        let num_priority_bits = (vtr >> 29) + 1;
+        write_sysreg!(ICH_AP1R0_EL2, self.ap1r[0]);
+        if num_priority_bits >= 6 {
+            write_sysreg!(ICH_AP1R1_EL2, self.ap1r[1]);
+        }
+        if num_priority_bits > 6 {
+            write_sysreg!(ICH_AP1R2_EL2, self.ap1r[2]);
+            write_sysreg!(ICH_AP1R3_EL2, self.ap1r[3]);
+        }
+        while let Some((irq_id, is_hardware)) = self.pending.pop_front() {
+            inject_irq(irq_id, is_hardware);
+        }
+    }
+
+    /// Remember `irq_id` for injection once the vcpu runs again.
+    pub fn add_pending(&mut self, irq_id: usize, is_hardware: bool) {
+        if !self.pending.contains(&(irq_id, is_hardware)) {
+            self.pending.push_back((irq_id, is_hardware));
+        }
+    }
 }
 
 // Enable or disable an underflow maintenace interrupt.
@@ -473,6 +590,14 @@ pub fn primary_init_late() {
 pub fn percpu_init() {
     gicc_init();
     enable_ipi();
+    hyp_timer_disarm();
+    gicr::enable_ppi(HYP_TIMER_IRQ);
+}
+
+/// Drop the per-zone ITS and LPI state of a removed zone.
+pub fn zone_remove(zone_id: usize) {
+    gits::gits_zone_remove(zone_id);
+    gicr::lpi_zone_remove(zone_id);
 }
 
 impl Zone {
@@ -488,4 +613,19 @@ impl Zone {
             }
         }
     }
+
+    /// Route the zone's SPIs that target physical cpu `from` to `to`.
+    pub fn arch_irqchip_reroute(&self, from: usize, to: usize) {
+        let gicd_base = host_gicd_base();
+        for irq in 32..self.irq_bitmap.len() * 32 {
+            if !self.irq_in_zone(irq as _) {
+                continue;
+            }
+            let irouter = (gicd_base + GICD_IROUTER + irq * 8) as *mut usize;
+            let route = unsafe { read_volatile(irouter) };
+            if route & GICD_IROUTER_IRM == 0 && route & GICD_IROUTER_AFF_MASK == from {
+                unsafe { write_volatile(irouter, to) };
+            }
+        }
+    }
 }
@@ -15,6 +15,10 @@ use crate::{
     zone::{this_zone_id, Zone},
 };
 
+const GICR_TYPER_AFF_FOREIGN: usize = 0xff << 24;
+pub(super) const GICD_IROUTER_IRM: usize = 1 << 31;
+pub(super) const GICD_IROUTER_AFF_MASK: usize = 0xff00ffffff;
+
 pub fn reg_range(base: usize, n: usize, size: usize) -> core::ops::Range<usize> {
     base..(base + (n - 1) * size)
 }
@@ -141,6 +145,16 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
             if cpu == MAX_CPU_NUM - 1 {
                 mmio.value |= GICR_TYPER_LAST;
             }
+            if !mmio.is_write && mmio.size == 8 {
+                // report the virtual mpidr of the owning vcpu, so the guest
+                // finds its redistributor. Foreign ones get an affinity no
+                // vcpu can have.
+                let aff = this_zone()
+                    .read()
+                    .cpu_to_vcpu(cpu)
+                    .unwrap_or(GICR_TYPER_AFF_FOREIGN);
+                mmio.value = (mmio.value & 0xffff_ffff) | aff << 32;
+            }
         }
         GICR_IIDR | 0xffd0..=0xfffc => {
             // Read-only registers that might be used by a zone to find the redistributor corresponding to a CPU. Keep them accessible.
@@ -196,6 +210,7 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
                 if reg == GICR_SGI_BASE + GICR_ICENABLER {
                     mmio.value &= !(1 << MAINTENACE_INTERRUPT);
                     mmio.value &= !(1 << SGI_IPI_ID);
+                    mmio.value &= !(1 << super::HYP_TIMER_IRQ);
                 }
                 // ignore access to foreign redistributors
                 mmio_perform_access(gicr_base, mmio);
@@ -226,6 +241,39 @@ fn vgicv3_handle_irq_ops(mmio: &mut MMIOAccess, irq: u32) -> HvResult {
     Ok(())
 }
 
+/// The guest routes SPIs by virtual mpidr, translate Aff0 between its vcpu
+/// id and the physical cpu of the zone.
+fn vgicv3_handle_irouter(mmio: &mut MMIOAccess, irq: u32) -> HvResult {
+    let zone = this_zone();
+    let zone_r = zone.read();
+
+    if !is_spi(irq) || !zone_r.irq_in_zone(irq) {
+        debug!(
+            "gicd-mmio: skip irq {} access, reg = {:#x?}",
+            irq, mmio.address
+        );
+        return Ok(());
+    }
+
+    if mmio.is_write {
+        let vcpu = mmio.value & GICD_IROUTER_AFF_MASK;
+        let cpu = match zone_r.vcpu_to_cpu(vcpu) {
+            Some(cpu) => cpu,
+            // keep the irq inside the zone
+            None => zone_r.cpu_set.first_cpu().unwrap(),
+        };
+        mmio.value = (mmio.value & GICD_IROUTER_IRM) | cpu;
+        mmio_perform_access(host_gicd_base(), mmio);
+    } else {
+        mmio_perform_access(host_gicd_base(), mmio);
+        let cpu = mmio.value & GICD_IROUTER_AFF_MASK;
+        let vcpu = zone_r.cpu_to_vcpu(cpu).unwrap_or(0);
+        mmio.value = (mmio.value & GICD_IROUTER_IRM) | vcpu;
+    }
+
+    Ok(())
+}
+
 fn vgicv3_dist_misc_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult {
     let reg = mmio.address;
     if reg_range(GICDV3_PIDR0, 4, 4).contains(&reg)
@@ -254,7 +302,7 @@ pub fn vgicv3_dist_handler(mmio: &mut MMIOAccess, _arg: usize) -> HvResult {
 
     match reg {
         reg if reg_range(GICD_IROUTER, 1024, 8).contains(&reg) => {
-            vgicv3_handle_irq_ops(mmio, (reg - GICD_IROUTER) as u32 / 8)
+            vgicv3_handle_irouter(mmio, (reg - GICD_IROUTER) as u32 / 8)
         }
         reg if reg_range(GICD_ITARGETSR, 1024, 1).contains(&reg) => {
             vgicv3_handle_irq_ops(mmio, (reg - GICD_ITARGETSR) as u32)
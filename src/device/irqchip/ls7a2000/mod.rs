@@ -109,6 +109,13 @@ impl Zone {
     pub fn arch_irqchip_reset(&self) {
         warn!("loongarch64: irqchip: arch_irqchip_reset do nothing");
     }
+
+    pub fn arch_irqchip_reroute(&self, from: usize, to: usize) {
+        warn!(
+            "loongarch64: irqchip: arch_irqchip_reroute {} -> {} do nothing",
+            from, to
+        );
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -31,6 +31,15 @@ pub fn gic_handle_irq() {
     gicv3::gicv3_handle_irq_el1();
 }
 
+/// Release hook of `remove_zone`: drop the irqchip state kept for `zone_id`
+/// outside the zone.
+pub fn irqchip_zone_remove(zone_id: usize) {
+    #[cfg(all(feature = "gicv3", target_arch = "aarch64"))]
+    gicv3::zone_remove(zone_id);
+    #[cfg(not(all(feature = "gicv3", target_arch = "aarch64")))]
+    let _ = zone_id;
+}
+
 impl Zone {
     pub fn mmio_init(&mut self, hv_config: &HvArchZoneConfig) {
         #[cfg(all(feature = "gicv2", target_arch = "aarch64"))]
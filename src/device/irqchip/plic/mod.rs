@@ -78,6 +78,26 @@ impl Plic {
         }
     }
 }
+/// Each hart has two plic contexts (M and S mode), the guest numbers them by
+/// its virtual hart id.
+fn vcontext_to_context(vcontext: usize) -> Option<usize> {
+    let cpu = this_cpu_data()
+        .zone
+        .as_ref()
+        .unwrap()
+        .read()
+        .vcpu_to_cpu(vcontext / 2)?;
+    Some(cpu * 2 + vcontext % 2)
+}
+
+/// The contexts of harts outside the zone read as zero and ignore writes.
+fn ignore_access(current_cpu: &mut ArchCpu, addr: GuestPhysAddr, inst: &Instruction) {
+    warn!("PLIC access addr@{:#x} to a hart outside the zone", addr);
+    if let Instruction::Lw(i) = inst {
+        current_cpu.x[i.rd() as usize] = 0;
+    }
+}
+
 pub fn vplic_global_emul_handler(
     current_cpu: &mut ArchCpu,
     addr: GuestPhysAddr,
@@ -105,19 +125,14 @@ pub fn vplic_global_emul_handler(
         }
     } else if offset >= PLIC_ENABLE_BASE && offset < PLIC_GLOBAL_SIZE {
         //enable
+        let vcontext = (offset - 0x002000) / 0x80;
+        let context = match vcontext_to_context(vcontext) {
+            Some(context) => context,
+            None => return ignore_access(current_cpu, addr, &inst),
+        };
         match inst {
             Instruction::Lw(i) => {
                 // guest read
-                let vcontext = (offset - 0x002000) / 0x80;
-                let first_cpu = this_cpu_data()
-                    .zone
-                    .as_ref()
-                    .unwrap()
-                    .read()
-                    .cpu_set
-                    .first_cpu()
-                    .unwrap();
-                let context = vcontext + first_cpu * 2;
                 let irq_base = (offset - 0x002000) % 0x80;
                 let value = host_plic.read().read_enable(context, irq_base);
                 current_cpu.x[i.rd() as usize] = value as usize;
@@ -133,16 +148,6 @@ pub fn vplic_global_emul_handler(
             }
             Instruction::Sw(i) => {
                 // guest write irq enable
-                let vcontext = (offset - 0x002000) / 0x80;
-                let first_cpu = this_cpu_data()
-                    .zone
-                    .as_ref()
-                    .unwrap()
-                    .read()
-                    .cpu_set
-                    .first_cpu()
-                    .unwrap();
-                let context = vcontext + first_cpu * 2;
                 let irq_base = (offset - 0x002000) % 0x80;
                 let value = current_cpu.x[i.rs2() as usize] as u32;
                 host_plic.write().set_enable(context, irq_base, value);
@@ -170,15 +175,10 @@ pub fn vplic_hart_emul_handler(current_cpu: &mut ArchCpu, addr: GuestPhysAddr, i
     // threshold/claim/complete
     if offset >= PLIC_GLOBAL_SIZE && offset < PLIC_TOTAL_SIZE {
         let vcontext = (offset - PLIC_GLOBAL_SIZE) / 0x1000;
-        let first_cpu = this_cpu_data()
-            .zone
-            .as_ref()
-            .unwrap()
-            .read()
-            .cpu_set
-            .first_cpu()
-            .unwrap();
-        let context = vcontext + first_cpu * 2;
+        let context = match vcontext_to_context(vcontext) {
+            Some(context) => context,
+            None => return ignore_access(current_cpu, addr, &inst),
+        };
         let index = (offset - PLIC_GLOBAL_SIZE) & 0xfff;
         if index == 0 {
             // threshold
@@ -235,4 +235,22 @@ impl Zone {
     pub fn arch_irqchip_reset(&self) {
         //TODO
     }
+
+    /// Move the irqs enabled on physical hart `from` to hart `to`, the
+    /// contexts of a hart only hold the enables of its zone.
+    pub fn arch_irqchip_reroute(&self, from: usize, to: usize) {
+        let plic = host_plic().read();
+        for mode in 0..2 {
+            let (from_ctx, to_ctx) = (from * 2 + mode, to * 2 + mode);
+            for irq_base in (0..PLIC_MAX_IRQ / 8).step_by(4) {
+                let enables = plic.read_enable(from_ctx, irq_base);
+                if enables == 0 {
+                    continue;
+                }
+                let moved = plic.read_enable(to_ctx, irq_base) | enables;
+                plic.set_enable(to_ctx, irq_base, moved);
+                plic.set_enable(from_ctx, irq_base, 0);
+            }
+        }
+    }
 }
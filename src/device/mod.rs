@@ -2,3 +2,4 @@ pub mod common;
 pub mod irqchip;
 pub mod uart;
 pub mod virtio_trampoline;
+pub mod watchdog;
@@ -12,6 +12,7 @@ use crate::device::irqchip::inject_irq;
 use crate::event::send_event;
 use crate::event::IPI_EVENT_WAKEUP_VIRTIO_DEVICE;
 use crate::hypercall::SGI_IPI_ID;
+use crate::percpu::{get_cpu_data, CpuSet};
 use crate::zone::root_zone;
 use crate::zone::this_zone_id;
 use crate::{error::HvResult, memory::MMIOAccess};
@@ -93,6 +94,18 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
     Ok(())
 }
 
+/// Release hook of `remove_zone`: drop the virtio irqs still pending for a
+/// removed zone on the cpus it left idle. A cpu running another zone keeps
+/// that zone's irqs.
+pub fn virtio_zone_remove(cpu_set: &CpuSet) {
+    let mut map = VIRTIO_IRQS.lock();
+    for cpu_id in cpu_set.iter() {
+        if get_cpu_data(cpu_id).zone.is_none() {
+            map.remove(&cpu_id);
+        }
+    }
+}
+
 /// When virtio req type is notify, root zone will send sgi to non root, \
 /// and non root will call this function.
 pub fn handle_virtio_irq() {
@@ -0,0 +1,485 @@
+//! Virtual watchdog a zone can be given, so that a hung guest is caught
+//! without the root zone taking part in the timing.
+//!
+//! On aarch64 the device is an SP805, driven by the guest's stock driver;
+//! the other architectures get a minimal generic device. The countdown is
+//! armed on the hypervisor timer of the cpu the guest last programmed the
+//! device from, and every cpu's timer checks the watchdogs of all zones.
+//! When one runs out the zone's `WatchdogAction` is taken and reported to
+//! the root zone.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use numeric_enum_macro::numeric_enum;
+use spin::Mutex;
+
+use crate::arch::cpu::time_ms;
+use crate::consts::PAGE_SIZE;
+use crate::error::HvResult;
+use crate::memory::{GuestPhysAddr, MMIOAccess};
+use crate::notify::{
+    notify_root, ZONE_CRASH_REBOOT_FAILED, ZONE_CRASH_WATCHDOG, ZONE_EVENT_WATCHDOG,
+};
+use crate::percpu::this_cpu_data;
+use crate::zone::{find_zone, this_zone_id, zone_crash, zone_reboot, Zone};
+#[cfg(target_arch = "aarch64")]
+use crate::{
+    event::{send_zone_event, IPI_EVENT_WATCHDOG_IRQ},
+    hypercall::SGI_IPI_ID,
+};
+
+#[cfg(test)]
+mod tests;
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum WatchdogAction {
+        /// restart the zone from its entry point
+        Reset = 0,
+        /// stop the zone, it is kept until the root zone shuts it down
+        Shutdown = 1,
+        /// only report the expiry to the root zone
+        Notify = 2,
+    }
+}
+
+/// A zone's watchdog, as passed by the root zone with `HvZoneWatchdogInit`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvWatchdogConfig {
+    /// guest physical address of the device's page, must not be mapped
+    pub base: u64,
+    /// irq raised on the first timeout (SP805 only), 0 for none
+    pub irq: u32,
+    /// one of `WatchdogAction`
+    pub action: u32,
+}
+
+/// Rate of the SP805 counter, the guest's device tree gives the device a
+/// fixed clock of this rate.
+#[cfg(target_arch = "aarch64")]
+pub const SP805_CLOCK_HZ: u64 = 1_000_000;
+
+#[cfg(target_arch = "aarch64")]
+mod regs {
+    pub const SP805_LOAD: usize = 0x000;
+    pub const SP805_VALUE: usize = 0x004;
+    pub const SP805_CTRL: usize = 0x008;
+    pub const SP805_INTCLR: usize = 0x00c;
+    pub const SP805_RIS: usize = 0x010;
+    pub const SP805_MIS: usize = 0x014;
+    pub const SP805_LOCK: usize = 0xc00;
+    /// PeriphID0-3 and PCellID0-3
+    pub const SP805_ID: usize = 0xfe0;
+    pub const SP805_IDS: [usize; 8] = [0x05, 0x18, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+    pub const SP805_CTRL_INTEN: u32 = 1 << 0;
+    pub const SP805_CTRL_RESEN: u32 = 1 << 1;
+    pub const SP805_UNLOCK_KEY: usize = 0x1acc_e551;
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+mod regs {
+    /// reads `WDT_MAGIC`
+    pub const WDT_ID: usize = 0x00;
+    /// bit 0 starts the countdown, clearing it stops it
+    pub const WDT_CTRL: usize = 0x04;
+    /// timeout in milliseconds
+    pub const WDT_TIMEOUT: usize = 0x08;
+    /// any write restarts the countdown
+    pub const WDT_KICK: usize = 0x0c;
+    /// milliseconds left, read only
+    pub const WDT_REMAINING: usize = 0x10;
+
+    pub const WDT_MAGIC: usize = 0x4856_5744; // "HVWD"
+    pub const WDT_CTRL_EN: u32 = 1 << 0;
+    pub const WDT_DEFAULT_TIMEOUT_MS: u64 = 10_000;
+}
+
+use regs::*;
+
+/// What a deadline check found.
+#[derive(Debug, PartialEq, Eq)]
+enum Poll {
+    /// the watchdog is stopped
+    Idle,
+    /// the watchdog runs out in the given milliseconds
+    Pending(u64),
+    /// SP805 first timeout: raise the irq, the counter restarts and runs out
+    /// again in the given milliseconds
+    #[cfg(target_arch = "aarch64")]
+    Interrupt(u64),
+    /// take the action
+    Bite,
+}
+
+struct Watchdog {
+    base: GuestPhysAddr,
+    action: WatchdogAction,
+    /// reload value, counter ticks on the SP805 and milliseconds otherwise
+    load: u64,
+    ctrl: u32,
+    #[cfg(target_arch = "aarch64")]
+    irq: u32,
+    /// register write lock
+    #[cfg(target_arch = "aarch64")]
+    locked: bool,
+    /// raw interrupt status, set on the first timeout
+    #[cfg(target_arch = "aarch64")]
+    raised: bool,
+    /// when the counter runs out, `None` while it is stopped
+    deadline_ms: Option<u64>,
+}
+
+/// zone id -> the zone's watchdog
+static WATCHDOGS: Mutex<BTreeMap<usize, Watchdog>> = Mutex::new(BTreeMap::new());
+
+impl Watchdog {
+    fn new(base: GuestPhysAddr, _irq: u32, action: WatchdogAction) -> Self {
+        let mut wdt = Self {
+            base,
+            action,
+            load: 0,
+            ctrl: 0,
+            #[cfg(target_arch = "aarch64")]
+            irq: _irq,
+            #[cfg(target_arch = "aarch64")]
+            locked: false,
+            #[cfg(target_arch = "aarch64")]
+            raised: false,
+            deadline_ms: None,
+        };
+        wdt.reset();
+        wdt
+    }
+
+    /// Back to the power-on state, stopped.
+    fn reset(&mut self) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            self.load = 0xffff_ffff;
+            self.locked = false;
+            self.raised = false;
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        {
+            self.load = WDT_DEFAULT_TIMEOUT_MS;
+        }
+        self.ctrl = 0;
+        self.deadline_ms = None;
+    }
+
+    fn period_ms(&self) -> u64 {
+        #[cfg(target_arch = "aarch64")]
+        let period = (self.load + 1) * 1000 / SP805_CLOCK_HZ;
+        #[cfg(not(target_arch = "aarch64"))]
+        let period = self.load;
+        period.max(1)
+    }
+
+    /// Start counting down from the reload value, returns the milliseconds
+    /// the timer needs to be armed for.
+    fn restart(&mut self, now: u64) -> u64 {
+        let period = self.period_ms();
+        self.deadline_ms = Some(now + period);
+        period
+    }
+
+    fn remaining_ms(&self, now: u64) -> u64 {
+        self.deadline_ms
+            .map_or(0, |deadline| deadline.saturating_sub(now))
+    }
+
+    fn poll(&mut self, now: u64) -> Poll {
+        match self.deadline_ms {
+            None => return Poll::Idle,
+            Some(deadline) if now < deadline => return Poll::Pending(deadline - now),
+            _ => {}
+        }
+        // the SP805 interrupts on the first timeout and only resets on the
+        // second one, if the interrupt wasn't cleared meanwhile
+        #[cfg(target_arch = "aarch64")]
+        if !self.raised {
+            self.raised = true;
+            return Poll::Interrupt(self.restart(now));
+        } else if self.ctrl & SP805_CTRL_RESEN == 0 {
+            return Poll::Pending(self.restart(now));
+        }
+        self.deadline_ms = None;
+        Poll::Bite
+    }
+
+    /// Emulate a register access, returns the milliseconds to arm the timer
+    /// for if the countdown was (re)started.
+    #[cfg(target_arch = "aarch64")]
+    fn access(&mut self, mmio: &mut MMIOAccess, now: u64) -> Option<u64> {
+        let offset = mmio.address;
+        if !mmio.is_write {
+            mmio.value = match offset {
+                SP805_LOAD => self.load as _,
+                SP805_VALUE if self.deadline_ms.is_some() => {
+                    (self.remaining_ms(now) * SP805_CLOCK_HZ / 1000) as _
+                }
+                SP805_VALUE => self.load as _,
+                SP805_CTRL => self.ctrl as _,
+                SP805_RIS => self.raised as _,
+                SP805_MIS => (self.raised && self.ctrl & SP805_CTRL_INTEN != 0) as _,
+                SP805_LOCK => self.locked as _,
+                SP805_ID..=0xffc => SP805_IDS[(offset - SP805_ID) / 4],
+                _ => 0,
+            };
+            return None;
+        }
+        if offset == SP805_LOCK {
+            self.locked = mmio.value != SP805_UNLOCK_KEY;
+            return None;
+        }
+        if self.locked {
+            return None;
+        }
+        let running = self.ctrl & SP805_CTRL_INTEN != 0;
+        match offset {
+            SP805_LOAD => {
+                self.load = mmio.value as u32 as _;
+                running.then(|| self.restart(now))
+            }
+            SP805_CTRL => {
+                self.ctrl = mmio.value as u32 & (SP805_CTRL_INTEN | SP805_CTRL_RESEN);
+                match (running, self.ctrl & SP805_CTRL_INTEN != 0) {
+                    (false, true) => Some(self.restart(now)),
+                    (true, false) => {
+                        self.deadline_ms = None;
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            SP805_INTCLR => {
+                self.raised = false;
+                running.then(|| self.restart(now))
+            }
+            _ => None,
+        }
+    }
+
+    /// Emulate a register access, returns the milliseconds to arm the timer
+    /// for if the countdown was (re)started.
+    #[cfg(not(target_arch = "aarch64"))]
+    fn access(&mut self, mmio: &mut MMIOAccess, now: u64) -> Option<u64> {
+        let running = self.ctrl & WDT_CTRL_EN != 0;
+        if !mmio.is_write {
+            mmio.value = match mmio.address {
+                WDT_ID => WDT_MAGIC,
+                WDT_CTRL => self.ctrl as _,
+                WDT_TIMEOUT => self.load as _,
+                WDT_REMAINING => self.remaining_ms(now) as _,
+                _ => 0,
+            };
+            return None;
+        }
+        match mmio.address {
+            WDT_CTRL => {
+                self.ctrl = mmio.value as u32 & WDT_CTRL_EN;
+                match (running, self.ctrl & WDT_CTRL_EN != 0) {
+                    (false, true) => Some(self.restart(now)),
+                    (true, false) => {
+                        self.deadline_ms = None;
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            WDT_TIMEOUT => {
+                self.load = mmio.value as u32 as _;
+                running.then(|| self.restart(now))
+            }
+            WDT_KICK => running.then(|| self.restart(now)),
+            _ => None,
+        }
+    }
+}
+
+/// The vcpu scheduler ticks every time slice and checks the watchdogs then,
+/// otherwise the timer is armed for the deadline.
+fn arm_timer(_ms: u64) {
+    #[cfg(not(feature = "vcpu_sched"))]
+    crate::arch::cpu::hyp_timer_arm(_ms as _);
+}
+
+impl Zone {
+    /// Give the zone a watchdog at `config.base`.
+    pub fn watchdog_init(&mut self, config: &HvWatchdogConfig) -> HvResult {
+        let action = match WatchdogAction::try_from(config.action) {
+            Ok(action) => action,
+            Err(_) => {
+                return hv_result_err!(EINVAL, format!("invalid watchdog action {}", config.action))
+            }
+        };
+        let base = config.base as GuestPhysAddr;
+        if base == 0 || base % PAGE_SIZE != 0 {
+            return hv_result_err!(EINVAL, "watchdog base must be page aligned");
+        }
+        if self.mem_regions.iter().any(|region| {
+            let start = region.virtual_start as usize;
+            base < start + region.size as usize && start < base + PAGE_SIZE
+        }) || self.find_mmio_region(base, PAGE_SIZE).is_some()
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("watchdog at {:#x} overlaps the zone's memory", base)
+            );
+        }
+        #[cfg(target_arch = "aarch64")]
+        if config.irq != 0 && !self.irq_in_zone(config.irq) {
+            return hv_result_err!(
+                EINVAL,
+                format!("watchdog irq {} is not in the zone", config.irq)
+            );
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        if config.irq != 0 {
+            return hv_result_err!(EINVAL, "the generic watchdog has no irq");
+        }
+
+        let mut watchdogs = WATCHDOGS.lock();
+        if watchdogs.contains_key(&self.id) {
+            return hv_result_err!(EEXIST, format!("zone {} has a watchdog", self.id));
+        }
+        watchdogs.insert(self.id, Watchdog::new(base, config.irq, action));
+        drop(watchdogs);
+        self.mmio_region_register(base, PAGE_SIZE, mmio_watchdog_handler, 0);
+        info!(
+            "zone {} watchdog at {:#x}, action {:?}",
+            self.id, base, action
+        );
+        Ok(())
+    }
+}
+
+/// Stop the zone's watchdog, on a reboot.
+pub fn watchdog_reset(zone_id: usize) {
+    if let Some(wdt) = WATCHDOGS.lock().get_mut(&zone_id) {
+        wdt.reset();
+    }
+}
+
+/// Drop the zone's watchdog, on a shutdown.
+pub fn watchdog_remove(zone_id: usize) {
+    WATCHDOGS.lock().remove(&zone_id);
+}
+
+pub fn mmio_watchdog_handler(mmio: &mut MMIOAccess, _arg: usize) -> HvResult {
+    let zone_id = this_zone_id();
+    let mut watchdogs = WATCHDOGS.lock();
+    let wdt = match watchdogs.get_mut(&zone_id) {
+        Some(wdt) => wdt,
+        None => return hv_result_err!(ENODEV),
+    };
+    let now = time_ms();
+    let restarted = wdt.access(mmio, now).is_some();
+    trace!(
+        "zone {} watchdog {:#x} {} {:#x}",
+        zone_id,
+        wdt.base + mmio.address,
+        if mmio.is_write { "<-" } else { "->" },
+        mmio.value
+    );
+    if restarted {
+        // this cpu's timer may be armed for the watchdog of a zone that ran
+        // here before, keep the earliest deadline
+        let next_ms = watchdogs
+            .values()
+            .filter_map(|wdt| wdt.deadline_ms)
+            .min()
+            .map(|deadline| deadline.saturating_sub(now));
+        if let Some(ms) = next_ms {
+            arm_timer(ms);
+        }
+    }
+    Ok(())
+}
+
+/// Check the watchdogs of all zones, called on the hypervisor timer of any
+/// cpu. The cpu that armed the timer for a countdown may have moved to
+/// another zone since, so the zone that runs here doesn't matter. Doesn't
+/// return if the watchdog of the zone on this cpu resets or stops it.
+pub fn check_expiry() {
+    let running = this_cpu_data().zone.as_ref().map(|zone| zone.read().id);
+    let now = time_ms();
+    let mut next_ms = None;
+    let mut bitten = Vec::new();
+    #[cfg(target_arch = "aarch64")]
+    let mut raised = Vec::new();
+    let mut watchdogs = WATCHDOGS.lock();
+    for (&zone_id, wdt) in watchdogs.iter_mut() {
+        match wdt.poll(now) {
+            Poll::Idle => {}
+            Poll::Pending(ms) => next_ms = Some(next_ms.map_or(ms, |next: u64| next.min(ms))),
+            #[cfg(target_arch = "aarch64")]
+            Poll::Interrupt(ms) => {
+                next_ms = Some(next_ms.map_or(ms, |next: u64| next.min(ms)));
+                if wdt.irq == 0 {
+                    continue;
+                }
+                if running == Some(zone_id) {
+                    crate::device::irqchip::inject_irq(wdt.irq as _, false);
+                } else {
+                    raised.push(zone_id);
+                }
+            }
+            Poll::Bite => bitten.push((zone_id, wdt.action)),
+        }
+    }
+    drop(watchdogs);
+    if let Some(ms) = next_ms {
+        arm_timer(ms);
+    }
+
+    // the irq of a zone that doesn't run here is raised by its boot cpu, or
+    // once its vcpu runs again
+    #[cfg(target_arch = "aarch64")]
+    for zone_id in raised {
+        if let Some(zone) = find_zone(zone_id) {
+            let boot_cpu = zone.read().boot_cpu();
+            send_zone_event(boot_cpu, zone_id, SGI_IPI_ID as _, IPI_EVENT_WATCHDOG_IRQ);
+        }
+    }
+
+    // the zone on this cpu last, its action may not return
+    bitten.sort_by_key(|&(zone_id, _)| Some(zone_id) == running);
+    for (zone_id, action) in bitten {
+        warn!("zone {} watchdog expired, action {:?}", zone_id, action);
+        notify_root(zone_id, ZONE_EVENT_WATCHDOG, action as _);
+        let zone = match find_zone(zone_id) {
+            Some(zone) => zone,
+            None => continue,
+        };
+        match action {
+            WatchdogAction::Reset => {
+                if let Err(e) = zone_reboot(zone, false) {
+                    error!("zone {} watchdog reset failed: {:?}", zone_id, e);
+                    if let Some(zone) = find_zone(zone_id) {
+                        zone_crash(zone, ZONE_CRASH_REBOOT_FAILED);
+                    }
+                }
+            }
+            WatchdogAction::Shutdown => zone_crash(zone, ZONE_CRASH_WATCHDOG),
+            WatchdogAction::Notify => {}
+        }
+    }
+}
+
+/// Raise the first-timeout irq of the watchdog of the zone on this cpu,
+/// on `IPI_EVENT_WATCHDOG_IRQ`.
+#[cfg(target_arch = "aarch64")]
+pub fn watchdog_inject_irq() {
+    let zone_id = this_zone_id();
+    let irq = match WATCHDOGS.lock().get(&zone_id) {
+        Some(wdt) => wdt.irq,
+        None => return,
+    };
+    if irq != 0 {
+        crate::device::irqchip::inject_irq(irq as _, false);
+    }
+}
@@ -0,0 +1,69 @@
+use super::*;
+
+fn write(wdt: &mut Watchdog, address: usize, value: usize, now: u64) -> Option<u64> {
+    let mut mmio = MMIOAccess {
+        address,
+        size: 4,
+        is_write: true,
+        value,
+    };
+    wdt.access(&mut mmio, now)
+}
+
+#[cfg(target_arch = "aarch64")]
+#[test_case]
+fn test_sp805_two_stage_expiry() {
+    let mut wdt = Watchdog::new(0x1000, 0, WatchdogAction::Reset);
+    let ticks_per_ms = SP805_CLOCK_HZ as usize / 1000;
+    assert_eq!(write(&mut wdt, SP805_LOAD, 100 * ticks_per_ms - 1, 0), None);
+    assert_eq!(
+        write(
+            &mut wdt,
+            SP805_CTRL,
+            (SP805_CTRL_INTEN | SP805_CTRL_RESEN) as _,
+            0
+        ),
+        Some(100)
+    );
+    assert_eq!(wdt.poll(50), Poll::Pending(50));
+    // the first timeout only interrupts
+    assert_eq!(wdt.poll(100), Poll::Interrupt(100));
+    assert_eq!(wdt.poll(200), Poll::Bite);
+    assert_eq!(wdt.poll(300), Poll::Idle);
+
+    // clearing the interrupt is a ping
+    wdt.reset();
+    write(&mut wdt, SP805_LOAD, 100 * ticks_per_ms - 1, 0);
+    write(&mut wdt, SP805_CTRL, SP805_CTRL_INTEN as _, 0);
+    assert_eq!(wdt.poll(100), Poll::Interrupt(100));
+    assert_eq!(write(&mut wdt, SP805_INTCLR, 1, 150), Some(100));
+    assert_eq!(wdt.poll(250), Poll::Interrupt(100));
+    // without RESEN the counter just keeps going
+    assert_eq!(wdt.poll(350), Poll::Pending(100));
+}
+
+#[cfg(target_arch = "aarch64")]
+#[test_case]
+fn test_sp805_lock() {
+    let mut wdt = Watchdog::new(0x1000, 0, WatchdogAction::Reset);
+    write(&mut wdt, SP805_LOCK, 0, 0);
+    assert_eq!(write(&mut wdt, SP805_CTRL, SP805_CTRL_INTEN as _, 0), None);
+    assert_eq!(wdt.ctrl, 0);
+    write(&mut wdt, SP805_LOCK, SP805_UNLOCK_KEY, 0);
+    assert!(write(&mut wdt, SP805_CTRL, SP805_CTRL_INTEN as _, 0).is_some());
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+#[test_case]
+fn test_generic_watchdog_expiry() {
+    let mut wdt = Watchdog::new(0x1000, 0, WatchdogAction::Notify);
+    assert_eq!(write(&mut wdt, WDT_TIMEOUT, 100, 0), None);
+    assert_eq!(write(&mut wdt, WDT_CTRL, WDT_CTRL_EN as _, 0), Some(100));
+    assert_eq!(write(&mut wdt, WDT_KICK, 1, 50), Some(100));
+    assert_eq!(wdt.poll(100), Poll::Pending(50));
+    assert_eq!(wdt.poll(150), Poll::Bite);
+    assert_eq!(wdt.poll(200), Poll::Idle);
+    // stopped watchdogs aren't kicked
+    write(&mut wdt, WDT_CTRL, 0, 200);
+    assert_eq!(write(&mut wdt, WDT_KICK, 1, 200), None);
+}
@@ -4,9 +4,12 @@ use crate::{
         irqchip::{self, inject_irq},
         virtio_trampoline::{handle_virtio_irq, IRQ_WAKEUP_VIRTIO_DEVICE},
     },
+    notify::IRQ_ZONE_EVENT,
     percpu::this_cpu_data,
 };
 use alloc::{collections::VecDeque, vec::Vec};
+#[cfg(feature = "vcpu_sched")]
+use core::sync::atomic::Ordering;
 use spin::{Mutex, Once};
 
 #[cfg(test)]
@@ -17,6 +20,9 @@ pub const IPI_EVENT_SHUTDOWN: usize = 1;
 pub const IPI_EVENT_VIRTIO_INJECT_IRQ: usize = 2;
 pub const IPI_EVENT_WAKEUP_VIRTIO_DEVICE: usize = 3;
 pub const IPI_EVENT_CLEAR_INJECT_IRQ: usize = 4;
+pub const IPI_EVENT_PAUSE: usize = 5;
+pub const IPI_EVENT_ZONE_NOTIFY: usize = 6;
+pub const IPI_EVENT_WATCHDOG_IRQ: usize = 7;
 
 static EVENT_MANAGER: Once<EventManager> = Once::new();
 
@@ -72,11 +78,11 @@ impl EventManager {
     }
 }
 
-fn add_event(cpu: usize, event_id: usize) -> Option<()> {
+pub fn add_event(cpu: usize, event_id: usize) -> Option<()> {
     EVENT_MANAGER.get().unwrap().add_event(cpu, event_id)
 }
 
-fn fetch_event(cpu: usize) -> Option<usize> {
+pub fn fetch_event(cpu: usize) -> Option<usize> {
     EVENT_MANAGER.get().unwrap().fetch_event(cpu)
 }
 
@@ -103,6 +109,21 @@ pub fn check_events() -> bool {
         Some(IPI_EVENT_SHUTDOWN) => {
             cpu_data.arch_cpu.idle();
         }
+        #[cfg(not(feature = "vcpu_sched"))]
+        Some(IPI_EVENT_PAUSE) => {
+            cpu_data.wait_for_resume();
+            // handle what was queued while parked, e.g. a shutdown
+            check_events();
+            true
+        }
+        #[cfg(feature = "vcpu_sched")]
+        Some(IPI_EVENT_PAUSE) => {
+            // don't spin here, the scheduler runs the other vcpus of this
+            // cpu until the zone is resumed
+            cpu_data.paused.store(true, Ordering::Release);
+            crate::scheduler::resched();
+            true
+        }
         Some(IPI_EVENT_VIRTIO_INJECT_IRQ) => {
             handle_virtio_irq();
             true
@@ -111,6 +132,15 @@ pub fn check_events() -> bool {
             inject_irq(IRQ_WAKEUP_VIRTIO_DEVICE, false);
             true
         }
+        Some(IPI_EVENT_ZONE_NOTIFY) => {
+            inject_irq(IRQ_ZONE_EVENT, false);
+            true
+        }
+        #[cfg(target_arch = "aarch64")]
+        Some(IPI_EVENT_WATCHDOG_IRQ) => {
+            crate::device::watchdog::watchdog_inject_irq();
+            true
+        }
         #[cfg(target_arch = "loongarch64")]
         Some(IPI_EVENT_CLEAR_INJECT_IRQ) => {
             irqchip::ls7a2000::clear_hwi_injected_irq();
@@ -134,3 +164,18 @@ pub fn send_event(cpu_id: usize, ipi_int_id: usize, event_id: usize) {
     add_event(cpu_id, event_id);
     arch_send_event(cpu_id as _, ipi_int_id as _);
 }
+
+/// Send `event_id` to the vcpu of `zone_id` on `cpu_id`. With the vcpu
+/// scheduler the event waits with the vcpu if another one is running on
+/// the cpu.
+pub fn send_zone_event(cpu_id: usize, zone_id: usize, ipi_int_id: usize, event_id: usize) {
+    #[cfg(feature = "vcpu_sched")]
+    if crate::scheduler::queue_event(cpu_id, zone_id, event_id) {
+        arch_send_event(cpu_id as _, ipi_int_id as _);
+    }
+    #[cfg(not(feature = "vcpu_sched"))]
+    {
+        let _ = zone_id;
+        send_event(cpu_id, ipi_int_id, event_id);
+    }
+}
@@ -1,16 +1,23 @@
 #![allow(dead_code)]
 use crate::arch::cpu::this_cpu_id;
-use crate::config::HvZoneConfig;
-use crate::consts::{INVALID_ADDRESS, MAX_CPU_NUM, PAGE_SIZE};
+use crate::balloon::{balloon_deflate, balloon_inflate};
+use crate::config::{ZoneConfig, ZONE_CONFIG_MAX_SIZE};
+use crate::consts::{MAX_CPU_NUM, PAGE_SIZE};
 use crate::device::irqchip::inject_irq;
 use crate::device::virtio_trampoline::{MAX_DEVS, MAX_REQ, VIRTIO_BRIDGE, VIRTIO_IRQS};
+use crate::device::watchdog::HvWatchdogConfig;
 use crate::error::HvResult;
-use crate::percpu::{get_cpu_data, this_zone, PerCpu};
+use crate::notify::{self, notify_root, ZONE_EVENT_EXITED, ZONE_EVENT_STARTED};
+use crate::percpu::{get_cpu_data, this_zone, zone_cpu_data, PerCpu};
 use crate::zone::{
-    all_zones_info, find_zone, is_this_root_zone, remove_zone, this_zone_id, zone_create, ZoneInfo,
+    all_zones_info, find_zone, is_this_root_zone, this_zone_id, zone_create, zone_mem_map,
+    zone_move_cpu, zone_pause, zone_reboot, zone_resume, zone_shutdown, HvZoneMemOp, HvZoneStatus,
+    ZoneInfo, ZoneRollback, ZoneState,
 };
 
-use crate::event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
+use crate::event::{send_event, send_zone_event, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
+#[cfg(feature = "vcpu_sched")]
+use crate::scheduler::{SchedPolicy, MAX_TIME_SLICE_MS};
 use core::convert::TryFrom;
 use core::sync::atomic::{fence, Ordering};
 
@@ -30,10 +37,27 @@ numeric_enum! {
         HvZoneList = 4,
         HvClearInjectIrq = 20,
         HvIvcInfo = 5,
+        HvZonePause = 6,
+        HvZoneResume = 7,
+        HvZoneReboot = 8,
+        HvSchedConfig = 9,
+        HvZoneSetPriority = 10,
+        HvZoneMoveCpu = 11,
+        HvZoneStatus = 12,
+        HvZoneEventInit = 13,
+        HvZoneWatchdogInit = 14,
+        HvZoneMemMap = 15,
+        HvZoneMemUnmap = 16,
+        HvZoneMemProtect = 17,
+        HvZoneBalloonInflate = 18,
+        HvZoneBalloonDeflate = 19,
     }
 }
 pub const SGI_IPI_ID: u64 = 7;
 
+/// `HvZoneReboot` flag: zero the zone's RAM (except the kernel and dtb) before restarting it.
+pub const ZONE_REBOOT_ZERO_RAM: u64 = 1 << 0;
+
 pub type HyperCallResult = HvResult<usize>;
 
 pub struct HyperCall<'a> {
@@ -61,11 +85,29 @@ impl<'a> HyperCall<'a> {
             match code {
                 HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
                 HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
-                HyperCallCode::HvZoneStart => {
-                    self.hv_zone_start(&*(arg0 as *const HvZoneConfig), arg1)
-                }
+                HyperCallCode::HvZoneStart => self.hv_zone_start(arg0, arg1),
                 HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
                 HyperCallCode::HvZoneList => self.hv_zone_list(&mut *(arg0 as *mut ZoneInfo), arg1),
+                HyperCallCode::HvZonePause => self.hv_zone_pause(arg0),
+                HyperCallCode::HvZoneResume => self.hv_zone_resume(arg0),
+                HyperCallCode::HvZoneReboot => self.hv_zone_reboot(arg0, arg1),
+                HyperCallCode::HvZoneMoveCpu => self.hv_zone_move_cpu(arg0, arg1),
+                HyperCallCode::HvZoneStatus => self.hv_zone_status(arg0, arg1 as *mut HvZoneStatus),
+                HyperCallCode::HvZoneEventInit => self.hv_zone_event_init(arg0),
+                HyperCallCode::HvZoneWatchdogInit => {
+                    self.hv_zone_watchdog_init(arg0, arg1 as *const HvWatchdogConfig)
+                }
+                HyperCallCode::HvZoneMemMap
+                | HyperCallCode::HvZoneMemUnmap
+                | HyperCallCode::HvZoneMemProtect
+                | HyperCallCode::HvZoneBalloonInflate
+                | HyperCallCode::HvZoneBalloonDeflate => {
+                    self.hv_zone_mem(code, arg0, arg1 as *const HvZoneMemOp)
+                }
+                #[cfg(feature = "vcpu_sched")]
+                HyperCallCode::HvSchedConfig => self.hv_sched_config(arg0, arg1),
+                #[cfg(feature = "vcpu_sched")]
+                HyperCallCode::HvZoneSetPriority => self.hv_zone_set_priority(arg0, arg1),
                 HyperCallCode::HvClearInjectIrq => {
                     use crate::event::IPI_EVENT_CLEAR_INJECT_IRQ;
                     for i in 1..MAX_CPU_NUM {
@@ -160,9 +202,14 @@ impl<'a> HyperCall<'a> {
             let irq_id = region.res_list[res_front].irq_id as u64;
             let target_zone = region.res_list[res_front].target_zone;
             let target_cpu = match find_zone(target_zone as _) {
-                Some(zone) => zone.read().cpu_set.first_cpu().unwrap(),
+                Some(zone) => zone.read().boot_cpu(),
                 _ => continue,
             };
+            // the target vcpu waits for its turn, it gets the irq when it runs
+            #[cfg(feature = "vcpu_sched")]
+            let waiting = crate::scheduler::queue_irq(target_cpu, target_zone as _, irq_id as _);
+            #[cfg(not(feature = "vcpu_sched"))]
+            let waiting = false;
 
             let irq_list = map_irq.entry(target_cpu).or_insert([0; MAX_DEVS + 1]);
             #[cfg(target_arch = "loongarch64")]
@@ -176,13 +223,14 @@ impl<'a> HyperCall<'a> {
                 drop(status);
                 irq_list[0] = 0; // CAUTION: this is a workaround for loongarch64
             }
-            if !irq_list[1..=irq_list[0] as usize].contains(&irq_id) {
+            if !waiting && !irq_list[1..=irq_list[0] as usize].contains(&irq_id) {
                 let len = irq_list[0] as usize;
                 assert!(len + 1 < MAX_DEVS);
                 irq_list[len + 1] = irq_id;
                 irq_list[0] += 1;
-                send_event(
+                send_zone_event(
                     target_cpu as _,
+                    target_zone as _,
                     SGI_IPI_ID as _,
                     IPI_EVENT_VIRTIO_INJECT_IRQ,
                 );
@@ -196,32 +244,135 @@ impl<'a> HyperCall<'a> {
         HyperCallResult::Ok(0)
     }
 
-    pub fn hv_zone_start(&mut self, config: &HvZoneConfig, config_size: u64) -> HyperCallResult {
+    /// Set up the ring zone lifecycle events are reported in, the root zone
+    /// passes a page it keeps for the hypervisor.
+    fn hv_zone_event_init(&mut self, ring_addr: u64) -> HyperCallResult {
+        info!("handle hvc zone event init, ring_addr = {:#x?}", ring_addr);
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Init zone events over non-root zones: unsupported!");
+        }
+        let ring_addr = ring_addr as usize;
+        if ring_addr == 0 || ring_addr % PAGE_SIZE != 0 {
+            return hv_result_err!(EINVAL, "zone event ring must be page aligned");
+        }
+        // the hypervisor writes the ring, it must not land outside the root zone
+        let in_root_ram = this_zone()
+            .read()
+            .boot
+            .ram
+            .iter()
+            .any(|&(start, size)| start <= ring_addr && ring_addr + PAGE_SIZE <= start + size);
+        if !in_root_ram {
+            return hv_result_err!(EINVAL, "zone event ring must lie in the root zone's RAM");
+        }
         #[cfg(target_arch = "loongarch64")]
-        let config = unsafe {
-            &*((config as *const HvZoneConfig as u64
-                | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX)
-                as *const HvZoneConfig)
+        let ring_addr = ring_addr | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize;
+        notify::init(ring_addr);
+        HyperCallResult::Ok(0)
+    }
+
+    /// Give zone `zone_id` a virtual watchdog, the root zone describes the
+    /// device in the zone's device tree.
+    fn hv_zone_watchdog_init(
+        &mut self,
+        zone_id: u64,
+        config: *const HvWatchdogConfig,
+    ) -> HyperCallResult {
+        info!("handle hvc zone watchdog init, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Watchdog init operation over non-root zones: unsupported!"
+            );
+        }
+        if config.is_null() {
+            return hv_result_err!(EINVAL, "hv_zone_watchdog_init: config is null");
+        }
+        #[cfg(target_arch = "loongarch64")]
+        let config = (config as u64 | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX)
+            as *const HvWatchdogConfig;
+        let config = unsafe { *config };
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL, "the root zone can't have a watchdog");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
         };
+        zone.write().watchdog_init(&config)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Map, unmap or reprotect part of running zone `zone_id`'s guest
+    /// physical memory as described by `op`, or move guest RAM between the
+    /// zone and the root zone for its balloon.
+    fn hv_zone_mem(
+        &mut self,
+        code: HyperCallCode,
+        zone_id: u64,
+        op: *const HvZoneMemOp,
+    ) -> HyperCallResult {
+        info!("handle hvc {:?}, id={}", code, zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Zone memory operation over non-root zones: unsupported!"
+            );
+        }
+        if op.is_null() {
+            return hv_result_err!(EINVAL, "hv_zone_mem: op is null");
+        }
+        #[cfg(target_arch = "loongarch64")]
+        let op = (op as u64 | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX) as *const HvZoneMemOp;
+        let op = unsafe { *op };
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL, "the root zone's memory can't be changed");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        match code {
+            HyperCallCode::HvZoneMemMap => zone_mem_map(&zone, &op)?,
+            HyperCallCode::HvZoneMemUnmap => zone.write().mem_unmap(&op)?,
+            HyperCallCode::HvZoneBalloonInflate => balloon_inflate(&zone, &op)?,
+            HyperCallCode::HvZoneBalloonDeflate => balloon_deflate(&zone, &op)?,
+            _ => zone.write().mem_protect(&op)?,
+        }
+        HyperCallResult::Ok(0)
+    }
+
+    /// `config_addr` holds either a `HvZoneConfig` or a tagged config, told
+    /// apart by its first word. Returns the id of the started zone, which
+    /// hvisor picks when the config asks for `ZONE_ID_AUTO`.
+    pub fn hv_zone_start(&mut self, config_addr: u64, config_size: u64) -> HyperCallResult {
+        #[cfg(target_arch = "loongarch64")]
+        let config_addr = config_addr | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX;
 
-        info!("hv_zone_start: config: {:#x?}", config);
         if !is_this_root_zone() {
             return hv_result_err!(
                 EPERM,
                 "Start zone operation over non-root zones: unsupported!"
             );
         }
-        if config_size != core::mem::size_of::<HvZoneConfig>() as _ {
-            return hv_result_err!(EINVAL, "Invalid config!");
+        if config_size as usize > ZONE_CONFIG_MAX_SIZE {
+            return hv_result_err!(E2BIG, "zone config too large");
         }
-        let zone = zone_create(config)?;
-        let boot_cpu = zone.read().cpu_set.first_cpu().unwrap();
+        let buf =
+            unsafe { core::slice::from_raw_parts(config_addr as *const u8, config_size as usize) };
+        let config = ZoneConfig::from_bytes(buf)?;
+        info!("hv_zone_start: config: {:#x?}", config);
+        // undone on any error below, so a failed start can simply be retried
+        let mut rollback = ZoneRollback::new();
+        let zone = zone_create(&config, &mut rollback)?;
+        let zone_id = zone.read().id;
+        let boot_cpu = zone.read().boot_cpu();
 
-        let target_data = get_cpu_data(boot_cpu as _);
-        let _lock = target_data.ctrl_lock.lock();
+        let _lock = get_cpu_data(boot_cpu).ctrl_lock.lock();
+        let power_on = zone_cpu_data(boot_cpu, zone_id).arch_cpu.power_on;
 
-        if !target_data.arch_cpu.power_on {
-            send_event(boot_cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+        if !power_on {
+            send_zone_event(boot_cpu, zone_id, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
         } else {
             error!("hv_zone_start: cpu {} already on", boot_cpu);
             return hv_result_err!(EBUSY);
@@ -233,7 +384,9 @@ impl<'a> HyperCall<'a> {
             assert_eq!(cpuid, 0);
         }
         drop(_lock);
-        HyperCallResult::Ok(0)
+        rollback.commit();
+        notify_root(zone_id, ZONE_EVENT_STARTED, 0);
+        HyperCallResult::Ok(zone_id)
     }
 
     fn hv_zone_shutdown(&mut self, zone_id: u64) -> HyperCallResult {
@@ -251,32 +404,196 @@ impl<'a> HyperCall<'a> {
             Some(zone) => zone,
             _ => return hv_result_err!(EEXIST),
         };
-        let zone_r = zone.read();
+        zone_shutdown(zone);
+        let zone_id = zone_id as usize;
+        notify_root(zone_id, ZONE_EVENT_EXITED, 0);
 
-        // // return zone's cpus to root_zone
-        zone_r.cpu_set.iter().for_each(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
-            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
-        });
-        // wait all zone's cpus shutdown
-        while zone_r.cpu_set.iter().any(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            get_cpu_data(cpu_id).arch_cpu.power_on
-        }) {}
-        zone_r.cpu_set.iter().for_each(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            get_cpu_data(cpu_id).zone = None;
-        });
-        zone_r.arch_irqchip_reset();
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_pause(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone pause, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Pause zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        let cpu_set = {
+            let mut zone_w = zone.write();
+            if zone_w.state != ZoneState::Running {
+                return hv_result_err!(EBUSY, format!("zone {} is not running", zone_id));
+            }
+            zone_w.state = ZoneState::Paused;
+            zone_w.cpu_set
+        };
+        // wait without the zone lock, the zone's cpus may still need it to
+        // finish the trap they are handling, a guest reset takes it for writing
+        zone_pause(&zone, cpu_set)?;
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_resume(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone resume, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Resume zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        let cpu_set = {
+            let mut zone_w = zone.write();
+            if zone_w.state != ZoneState::Paused {
+                return hv_result_err!(EINVAL, format!("zone {} is not paused", zone_id));
+            }
+            zone_w.state = ZoneState::Running;
+            zone_w.cpu_set
+        };
+        // as for pausing, the zone's cpus may need the lock to get going again
+        zone_resume(zone_id as _, cpu_set);
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_reboot(&mut self, zone_id: u64, flags: u64) -> HyperCallResult {
+        info!("handle hvc zone reboot, id={}, flags={:#x}", zone_id, flags);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Reboot zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone_reboot(zone, flags & ZONE_REBOOT_ZERO_RAM != 0)?;
+        HyperCallResult::Ok(0)
+    }
 
-        drop(zone_r);
-        drop(zone);
-        remove_zone(zone_id as _);
+    /// Give the powered-off physical cpu `cpu_id` to zone `zone_id`, returns
+    /// the vcpu id the cpu gets in the zone. The zone's device tree needs a
+    /// cpu node for that vcpu id, so the guest can bring it online.
+    fn hv_zone_move_cpu(&mut self, cpu_id: u64, zone_id: u64) -> HyperCallResult {
+        info!(
+            "handle hvc zone move cpu, cpu={}, target id={}",
+            cpu_id, zone_id
+        );
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Move cpu operation over non-root zones: unsupported!"
+            );
+        }
+        if cpu_id >= MAX_CPU_NUM as _ {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone_move_cpu(cpu_id as _, zone)
+    }
+
+    #[cfg(feature = "vcpu_sched")]
+    fn hv_sched_config(&mut self, policy: u64, time_slice_ms: u64) -> HyperCallResult {
+        info!(
+            "handle hvc sched config, policy={}, time_slice_ms={}",
+            policy, time_slice_ms
+        );
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Scheduler config operation over non-root zones: unsupported!"
+            );
+        }
+        let policy = match SchedPolicy::try_from(policy) {
+            Ok(policy) => policy,
+            Err(_) => return hv_result_err!(EINVAL, format!("unknown policy {}", policy)),
+        };
+        if time_slice_ms == 0 || time_slice_ms > MAX_TIME_SLICE_MS as _ {
+            return hv_result_err!(EINVAL, "time slice out of range");
+        }
+        crate::scheduler::set_config(policy, time_slice_ms as _);
+        HyperCallResult::Ok(0)
+    }
 
+    #[cfg(feature = "vcpu_sched")]
+    fn hv_zone_set_priority(&mut self, zone_id: u64, priority: u64) -> HyperCallResult {
+        info!(
+            "handle hvc zone set priority, id={}, priority={}",
+            zone_id, priority
+        );
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Set zone priority operation over non-root zones: unsupported!"
+            );
+        }
+        if priority > u8::MAX as _ {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone.read().cpu_set.iter().for_each(|cpu_id| {
+            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+            zone_cpu_data(cpu_id, zone_id as _).sched_priority = priority as _;
+        });
         HyperCallResult::Ok(0)
     }
 
+    /// Fill the caller's `HvZoneStatus` of zone `zone_id`, returns the bytes
+    /// written. A caller built against an older layout passes a smaller
+    /// `size` and gets the fields it knows about.
+    fn hv_zone_status(&self, zone_id: u64, status: *mut HvZoneStatus) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Zone status operation over non-root zones: unsupported!"
+            );
+        }
+        if status.is_null() {
+            return hv_result_err!(EINVAL, "hv_zone_status: status is null");
+        }
+        #[cfg(target_arch = "loongarch64")]
+        let status =
+            (status as u64 | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX) as *mut HvZoneStatus;
+        // only the version and size header is known to fit
+        let size = unsafe { (*status).size } as usize;
+        if size < 2 * core::mem::size_of::<u32>() {
+            return hv_result_err!(EINVAL, "hv_zone_status: buffer too small");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        let mut info = zone.read().status();
+        let size = core::cmp::min(size, core::mem::size_of::<HvZoneStatus>());
+        info.size = size as _;
+        unsafe {
+            core::ptr::copy_nonoverlapping(&info as *const _ as *const u8, status as *mut u8, size)
+        };
+        HyperCallResult::Ok(size)
+    }
+
     fn hv_zone_list(&self, zones: *mut ZoneInfo, cnt: u64) -> HyperCallResult {
         if zones.is_null() {
             return hv_result_err!(EINVAL, "hv_zone_list: zones is null");
@@ -10,10 +10,10 @@ use crate::device::irqchip::set_ispender;
 use crate::{
     config::{HvIvcConfig, CONFIG_MAX_IVC_CONGIGS},
     consts::PAGE_SIZE,
-    error::HvResult,
+    error::{HvError, HvResult},
     hypercall::SGI_IPI_ID,
-    memory::{Frame, GuestPhysAddr, MMIOAccess, MemFlags, MemoryRegion},
-    zone::{find_zone, this_zone_id, Zone},
+    memory::{addr::is_aligned, Frame, GuestPhysAddr, MMIOAccess, MemFlags, MemoryRegion},
+    zone::{find_zone, this_zone_id, Zone, ZoneRollback},
 };
 
 // ivc_id -> ivc_record
@@ -59,7 +59,84 @@ impl From<&[HvIvcConfig]> for IvcInfo {
         }
     }
 }
-fn insert_ivc_record(ivc_config: &HvIvcConfig, zone_id: u32) -> Result<(bool, usize), ()> {
+/// The ivc regions `zone_id` participates in.
+pub fn zone_ivc_ids(zone_id: usize) -> Vec<u32> {
+    match IVC_INFOS.lock().get(&zone_id) {
+        Some(info) => {
+            let ivc_ids = info.ivc_ids;
+            ivc_ids[..info.len as usize].to_vec()
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Release hook of `remove_zone`: leave every ivc region `zone_id` joined,
+/// the shared memory of a region goes with its last peer.
+pub fn ivc_zone_remove(zone_id: usize) {
+    IVC_INFOS.lock().remove(&zone_id);
+    let mut recs = IVC_RECORDS.lock();
+    recs.values_mut().for_each(|rec| {
+        rec.peer_infos
+            .retain(|_, peer| peer.zone_id as usize != zone_id)
+    });
+    recs.retain(|_, rec| !rec.peer_infos.is_empty());
+}
+
+/// Check the ivc regions a new zone wants to join against each other and
+/// against the peers that already joined them.
+pub fn ivc_config_check(ivc_configs: &[HvIvcConfig]) -> HvResult {
+    if ivc_configs.len() > CONFIG_MAX_IVC_CONGIGS {
+        return hv_result_err!(E2BIG, "too many ivc configs");
+    }
+    let recs = IVC_RECORDS.lock();
+    for (i, config) in ivc_configs.iter().enumerate() {
+        let ivc_id = config.ivc_id;
+        if ivc_configs[..i].iter().any(|other| other.ivc_id == ivc_id) {
+            return hv_result_err!(EINVAL, format!("ivc {} configured twice", ivc_id));
+        }
+        if config.peer_id >= config.max_peers {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "ivc {}: peer {} out of {} peers",
+                    ivc_id, config.peer_id, config.max_peers
+                )
+            );
+        }
+        if !is_aligned(config.rw_sec_size as _)
+            || !is_aligned(config.out_sec_size as _)
+            || !is_aligned(config.control_table_ipa as _)
+            || !is_aligned(config.shared_mem_ipa as _)
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("ivc {}: sections must be page aligned", ivc_id)
+            );
+        }
+        if let Some(rec) = recs.get(&ivc_id) {
+            if rec.max_peers != config.max_peers
+                || rec.rw_sec_size != config.rw_sec_size
+                || rec.out_sec_size != config.out_sec_size
+            {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("ivc {}: config conflicts with the other peers", ivc_id)
+                );
+            }
+            if rec.peer_infos.contains_key(&config.peer_id) {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("ivc {}: peer {} is taken", ivc_id, config.peer_id)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add `zone_id` as a peer of the ivc region, returns where the region's
+/// shared memory begins.
+fn insert_ivc_record(ivc_config: &HvIvcConfig, zone_id: u32) -> HvResult<usize> {
     let mut recs = IVC_RECORDS.lock();
     let ivc_id = ivc_config.ivc_id;
     if let Some(rec) = recs.get_mut(&ivc_id) {
@@ -67,12 +144,10 @@ fn insert_ivc_record(ivc_config: &HvIvcConfig, zone_id: u32) -> Result<(bool, us
             || rec.rw_sec_size != ivc_config.rw_sec_size
             || rec.out_sec_size != ivc_config.out_sec_size
         {
-            error!("ivc config conflicts!!!");
-            return Err(());
+            return hv_result_err!(EINVAL, format!("ivc {}: config conflicts", ivc_id));
         }
         if rec.peer_infos.len() == rec.max_peers as _ {
-            error!("can't add more peers to ivc_id {}", ivc_id);
-            return Err(());
+            return hv_result_err!(EBUSY, format!("can't add more peers to ivc_id {}", ivc_id));
         }
         rec.peer_infos.insert(
             ivc_config.peer_id,
@@ -82,15 +157,14 @@ fn insert_ivc_record(ivc_config: &HvIvcConfig, zone_id: u32) -> Result<(bool, us
                 shared_mem_ipa: ivc_config.shared_mem_ipa,
             },
         );
-        Ok((false, rec.shared_mem.start_paddr()))
+        Ok(rec.shared_mem.start_paddr())
     } else {
         if ivc_config.rw_sec_size as usize % PAGE_SIZE != 0
             || ivc_config.out_sec_size as usize % PAGE_SIZE != 0
         {
-            error!("section size must be page aligned!!!");
-            return Err(());
+            return hv_result_err!(EINVAL, "section size must be page aligned");
         }
-        let mut rec = IvcRecord::from(ivc_config);
+        let mut rec = IvcRecord::try_from(ivc_config)?;
         let start_paddr = rec.shared_mem.start_paddr();
         rec.peer_infos.insert(
             ivc_config.peer_id,
@@ -101,7 +175,19 @@ fn insert_ivc_record(ivc_config: &HvIvcConfig, zone_id: u32) -> Result<(bool, us
             },
         );
         recs.insert(ivc_id, rec);
-        Ok((true, start_paddr))
+        Ok(start_paddr)
+    }
+}
+
+/// Take `peer_id` out of ivc region `ivc_id`, the shared memory is freed
+/// with the last peer.
+fn remove_ivc_peer(ivc_id: u32, peer_id: u32) {
+    let mut recs = IVC_RECORDS.lock();
+    if let Some(rec) = recs.get_mut(&ivc_id) {
+        rec.peer_infos.remove(&peer_id);
+        if rec.peer_infos.is_empty() {
+            recs.remove(&ivc_id);
+        }
     }
 }
 
@@ -120,70 +206,73 @@ struct PeerInfo {
     shared_mem_ipa: u64,
 }
 
-impl From<&HvIvcConfig> for IvcRecord {
-    fn from(config: &HvIvcConfig) -> Self {
+impl TryFrom<&HvIvcConfig> for IvcRecord {
+    type Error = HvError;
+
+    fn try_from(config: &HvIvcConfig) -> HvResult<Self> {
         let frames = Frame::new_contiguous(
             ((config.rw_sec_size + config.out_sec_size * config.max_peers) / PAGE_SIZE as u32)
                 as usize,
             0,
-        )
-        .unwrap();
-        Self {
+        )?;
+        Ok(Self {
             max_peers: config.max_peers,
             rw_sec_size: config.rw_sec_size,
             out_sec_size: config.out_sec_size,
             peer_infos: BTreeMap::new(),
             shared_mem: frames,
-        }
+        })
     }
 }
 
 impl Zone {
-    pub fn ivc_init(&mut self, ivc_configs: &[HvIvcConfig]) {
+    pub fn ivc_init(
+        &mut self,
+        ivc_configs: &[HvIvcConfig],
+        rollback: &mut ZoneRollback,
+    ) -> HvResult {
         for ivc_config in ivc_configs {
-            // is_new is ok to remove
-            if let Ok((is_new, start_paddr)) = insert_ivc_record(ivc_config, self.id as _) {
-                info!(
-                    "ivc init: zone {}'s shared mem begins at {:x}, ipa is {:x}",
-                    self.id, start_paddr, ivc_config.shared_mem_ipa
-                );
-                let max_peers = ivc_config.max_peers;
-                let rw_sec_size: usize = ivc_config.rw_sec_size as usize;
-                let out_sec_size: usize = ivc_config.out_sec_size as usize;
-                self.gpm
-                    .insert(MemoryRegion::new_with_offset_mapper(
-                        ivc_config.shared_mem_ipa as _,
-                        start_paddr,
-                        rw_sec_size as _,
-                        MemFlags::READ | MemFlags::WRITE,
-                    ))
-                    .unwrap();
-                for i in 0..ivc_config.max_peers as usize {
-                    let flags = if i == ivc_config.peer_id as _ {
-                        MemFlags::READ | MemFlags::WRITE
-                    } else {
-                        MemFlags::READ
-                    };
-                    self.gpm
-                        .insert(MemoryRegion::new_with_offset_mapper(
-                            ivc_config.shared_mem_ipa as usize + rw_sec_size + i * out_sec_size,
-                            start_paddr + rw_sec_size + i * out_sec_size,
-                            out_sec_size as _,
-                            flags,
-                        ))
-                        .unwrap();
-                }
-                self.mmio_region_register(
-                    ivc_config.control_table_ipa as _,
-                    PAGE_SIZE,
-                    mmio_ivc_handler,
-                    ivc_config.control_table_ipa as _,
-                );
-            } else {
-                return;
+            let (ivc_id, peer_id) = (ivc_config.ivc_id, ivc_config.peer_id);
+            let start_paddr = insert_ivc_record(ivc_config, self.id as _)?;
+            rollback.push(move || remove_ivc_peer(ivc_id, peer_id));
+            info!(
+                "ivc init: zone {}'s shared mem begins at {:x}, ipa is {:x}",
+                self.id, start_paddr, ivc_config.shared_mem_ipa
+            );
+            let rw_sec_size: usize = ivc_config.rw_sec_size as usize;
+            let out_sec_size: usize = ivc_config.out_sec_size as usize;
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                ivc_config.shared_mem_ipa as _,
+                start_paddr,
+                rw_sec_size as _,
+                MemFlags::READ | MemFlags::WRITE,
+            ))?;
+            for i in 0..ivc_config.max_peers as usize {
+                let flags = if i == ivc_config.peer_id as _ {
+                    MemFlags::READ | MemFlags::WRITE
+                } else {
+                    MemFlags::READ
+                };
+                self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                    ivc_config.shared_mem_ipa as usize + rw_sec_size + i * out_sec_size,
+                    start_paddr + rw_sec_size + i * out_sec_size,
+                    out_sec_size as _,
+                    flags,
+                ))?;
             }
+            self.mmio_region_register(
+                ivc_config.control_table_ipa as _,
+                PAGE_SIZE,
+                mmio_ivc_handler,
+                ivc_config.control_table_ipa as _,
+            );
         }
         IVC_INFOS.lock().insert(self.id, IvcInfo::from(ivc_configs));
+        let zone_id = self.id;
+        rollback.push(move || {
+            IVC_INFOS.lock().remove(&zone_id);
+        });
+        Ok(())
     }
 }
 
@@ -35,17 +35,27 @@ extern crate lazy_static;
 #[macro_use]
 mod logging;
 mod arch;
+mod balloon;
 mod config;
 mod consts;
 mod device;
 mod event;
 mod hypercall;
 mod memory;
+mod notify;
 mod panic;
 mod percpu;
 mod platform;
+#[cfg(feature = "vcpu_sched")]
+mod scheduler;
 mod zone;
 
+#[cfg(all(
+    feature = "vcpu_sched",
+    not(all(target_arch = "aarch64", feature = "gicv3"))
+))]
+compile_error!("the vcpu scheduler is only supported on aarch64 with gicv3");
+
 #[cfg(target_arch = "aarch64")]
 mod ivc;
 
@@ -59,7 +69,7 @@ use arch::{cpu::cpu_start, entry::arch_entry};
 use config::root_zone_config;
 use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
 use percpu::PerCpu;
-use zone::zone_create;
+use zone::{vmid_init, zone_create, ZoneRollback};
 
 #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
 use crate::arch::iommu::iommu_init;
@@ -94,7 +104,7 @@ fn wait_for_counter(counter: &AtomicU32, max_value: u32) {
     wait_for(|| counter.load(Ordering::Acquire) < max_value)
 }
 
-fn primary_init_early() {
+fn primary_init_early(host_dtb: usize) {
     extern "C" {
         fn __core_end();
     }
@@ -113,16 +123,25 @@ fn primary_init_early() {
     );
     memory::frame::init();
     memory::frame::test();
+    memory::pool::init(platform::guest_mem_pool());
     event::init(MAX_CPU_NUM);
+    #[cfg(feature = "vcpu_sched")]
+    scheduler::init(MAX_CPU_NUM);
 
+    config::init(host_dtb);
     device::irqchip::primary_init_early();
     // crate::arch::mm::init_hv_page_table().unwrap();
 
     #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
     iommu_init();
 
+    vmid_init();
     #[cfg(not(test))]
-    zone_create(root_zone_config()).unwrap();
+    {
+        let mut rollback = ZoneRollback::new();
+        zone_create(root_zone_config(), &mut rollback).unwrap();
+        rollback.commit();
+    }
 
     INIT_EARLY_OK.store(1, Ordering::Release);
 }
@@ -192,13 +211,15 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
     setup_parange();
 
     if is_primary {
-        primary_init_early(); // create root zone here
+        primary_init_early(host_dtb); // create root zone here
     } else {
         wait_for_counter(&INIT_EARLY_OK, 1);
     }
 
     per_cpu_init(cpu);
     device::irqchip::percpu_init();
+    #[cfg(feature = "vcpu_sched")]
+    scheduler::percpu_init();
 
     INITED_CPUS.fetch_add(1, Ordering::SeqCst);
 
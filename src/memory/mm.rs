@@ -117,6 +117,7 @@ where
         if let Entry::Occupied(e) = self.regions.entry(start) {
             self.pt.unmap(e.get())?;
             e.remove();
+            self.pt.flush(None);
             Ok(())
         } else {
             hv_result_err!(
@@ -129,11 +130,127 @@ where
         }
     }
 
+    /// Cut `[start, end)` out of the region containing it. The parts of the
+    /// region before and after the range stay in the set, the cut part is
+    /// returned.
+    fn cut_region(&mut self, start: usize, end: usize) -> HvResult<MemoryRegion<PT::VA>> {
+        let region = match self.regions.range(..=PT::VA::from(start)).next_back() {
+            Some((_, region)) if region.start.into() + region.size >= end => region.clone(),
+            _ => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("{:#x?} is not inside one memory region", start..end)
+                )
+            }
+        };
+        self.regions.remove(&region.start);
+        let region_start = region.start.into();
+        let region_end = region_start + region.size;
+        let part = |from: usize, to: usize| MemoryRegion {
+            start: PT::VA::from(from),
+            size: to - from,
+            flags: region.flags,
+            mapper: region.mapper.clone(),
+        };
+        if region_start < start {
+            self.regions
+                .insert(PT::VA::from(region_start), part(region_start, start));
+        }
+        if end < region_end {
+            self.regions
+                .insert(PT::VA::from(end), part(end, region_end));
+        }
+        Ok(part(start, end))
+    }
+
+    /// Change the flags of `[start, start + size)`, which must lie inside one
+    /// region. That region is cut so each part keeps a single set of flags.
+    pub fn protect(&mut self, start: PT::VA, size: usize, flags: MemFlags) -> HvResult {
+        assert!(is_aligned(start.into()));
+        assert!(is_aligned(size));
+        let start = start.into();
+        let mut region = self.cut_region(start, start + size)?;
+        let old_flags = region.flags;
+        region.flags = flags;
+        let res = self.pt.protect(&region);
+        if res.is_err() {
+            // stopped at a block that could not be split, put the old flags
+            // back on the pages before it: they are split already, so this
+            // gets at least as far
+            region.flags = old_flags;
+            let _ = self.pt.protect(&region);
+        }
+        let start = region.start;
+        self.regions.insert(start, region);
+        self.join_regions(start);
+        res
+    }
+
+    /// Unmap `[start, start + size)`, which must lie inside one region. The
+    /// rest of that region stays mapped.
+    pub fn unmap_range(&mut self, start: PT::VA, size: usize) -> HvResult {
+        assert!(is_aligned(start.into()));
+        assert!(is_aligned(size));
+        let start = start.into();
+        let region = self.cut_region(start, start + size)?;
+        let res = self.pt.unmap(&region);
+        if res.is_err() {
+            // stopped at a block that could not be split, keep the record
+            let start = region.start;
+            self.regions.insert(start, region);
+            self.join_regions(start);
+        }
+        self.pt.flush(None);
+        res
+    }
+
+    /// Join the region at `start` with its neighbours if they continue the
+    /// same mapping with the same flags.
+    fn join_regions(&mut self, start: PT::VA) {
+        let joinable = |a: &MemoryRegion<PT::VA>, b: &MemoryRegion<PT::VA>| {
+            a.start.into() + a.size == b.start.into()
+                && a.flags.bits() == b.flags.bits()
+                && matches!((&a.mapper, &b.mapper),
+                    (Mapper::Offset(x), Mapper::Offset(y)) if x == y)
+        };
+        let mut start = start;
+        if let Some((&before, prev)) = self.regions.range(..start).next_back() {
+            if joinable(prev, &self.regions[&start]) {
+                let region = self.regions.remove(&start).unwrap();
+                start = before;
+                self.regions.get_mut(&start).unwrap().size += region.size;
+            }
+        }
+        let next = PT::VA::from(start.into() + self.regions[&start].size);
+        if let Some(after) = self.regions.get(&next) {
+            if joinable(&self.regions[&start], after) {
+                let region = self.regions.remove(&next).unwrap();
+                self.regions.get_mut(&start).unwrap().size += region.size;
+            }
+        }
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
             self.pt.unmap(region).unwrap();
         }
         self.regions.clear();
+        self.pt.flush(None);
+    }
+
+    /// Frames used by the page table of this set, root included.
+    pub fn page_table_frames(&self) -> usize {
+        self.pt.table_frames()
+    }
+
+    /// Tag the translations of this set with `vmid`, before it is activated.
+    pub fn set_vmid(&mut self, vmid: usize) {
+        self.pt.set_vmid(vmid);
+    }
+
+    /// Invalidate the cached translation of `vaddr`, or of the whole set.
+    pub fn flush(&self, vaddr: Option<PT::VA>) {
+        self.pt.flush(vaddr);
     }
 
     pub unsafe fn activate(&self) {
@@ -183,6 +300,10 @@ where
     }
 }
 
+/// VMID of the parking set, kept out of the zones' VMIDs so a parked cpu
+/// never hits a zone's translations.
+pub const PARKING_VMID: usize = 0;
+
 pub static PARKING_MEMORY_SET: Once<MemorySet<Stage2PageTable>> = Once::new();
 
 pub static mut PARKING_INST_PAGE: AlignedPage = AlignedPage::new();
@@ -4,6 +4,7 @@ pub mod heap;
 pub mod mapper;
 pub mod mm;
 pub mod mmio;
+pub mod pool;
 
 use core::ops::{Deref, DerefMut};
 
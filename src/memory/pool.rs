@@ -0,0 +1,149 @@
+//! Host RAM that hvisor hands out as guest RAM, for zones whose config
+//! leaves the physical placement to the hypervisor.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::addr::{is_aligned, phys_to_virt, PhysAddr};
+use crate::consts::PAGE_SIZE;
+use crate::error::HvResult;
+
+#[cfg(test)]
+mod tests;
+
+/// Guest RAM is aligned for block mappings when its size allows it.
+const BLOCK_SIZE: usize = 0x20_0000;
+
+struct GuestMemPool {
+    /// the ranges given to the pool
+    ranges: Vec<(PhysAddr, usize)>,
+    /// start -> size of each free range, neighbours are kept merged
+    free: BTreeMap<PhysAddr, usize>,
+}
+
+static GUEST_MEM_POOL: Mutex<GuestMemPool> = Mutex::new(GuestMemPool::empty());
+
+impl GuestMemPool {
+    const fn empty() -> Self {
+        Self {
+            ranges: Vec::new(),
+            free: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, start: PhysAddr, size: usize) {
+        self.ranges.push((start, size));
+        self.insert_free(start, size);
+    }
+
+    fn insert_free(&mut self, mut start: PhysAddr, mut size: usize) {
+        if let Some((&prev, &prev_size)) = self.free.range(..start).next_back() {
+            if prev + prev_size == start {
+                self.free.remove(&prev);
+                start = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(start + size)) {
+            size += next_size;
+        }
+        self.free.insert(start, size);
+    }
+
+    fn alloc(&mut self, size: usize, align: usize) -> Option<PhysAddr> {
+        let (start, free_size, paddr) = self.free.iter().find_map(|(&start, &free_size)| {
+            let paddr = (start + align - 1) & !(align - 1);
+            (paddr + size <= start + free_size).then_some((start, free_size, paddr))
+        })?;
+        self.free.remove(&start);
+        if start < paddr {
+            self.free.insert(start, paddr - start);
+        }
+        if paddr + size < start + free_size {
+            self.free
+                .insert(paddr + size, start + free_size - (paddr + size));
+        }
+        Some(paddr)
+    }
+
+    fn overlaps(&self, start: PhysAddr, size: usize) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(pool, pool_size)| start < pool + pool_size && pool < start + size)
+    }
+}
+
+/// Guest RAM allocated from the pool, zeroed. It goes back to the pool when
+/// dropped, after the zone can no longer reach it.
+#[derive(Debug)]
+pub struct GuestRam {
+    start_paddr: PhysAddr,
+    size: usize,
+}
+
+impl GuestRam {
+    pub fn new(size: usize) -> HvResult<Self> {
+        if size == 0 || !is_aligned(size) {
+            return hv_result_err!(EINVAL, format!("bad guest RAM size {:#x}", size));
+        }
+        let align = if size % BLOCK_SIZE == 0 {
+            BLOCK_SIZE
+        } else {
+            PAGE_SIZE
+        };
+        let start_paddr = match GUEST_MEM_POOL.lock().alloc(size, align) {
+            Some(start_paddr) => start_paddr,
+            None => {
+                return hv_result_err!(
+                    ENOMEM,
+                    format!("guest memory pool can't fit {:#x} bytes", size)
+                )
+            }
+        };
+        let ram = Self { start_paddr, size };
+        ram.clear();
+        Ok(ram)
+    }
+
+    pub fn start_paddr(&self) -> PhysAddr {
+        self.start_paddr
+    }
+
+    /// Whether the RAM overlaps `[start, start + size)`.
+    pub fn overlaps(&self, start: PhysAddr, size: usize) -> bool {
+        start < self.start_paddr + self.size && self.start_paddr < start + size
+    }
+
+    fn clear(&self) {
+        for paddr in (self.start_paddr..self.start_paddr + self.size).step_by(PAGE_SIZE) {
+            let vaddr = phys_to_virt(paddr);
+            #[cfg(target_arch = "loongarch64")]
+            let vaddr = vaddr | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize;
+            unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE) };
+        }
+    }
+}
+
+impl Drop for GuestRam {
+    fn drop(&mut self) {
+        trace!("Free guest RAM: {:#x?}", self);
+        GUEST_MEM_POOL
+            .lock()
+            .insert_free(self.start_paddr, self.size);
+    }
+}
+
+/// Whether `[start, start + size)` is part of the pool, allocated or not.
+pub fn pool_overlaps(start: PhysAddr, size: usize) -> bool {
+    GUEST_MEM_POOL.lock().overlaps(start, size)
+}
+
+/// Give the platform's guest memory ranges to the pool.
+pub fn init(ranges: &[(PhysAddr, usize)]) {
+    let mut pool = GUEST_MEM_POOL.lock();
+    for &(start, size) in ranges {
+        pool.add(start, size);
+    }
+    info!("Guest memory pool initialization finished: {:#x?}", ranges);
+}
@@ -0,0 +1,21 @@
+use super::*;
+
+#[test_case]
+fn test_guest_mem_pool() {
+    let mut pool = GuestMemPool::empty();
+    pool.add(0x9010_0000, 0x50_0000);
+    assert!(pool.overlaps(0x9050_0000, 0x1000));
+    assert!(!pool.overlaps(0x9060_0000, 0x1000));
+
+    // 2M-sized RAM is 2M aligned, the gap below stays free
+    assert_eq!(pool.alloc(0x20_0000, BLOCK_SIZE), Some(0x9020_0000));
+    assert_eq!(pool.alloc(0x20_0000, BLOCK_SIZE), Some(0x9040_0000));
+    assert_eq!(pool.alloc(0x20_0000, BLOCK_SIZE), None);
+    assert_eq!(pool.alloc(0x1000, PAGE_SIZE), Some(0x9010_0000));
+
+    // freed ranges merge with their neighbours
+    pool.insert_free(0x9020_0000, 0x20_0000);
+    pool.insert_free(0x9040_0000, 0x20_0000);
+    assert_eq!(pool.free.get(&0x9010_1000), Some(&0x4f_f000));
+    assert_eq!(pool.alloc(0x40_0000, BLOCK_SIZE), Some(0x9020_0000));
+}
@@ -0,0 +1,141 @@
+//! Zone lifecycle notifications for the root zone. The root zone hands over
+//! a page with `HvZoneEventInit`, then the hypervisor appends a `ZoneEvent`
+//! to the ring in it whenever a zone changes state, and raises
+//! `IRQ_ZONE_EVENT` in the root zone.
+//!
+//! On loongarch64 no virtual irq is raised: only the cpu's HWI and IPI lines
+//! can be injected into a guest there and `IRQ_ZONE_EVENT` maps to none of
+//! them, so the root zone has to poll the ring.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+
+use crate::arch::cpu::time_ms;
+use crate::consts::PAGE_SIZE;
+#[cfg(not(target_arch = "loongarch64"))]
+use crate::{
+    event::{send_event, IPI_EVENT_ZONE_NOTIFY},
+    hypercall::SGI_IPI_ID,
+    zone::root_zone,
+};
+
+#[cfg(test)]
+mod tests;
+
+pub const ZONE_EVENT_STARTED: u32 = 0;
+/// the zone powered itself off or was shut down by the root zone
+pub const ZONE_EVENT_EXITED: u32 = 1;
+/// `arg` holds one of the `ZONE_CRASH_*` reasons
+pub const ZONE_EVENT_CRASHED: u32 = 2;
+/// `arg` holds the physical cpu id
+pub const ZONE_EVENT_CPU_OFFLINE: u32 = 3;
+/// the zone's watchdog ran out, `arg` holds the `WatchdogAction` taken
+pub const ZONE_EVENT_WATCHDOG: u32 = 4;
+
+/// the guest reported a system failure when it went down
+pub const ZONE_CRASH_GUEST_REPORTED: u64 = 1;
+/// the guest took a trap the hypervisor has no handler for
+pub const ZONE_CRASH_UNHANDLED_TRAP: u64 = 2;
+/// the guest accessed a physical address that is neither RAM nor a device
+pub const ZONE_CRASH_BAD_ACCESS: u64 = 3;
+/// the trapped instruction couldn't be decoded for emulation
+pub const ZONE_CRASH_BAD_INSTRUCTION: u64 = 4;
+/// the zone's watchdog ran out and its action is to stop the zone
+pub const ZONE_CRASH_WATCHDOG: u64 = 5;
+/// the guest asked for a reset but the zone couldn't be restarted
+pub const ZONE_CRASH_REBOOT_FAILED: u64 = 6;
+
+/// The virtual irq raised in the root zone for new events, per platform.
+pub use crate::platform::IRQ_ZONE_EVENT;
+pub const ZONE_EVENT_RING_SIZE: usize = 128;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneEvent {
+    pub zone_id: u32,
+    pub event: u32,
+    pub arg: u64,
+    pub time_ms: u64,
+}
+
+/// The ring shared with the root zone. The hypervisor only moves `head`, the
+/// root zone only moves `tail`; both run freely and wrap around.
+#[repr(C)]
+pub struct ZoneEventRing {
+    pub head: u32,
+    pub tail: u32,
+    /// events lost because the root zone didn't keep up
+    pub dropped: u32,
+    pub reserved: u32,
+    pub entries: [ZoneEvent; ZONE_EVENT_RING_SIZE],
+}
+
+const _: () = assert!(core::mem::size_of::<ZoneEventRing>() <= PAGE_SIZE);
+
+impl ZoneEventRing {
+    fn reset(&mut self) {
+        unsafe {
+            write_volatile(&mut self.head, 0);
+            write_volatile(&mut self.tail, 0);
+            write_volatile(&mut self.dropped, 0);
+        }
+    }
+
+    /// Append `event`, returns false if the ring was full and it was dropped.
+    fn push(&mut self, event: ZoneEvent) -> bool {
+        let head = unsafe { read_volatile(&self.head) };
+        let tail = unsafe { read_volatile(&self.tail) };
+        if head.wrapping_sub(tail) as usize >= ZONE_EVENT_RING_SIZE {
+            unsafe { write_volatile(&mut self.dropped, read_volatile(&self.dropped) + 1) };
+            return false;
+        }
+        unsafe {
+            write_volatile(
+                &mut self.entries[head as usize % ZONE_EVENT_RING_SIZE],
+                event,
+            );
+        }
+        // the root zone must see the entry before the new head
+        fence(Ordering::Release);
+        unsafe { write_volatile(&mut self.head, head.wrapping_add(1)) };
+        true
+    }
+}
+
+/// Address of the ring, 0 until the root zone sets it up.
+static EVENT_RING: Mutex<usize> = Mutex::new(0);
+
+pub fn init(ring_addr: usize) {
+    let mut ring = EVENT_RING.lock();
+    unsafe { (*(ring_addr as *mut ZoneEventRing)).reset() };
+    *ring = ring_addr;
+}
+
+/// Tell the root zone about `event` of zone `zone_id`. Nothing happens until
+/// the root zone has set up the ring.
+pub fn notify_root(zone_id: usize, event: u32, arg: u64) {
+    let ring = EVENT_RING.lock();
+    if *ring == 0 {
+        return;
+    }
+    let pushed = unsafe { &mut *(*ring as *mut ZoneEventRing) }.push(ZoneEvent {
+        zone_id: zone_id as _,
+        event,
+        arg,
+        time_ms: time_ms(),
+    });
+    drop(ring);
+    if !pushed {
+        warn!(
+            "zone event ring is full, dropped event {} of zone {}",
+            event, zone_id
+        );
+    }
+    // no irq to raise on loongarch64, the root zone polls the ring
+    #[cfg(not(target_arch = "loongarch64"))]
+    {
+        let root_cpu = root_zone().read().boot_cpu();
+        send_event(root_cpu, SGI_IPI_ID as _, IPI_EVENT_ZONE_NOTIFY);
+    }
+}
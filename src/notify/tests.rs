@@ -0,0 +1,23 @@
+use super::*;
+use alloc::boxed::Box;
+
+#[test_case]
+fn test_event_ring_push() {
+    let mut ring: Box<ZoneEventRing> = Box::new(unsafe { core::mem::zeroed() });
+    ring.reset();
+    let event = ZoneEvent {
+        zone_id: 1,
+        event: ZONE_EVENT_STARTED,
+        arg: 0,
+        time_ms: 0,
+    };
+    for _ in 0..ZONE_EVENT_RING_SIZE {
+        assert!(ring.push(event));
+    }
+    // full until the root zone consumes
+    assert!(!ring.push(event));
+    assert_eq!(ring.dropped, 1);
+    ring.tail = 1;
+    assert!(ring.push(event));
+    assert_eq!(ring.head as usize, ZONE_EVENT_RING_SIZE + 1);
+}
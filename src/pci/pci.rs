@@ -1,11 +1,11 @@
 use core::{ptr, usize};
 
-use crate::config::{HvPciConfig, CONFIG_MAX_PCI_DEV};
+use crate::config::HvPciConfig;
 use crate::memory::addr::align_down;
 use crate::pci::pcibar::BarType;
 use crate::pci::{get_ecam_base, init_ecam_base};
 use crate::percpu::this_zone;
-use crate::zone::this_zone_id;
+use crate::zone::{this_zone_id, ZoneRollback};
 use crate::{
     error::HvResult,
     memory::MMIOAccess,
@@ -24,7 +24,7 @@ use super::{
 };
 
 #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
-use crate::arch::iommu::iommu_add_device;
+use crate::arch::iommu::{iommu_add_device, iommu_remove_device};
 
 #[derive(Debug)]
 pub struct PciRoot {
@@ -46,6 +46,10 @@ impl PciRoot {
         r
     }
 
+    pub fn alloc_devs(&self) -> &[usize] {
+        &self.alloc_devs
+    }
+
     pub fn is_assigned_device(&self, bdf: usize) -> bool {
         if self.alloc_devs.contains(&bdf) {
             true
@@ -121,26 +125,26 @@ impl Zone {
     pub fn pci_init(
         &mut self,
         pci_config: &HvPciConfig,
-        num_pci_devs: usize,
-        alloc_pci_devs: &[u64; CONFIG_MAX_PCI_DEV],
+        alloc_pci_devs: &[u64],
+        rollback: &mut ZoneRollback,
     ) {
-        if num_pci_devs == 0 {
+        if alloc_pci_devs.is_empty() {
             return;
         }
 
         info!("PCIe init!");
 
         init_ecam_base(pci_config.ecam_base as _);
+        #[cfg(not(all(feature = "platform_qemu", target_arch = "aarch64")))]
+        let _ = rollback;
 
-        for idx in 0..num_pci_devs {
-            info!(
-                "PCIe device assigned to zone {}: {:#x}",
-                self.id, alloc_pci_devs[idx]
-            );
-            self.pciroot.alloc_devs.push(alloc_pci_devs[idx] as _);
+        for &bdf in alloc_pci_devs {
+            info!("PCIe device assigned to zone {}: {:#x}", self.id, bdf);
+            self.pciroot.alloc_devs.push(bdf as _);
             #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
-            if alloc_pci_devs[idx] != 0 {
-                iommu_add_device(self.id, alloc_pci_devs[idx] as _);
+            if bdf != 0 {
+                iommu_add_device(self.id, bdf as _);
+                rollback.push(move || iommu_remove_device(bdf as _));
             }
         }
 
@@ -152,6 +156,17 @@ impl Zone {
         }
     }
 
+    /// Release hook of `remove_zone`: take the zone's devices out of the
+    /// SMMU so they can't DMA through its freed stage-2 table.
+    pub fn pci_remove(&self) {
+        #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
+        for &bdf in self.pciroot.alloc_devs() {
+            if bdf != 0 {
+                iommu_remove_device(bdf as _);
+            }
+        }
+    }
+
     pub fn root_pci_init(&mut self, pci_config: &HvPciConfig) {
         // Virtual ECAM
 
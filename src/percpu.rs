@@ -8,7 +8,8 @@ use crate::memory::addr::VirtAddr;
 use crate::zone::Zone;
 use crate::{arch, ENTERED_CPUS};
 use core::fmt::Debug;
-use core::sync::atomic::Ordering;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 // global_asm!(include_str!("./arch/aarch64/page_table.S"),);
 
@@ -24,24 +25,38 @@ pub struct PerCpu {
     pub zone: Option<Arc<RwLock<Zone>>>,
     pub ctrl_lock: Mutex<()>,
     pub boot_cpu: bool,
+    /// set by the root zone to ask this cpu to park, cleared to resume it
+    pub pause_req: AtomicBool,
+    /// whether this cpu is currently parked in `wait_for_resume`
+    pub paused: AtomicBool,
+    /// priority of the vcpu under the fixed priority scheduling policy
+    #[cfg(feature = "vcpu_sched")]
+    pub sched_priority: u8,
     // percpu stack
 }
 
 impl PerCpu {
+    /// The state of a cpu that hasn't been given to any zone yet.
+    pub fn blank(cpu_id: usize) -> Self {
+        PerCpu {
+            id: cpu_id,
+            cpu_on_entry: INVALID_ADDRESS,
+            dtb_ipa: INVALID_ADDRESS,
+            arch_cpu: ArchCpu::new(cpu_id),
+            zone: None,
+            ctrl_lock: Mutex::new(()),
+            boot_cpu: false,
+            pause_req: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            #[cfg(feature = "vcpu_sched")]
+            sched_priority: 0,
+        }
+    }
+
     pub fn new<'a>(cpu_id: usize) -> &'static mut PerCpu {
         let vaddr = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
         let ret = vaddr as *mut Self;
-        unsafe {
-            ret.write_volatile(PerCpu {
-                id: cpu_id,
-                cpu_on_entry: INVALID_ADDRESS,
-                dtb_ipa: INVALID_ADDRESS,
-                arch_cpu: ArchCpu::new(cpu_id),
-                zone: None,
-                ctrl_lock: Mutex::new(()),
-                boot_cpu: false,
-            })
-        };
+        unsafe { ret.write_volatile(Self::blank(cpu_id)) };
         #[cfg(target_arch = "riscv64")]
         {
             use crate::arch::csr::{write_csr, CSR_SSCRATCH};
@@ -66,6 +81,55 @@ impl PerCpu {
         ENTERED_CPUS.load(Ordering::Acquire)
     }
 
+    /// Spin in the hypervisor until `pause_req` is cleared. The guest context
+    /// saved on the trap stack is left untouched, so the vcpu continues right
+    /// where it was interrupted.
+    pub fn wait_for_resume(&self) {
+        info!("CPU{}: paused", self.id);
+        self.paused.store(true, Ordering::Release);
+        while self.pause_req.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        self.paused.store(false, Ordering::Release);
+        info!("CPU{}: resumed", self.id);
+    }
+
+    /// The vcpu id of this cpu in its zone, or the physical id if it has none.
+    pub fn vcpu_id(&self) -> usize {
+        self.zone
+            .as_ref()
+            .and_then(|zone| zone.read().cpu_to_vcpu(self.id))
+            .unwrap_or(self.id)
+    }
+
+    /// Whether this vcpu belongs to the zone running on the current cpu. A
+    /// cpu can change zones between looking it up and locking it.
+    pub fn in_this_zone(&self) -> bool {
+        match (self.zone.as_ref(), this_cpu_data().zone.as_ref()) {
+            (Some(zone), Some(this)) => Arc::ptr_eq(zone, this),
+            _ => false,
+        }
+    }
+
+    /// Exchange the vcpu-specific state with a vcpu saved by the scheduler.
+    #[cfg(feature = "vcpu_sched")]
+    pub fn swap_vcpu(&mut self, other: &mut PerCpu) {
+        core::mem::swap(&mut self.cpu_on_entry, &mut other.cpu_on_entry);
+        core::mem::swap(&mut self.dtb_ipa, &mut other.dtb_ipa);
+        core::mem::swap(&mut self.zone, &mut other.zone);
+        core::mem::swap(&mut self.boot_cpu, &mut other.boot_cpu);
+        core::mem::swap(&mut self.arch_cpu.power_on, &mut other.arch_cpu.power_on);
+        core::mem::swap(&mut self.sched_priority, &mut other.sched_priority);
+        for (mine, theirs) in [
+            (&self.pause_req, &other.pause_req),
+            (&self.paused, &other.paused),
+        ] {
+            let v = mine.load(Ordering::Acquire);
+            mine.store(theirs.load(Ordering::Acquire), Ordering::Release);
+            theirs.store(v, Ordering::Release);
+        }
+    }
+
     pub fn activate_gpm(&self) {
         unsafe {
             self.zone.clone().unwrap().read().gpm.activate();
@@ -78,6 +142,49 @@ pub fn get_cpu_data<'a>(cpu_id: usize) -> &'a mut PerCpu {
     unsafe { &mut *(cpu_data as *mut PerCpu) }
 }
 
+/// A vcpu's `PerCpu`, see `zone_cpu_data`.
+pub enum ZoneCpuData<'a> {
+    Running(&'a mut PerCpu),
+    #[cfg(feature = "vcpu_sched")]
+    Queued(crate::scheduler::QueuedCpuData<'a>),
+}
+
+impl Deref for ZoneCpuData<'_> {
+    type Target = PerCpu;
+
+    fn deref(&self) -> &PerCpu {
+        match self {
+            Self::Running(cpu_data) => cpu_data,
+            #[cfg(feature = "vcpu_sched")]
+            Self::Queued(cpu_data) => cpu_data,
+        }
+    }
+}
+
+impl DerefMut for ZoneCpuData<'_> {
+    fn deref_mut(&mut self) -> &mut PerCpu {
+        match self {
+            Self::Running(cpu_data) => cpu_data,
+            #[cfg(feature = "vcpu_sched")]
+            Self::Queued(cpu_data) => cpu_data,
+        }
+    }
+}
+
+/// The per-cpu state of `zone_id`'s vcpu on `cpu_id`. Callers hold the
+/// cpu's `ctrl_lock` to keep the vcpus on it from being swapped. With the
+/// vcpu scheduler this may be the saved copy of a waiting vcpu, which keeps
+/// the cpu's run queue locked: drop it before sending the vcpu an event.
+pub fn zone_cpu_data<'a>(cpu_id: usize, zone_id: usize) -> ZoneCpuData<'a> {
+    #[cfg(feature = "vcpu_sched")]
+    if let Some(cpu_data) = crate::scheduler::queued_cpu_data(cpu_id, zone_id) {
+        return ZoneCpuData::Queued(cpu_data);
+    }
+    #[cfg(not(feature = "vcpu_sched"))]
+    let _ = zone_id;
+    ZoneCpuData::Running(get_cpu_data(cpu_id))
+}
+
 pub fn this_cpu_data<'a>() -> &'a mut PerCpu {
     get_cpu_data(this_cpu_id())
 }
@@ -0,0 +1,198 @@
+//! Probe the root zone's hardware from the device tree hvisor was booted
+//! with, so the same image boots boards that differ in memory size and
+//! interrupt controller placement.
+
+use alloc::vec::Vec;
+use fdt::{node::FdtNode, standard_nodes::MemoryRegion, Fdt};
+
+use crate::{
+    arch::zone::HvArchZoneConfig,
+    config::{HvConfigMemoryRegion, MEM_TYPE_RAM},
+    consts::hv_phys_range,
+    memory::addr::{align_down, align_up, PhysAddr},
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Physical `[start, end)`.
+type Range = (PhysAddr, PhysAddr);
+
+/// Safety: `host_dtb` must be 0 or point at a mapped flattened device tree.
+pub unsafe fn host_fdt(host_dtb: usize) -> Option<Fdt<'static>> {
+    if host_dtb == 0 {
+        return None;
+    }
+    #[cfg(target_arch = "loongarch64")]
+    let host_dtb = host_dtb | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize;
+    Fdt::from_ptr(host_dtb as *const u8).ok()
+}
+
+fn reg_range(reg: MemoryRegion) -> Option<Range> {
+    let start = reg.starting_address as usize;
+    // a range running past the end of the address space is bogus
+    let end = start.checked_add(reg.size?)?;
+    Some((start, end))
+}
+
+fn reg_ranges(node: FdtNode) -> Vec<Range> {
+    node.reg()
+        .into_iter()
+        .flatten()
+        .filter_map(reg_range)
+        .collect()
+}
+
+/// Remove `hole` from `ranges`. What is left stays page aligned.
+pub fn carve(ranges: &[Range], hole: Range) -> Vec<Range> {
+    let mut left = Vec::new();
+    for &(start, end) in ranges {
+        if hole.1 <= start || hole.0 >= end {
+            left.push((start, end));
+            continue;
+        }
+        if hole.0 > start {
+            left.push((start, align_down(hole.0)));
+        }
+        if hole.1 < end {
+            left.push((align_up(hole.1), end));
+        }
+    }
+    left.retain(|(start, end)| start < end);
+    left
+}
+
+/// Memory neither the root zone nor any other zone may own: hvisor itself
+/// and what the firmware keeps out of the kernel's reach.
+fn reserved_ranges(fdt: &Fdt) -> Vec<Range> {
+    let mut reserved = Vec::new();
+    let hv_mem = hv_phys_range();
+    reserved.push((hv_mem.start, hv_mem.end));
+    reserved.extend(fdt.memory_reservations().map(|resv| {
+        let start = resv.address() as usize;
+        // keep an overflowing reservation, reserving too much is harmless
+        (start, start.saturating_add(resv.size()))
+    }));
+    if let Some(node) = fdt.find_node("/reserved-memory") {
+        for child in node.children() {
+            // reusable carve-outs such as CMA are still ordinary RAM
+            if child.property("no-map").is_some() {
+                reserved.extend(reg_ranges(child));
+            }
+        }
+    }
+    reserved
+}
+
+/// The RAM of every memory node, without the reserved ranges.
+pub fn ram_regions(fdt: &Fdt) -> Vec<HvConfigMemoryRegion> {
+    let mut ram: Vec<Range> = fdt
+        .all_nodes()
+        .filter(|node| {
+            node.property("device_type").and_then(|prop| prop.as_str()) == Some("memory")
+        })
+        .flat_map(reg_ranges)
+        .map(|(start, end)| (align_up(start), align_down(end)))
+        .filter(|(start, end)| start < end)
+        .collect();
+    for hole in reserved_ranges(fdt) {
+        ram = carve(&ram, hole);
+    }
+    ram.into_iter()
+        .map(|(start, end)| HvConfigMemoryRegion {
+            mem_type: MEM_TYPE_RAM,
+            physical_start: start as _,
+            virtual_start: start as _,
+            size: (end - start) as _,
+        })
+        .collect()
+}
+
+#[cfg(not(target_arch = "loongarch64"))]
+fn set_range(regs: &[Range], idx: usize, base: &mut usize, size: &mut usize) {
+    if let Some(&(start, end)) = regs.get(idx) {
+        *base = start;
+        *size = end - start;
+    }
+}
+
+#[cfg(any(feature = "gicv3", target_arch = "riscv64"))]
+fn is_compatible(node: FdtNode, with: &str) -> bool {
+    node.compatible()
+        .map_or(false, |compatible| compatible.all().any(|c| c == with))
+}
+
+/// Replace the interrupt controller ranges of `config` with the ones the
+/// device tree describes. Ranges it doesn't describe are left alone.
+#[cfg(target_arch = "aarch64")]
+pub fn probe_arch_config(fdt: &Fdt, config: &mut HvArchZoneConfig) {
+    #[cfg(feature = "gicv3")]
+    if let Some(gic) = fdt.find_compatible(&["arm,gic-v3"]) {
+        // GICD, GICR, then GICC, GICH and GICV if there is a v2 cpu interface
+        let regs = reg_ranges(gic);
+        set_range(&regs, 0, &mut config.gicd_base, &mut config.gicd_size);
+        set_range(&regs, 1, &mut config.gicr_base, &mut config.gicr_size);
+        set_range(&regs, 2, &mut config.gicc_base, &mut config.gicc_size);
+        set_range(&regs, 3, &mut config.gich_base, &mut config.gich_size);
+        set_range(&regs, 4, &mut config.gicv_base, &mut config.gicv_size);
+        if let Some(its) = gic
+            .children()
+            .find(|child| is_compatible(*child, "arm,gic-v3-its"))
+        {
+            set_range(
+                &reg_ranges(its),
+                0,
+                &mut config.gits_base,
+                &mut config.gits_size,
+            );
+        }
+    }
+    #[cfg(feature = "gicv2")]
+    if let Some(gic) =
+        fdt.find_compatible(&["arm,gic-400", "arm,cortex-a15-gic", "arm,cortex-a9-gic"])
+    {
+        // GICD, GICC, GICH, GICV
+        let regs = reg_ranges(gic);
+        set_range(&regs, 0, &mut config.gicd_base, &mut config.gicd_size);
+        set_range(&regs, 1, &mut config.gicc_base, &mut config.gicc_size);
+        set_range(&regs, 2, &mut config.gich_base, &mut config.gich_size);
+        set_range(&regs, 3, &mut config.gicv_base, &mut config.gicv_size);
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+pub fn probe_arch_config(fdt: &Fdt, config: &mut HvArchZoneConfig) {
+    if let Some(plic) = fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]) {
+        set_range(
+            &reg_ranges(plic),
+            0,
+            &mut config.plic_base,
+            &mut config.plic_size,
+        );
+    }
+    // the M-level APLIC delegates to the S-level one, which has no children
+    if let Some(aplic) = fdt.all_nodes().find(|node| {
+        is_compatible(*node, "riscv,aplic") && node.property("riscv,children").is_none()
+    }) {
+        set_range(
+            &reg_ranges(aplic),
+            0,
+            &mut config.aplic_base,
+            &mut config.aplic_size,
+        );
+    }
+}
+
+/// The `timebase-frequency` of `/cpus`, or of its first cpu node.
+#[cfg(target_arch = "riscv64")]
+pub fn timebase_frequency(fdt: &Fdt) -> Option<u64> {
+    let cpus = fdt.find_node("/cpus")?;
+    let prop = cpus.property("timebase-frequency").or_else(|| {
+        cpus.children()
+            .find_map(|cpu| cpu.property("timebase-frequency"))
+    })?;
+    prop.as_usize().map(|freq| freq as u64)
+}
+
+#[cfg(target_arch = "loongarch64")]
+pub fn probe_arch_config(_fdt: &Fdt, _config: &mut HvArchZoneConfig) {}
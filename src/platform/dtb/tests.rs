@@ -0,0 +1,35 @@
+use super::*;
+
+#[test_case]
+fn test_carve() {
+    let ram = [(0x4000_0000, 0x8000_0000), (0x1_0000_0000, 0x1_1000_0000)];
+    // hole in the middle of a range, unaligned
+    assert_eq!(
+        carve(&ram, (0x4040_0000, 0x41c0_0123)),
+        [
+            (0x4000_0000, 0x4040_0000),
+            (0x41c0_1000, 0x8000_0000),
+            (0x1_0000_0000, 0x1_1000_0000)
+        ]
+    );
+    // hole covering a whole range and the start of the next one
+    assert_eq!(
+        carve(&ram, (0x3000_0000, 0x1_0800_0000)),
+        [(0x1_0800_0000, 0x1_1000_0000)]
+    );
+    // hole outside every range
+    assert_eq!(carve(&ram, (0x9000_0000, 0xa000_0000)), ram);
+}
+
+#[test_case]
+fn test_reg_range_overflow() {
+    let reg = |start: usize, size| MemoryRegion {
+        starting_address: start as *const u8,
+        size: Some(size),
+    };
+    assert_eq!(
+        reg_range(reg(0x4000_0000, 0x1000)),
+        Some((0x4000_0000, 0x4000_1000))
+    );
+    assert_eq!(reg_range(reg(usize::MAX - 0xfff, 0x2000)), None);
+}
@@ -7,6 +7,9 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1);
 
 pub const ROOT_ZONE_NAME: &str = "root-linux";
 
+/// Host RAM hvisor allocates guest RAM from, as (start, size).
+pub const GUEST_MEM_POOL: &[(usize, usize)] = &[];
+
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 8] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
@@ -70,6 +73,9 @@ pub const ROOT_ZONE_IRQS: [u32; 28] = [
     105, 135, 150, 151, 152, 162,
 ];
 
+/// Virtual irq raised in the root zone for zone events.
+pub const IRQ_ZONE_EVENT: usize = 32 + 0x21;
+
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     gicd_base: 0x38800000,
     gicd_size: 0x10000,
@@ -7,6 +7,9 @@ pub const ROOT_ZONE_CPUS: u64 = 1 << 0;
 
 pub const ROOT_ZONE_NAME: &str = "root-linux-la64";
 
+/// Host RAM hvisor allocates guest RAM from, as (start, size).
+pub const GUEST_MEM_POOL: &[(usize, usize)] = &[];
+
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 7] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
@@ -71,4 +74,7 @@ pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 7] = [
 ];
 
 pub const ROOT_ZONE_IRQS: [u32; 0] = [];
+
+/// Zone events are polled on loongarch64, no irq is raised for them.
+pub const IRQ_ZONE_EVENT: usize = 32 + 0x21;
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig { dummy: 0 };
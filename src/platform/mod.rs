@@ -1,16 +1,21 @@
+use alloc::vec::Vec;
+
 use crate::{
     config::{
         HvConfigMemoryRegion, HvIvcConfig, HvPciConfig, HvZoneConfig, CONFIG_MAX_INTERRUPTS,
         CONFIG_MAX_IVC_CONGIGS, CONFIG_MAX_MEMORY_REGIONS, CONFIG_MAX_PCI_DEV, CONFIG_NAME_MAXLEN,
+        MEM_TYPE_RAM,
     },
     consts::INVALID_ADDRESS,
 };
 
+pub mod dtb;
+
 #[cfg(all(feature = "platform_qemu", target_arch = "riscv64"))]
 pub mod qemu_riscv64;
 
 #[cfg(all(feature = "platform_qemu", target_arch = "riscv64"))]
-use qemu_riscv64::*;
+pub use qemu_riscv64::*;
 
 #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
 pub mod qemu_aarch64;
@@ -22,7 +27,7 @@ pub use qemu_aarch64::*;
 pub mod imx8mp_aarch64;
 
 #[cfg(all(feature = "platform_imx8mp", target_arch = "aarch64"))]
-use imx8mp_aarch64::*;
+pub use imx8mp_aarch64::*;
 
 #[cfg(all(feature = "platform_zcu102", target_arch = "aarch64"))]
 pub mod zcu102_aarch64;
@@ -36,7 +41,69 @@ pub mod ls3a5000_loongarch64;
 #[cfg(target_arch = "loongarch64")]
 pub use ls3a5000_loongarch64::*;
 
-pub fn platform_root_zone_config() -> HvZoneConfig {
+/// Host RAM set aside for the zones whose RAM hvisor allocates, neither the
+/// root zone nor a fixed zone config may use it.
+pub fn guest_mem_pool() -> &'static [(usize, usize)] {
+    GUEST_MEM_POOL
+}
+
+/// `regions` with the guest memory pool taken out of their RAM.
+fn carve_guest_mem_pool(regions: &[HvConfigMemoryRegion]) -> Vec<HvConfigMemoryRegion> {
+    let mut carved = Vec::new();
+    for region in regions {
+        if region.mem_type != MEM_TYPE_RAM {
+            carved.push(*region);
+            continue;
+        }
+        let start = region.physical_start as usize;
+        let mut ram = vec![(start, start + region.size as usize)];
+        for &(pool, pool_size) in guest_mem_pool() {
+            ram = dtb::carve(&ram, (pool, pool + pool_size));
+        }
+        carved.extend(
+            ram.into_iter()
+                .map(|(ram_start, ram_end)| HvConfigMemoryRegion {
+                    physical_start: ram_start as _,
+                    virtual_start: region.virtual_start + (ram_start - start) as u64,
+                    size: (ram_end - ram_start) as _,
+                    ..*region
+                }),
+        );
+    }
+    carved
+}
+
+/// The root zone's RAM and interrupt controller come from the host device
+/// tree when there is one, everything else from the platform constants.
+pub fn platform_root_zone_config(host_dtb: usize) -> HvZoneConfig {
+    // the pool is never the root zone's, whichever layout is used
+    let mut regions = carve_guest_mem_pool(&ROOT_ZONE_MEMORY_REGIONS);
+    let mut arch_config = ROOT_ARCH_ZONE_CONFIG;
+    match unsafe { dtb::host_fdt(host_dtb) } {
+        Some(fdt) => {
+            let ram = carve_guest_mem_pool(&dtb::ram_regions(&fdt));
+            let devices = ROOT_ZONE_MEMORY_REGIONS
+                .iter()
+                .filter(|region| region.mem_type != MEM_TYPE_RAM);
+            if ram.is_empty() {
+                warn!("host device tree has no usable memory, using the built-in layout");
+            } else if ram.len() + devices.clone().count() > CONFIG_MAX_MEMORY_REGIONS {
+                warn!("host device tree has too many memory ranges, using the built-in layout");
+            } else {
+                regions = ram.into_iter().chain(devices.copied()).collect::<Vec<_>>();
+            }
+            dtb::probe_arch_config(&fdt, &mut arch_config);
+            #[cfg(target_arch = "riscv64")]
+            if let Some(freq) = dtb::timebase_frequency(&fdt) {
+                crate::arch::cpu::set_timebase_freq(freq);
+            }
+        }
+        None => warn!(
+            "no host device tree at {:#x}, using the built-in root zone layout",
+            host_dtb
+        ),
+    }
+
     // fill zero for memory regions and interrupts
 
     let mut memory_regions = [HvConfigMemoryRegion {
@@ -46,7 +113,7 @@ pub fn platform_root_zone_config() -> HvZoneConfig {
         size: 0,
     }; CONFIG_MAX_MEMORY_REGIONS];
 
-    memory_regions[..ROOT_ZONE_MEMORY_REGIONS.len()].copy_from_slice(&ROOT_ZONE_MEMORY_REGIONS);
+    memory_regions[..regions.len()].copy_from_slice(&regions);
 
     let mut ivc_configs = [HvIvcConfig::default(); CONFIG_MAX_IVC_CONGIGS];
     let mut num_ivc_configs = 0;
@@ -75,7 +142,7 @@ pub fn platform_root_zone_config() -> HvZoneConfig {
     HvZoneConfig::new(
         0,
         ROOT_ZONE_CPUS,
-        ROOT_ZONE_MEMORY_REGIONS.len() as u32,
+        regions.len() as u32,
         memory_regions,
         ROOT_ZONE_IRQS.len() as u32,
         interrupts,
@@ -87,7 +154,7 @@ pub fn platform_root_zone_config() -> HvZoneConfig {
         ROOT_ZONE_DTB_ADDR,
         INVALID_ADDRESS as _,
         name,
-        ROOT_ARCH_ZONE_CONFIG,
+        arch_config,
         root_pci_cfg,
         num_pci_devs,
         pci_devs,
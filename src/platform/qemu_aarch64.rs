@@ -7,6 +7,9 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1);
 
 pub const ROOT_ZONE_NAME: &str = "root-linux";
 
+/// Host RAM hvisor allocates guest RAM from, as (start, size).
+pub const GUEST_MEM_POOL: &[(usize, usize)] = &[(0x90000000, 0x10000000)];
+
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 3] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
@@ -32,6 +35,9 @@ pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 3] = [
 // 65 -> ivc
 pub const ROOT_ZONE_IRQS: [u32; 9] = [33, 64, 77, 79, 35, 36, 37, 38, 65];
 
+/// Virtual irq raised in the root zone for zone events, 65 is the ivc one.
+pub const IRQ_ZONE_EVENT: usize = 32 + 0x22;
+
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     gicd_base: 0x8000000,
     gicd_size: 0x10000,
@@ -17,6 +17,9 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1) | (1 << 2);
 
 pub const ROOT_ZONE_NAME: &str = "root-linux";
 
+/// Host RAM hvisor allocates guest RAM from, as (start, size).
+pub const GUEST_MEM_POOL: &[(usize, usize)] = &[];
+
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 9] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
@@ -76,6 +79,9 @@ pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 9] = [
 
 pub const ROOT_ZONE_IRQS: [u32; 11] = [1, 2, 3, 4, 5, 8, 10, 33, 34, 35, 36]; // ARCH= riscv .It doesn't matter temporarily.
 
+/// Virtual irq raised in the root zone for zone events.
+pub const IRQ_ZONE_EVENT: usize = 32 + 0x21;
+
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     plic_base: 0xc000000,
     plic_size: 0x4000000,
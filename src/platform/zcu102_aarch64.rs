@@ -8,6 +8,9 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1);
 
 pub const ROOT_ZONE_NAME: &str = "root-linux";
 
+/// Host RAM hvisor allocates guest RAM from, as (start, size).
+pub const GUEST_MEM_POOL: &[(usize, usize)] = &[];
+
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 5] = [
     // HvConfigMemoryRegion {
     //     mem_type: MEM_TYPE_RAM,
@@ -49,6 +52,9 @@ pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 5] = [
 
 pub const ROOT_ZONE_IRQS: [u32; 8] = [53, 81, 67, 175, 176, 177, 178, 64];
 
+/// Virtual irq raised in the root zone for zone events.
+pub const IRQ_ZONE_EVENT: usize = 32 + 0x21;
+
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     gicd_base: 0xf9010000,
     gicd_size: 0x10000,
@@ -0,0 +1,419 @@
+//! Time-sliced vcpu scheduler, lets several zones share a physical cpu.
+//!
+//! A physical cpu runs one vcpu at a time, whose state is kept in the cpu's
+//! `PerCpu` as usual. The other vcpus placed on the cpu wait in its run
+//! queue with a saved copy of that state, their guest context and the
+//! events sent to them meanwhile. On every tick of the hypervisor timer the
+//! policy picks the next vcpu, which is swapped in right before returning
+//! to the guest.
+//!
+//! The root zone never shares its cpus: it spins in hypercalls until the
+//! vcpus of other zones react, so they must never wait behind it.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use numeric_enum_macro::numeric_enum;
+use spin::{Mutex, MutexGuard, Once, RwLock};
+
+use crate::arch::cpu::{hyp_timer_arm, this_cpu_id, GeneralRegisters};
+use crate::arch::vcpu::VcpuContext;
+use crate::device::virtio_trampoline::VIRTIO_IRQS;
+use crate::event::{add_event, check_events, fetch_event, IPI_EVENT_PAUSE, IPI_EVENT_SHUTDOWN};
+use crate::percpu::{this_cpu_data, PerCpu};
+use crate::zone::Zone;
+
+#[cfg(test)]
+mod tests;
+
+numeric_enum! {
+    #[repr(u64)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum SchedPolicy {
+        /// runnable vcpus take turns
+        RoundRobin = 0,
+        /// the runnable vcpu with the highest priority runs, vcpus of equal
+        /// priority take turns
+        FixedPriority = 1,
+    }
+}
+
+pub const DEFAULT_TIME_SLICE_MS: usize = 10;
+pub const MAX_TIME_SLICE_MS: usize = 1000;
+
+struct SchedConfig {
+    policy: SchedPolicy,
+    time_slice_ms: usize,
+}
+
+static SCHED_CONFIG: RwLock<SchedConfig> = RwLock::new(SchedConfig {
+    policy: SchedPolicy::RoundRobin,
+    time_slice_ms: DEFAULT_TIME_SLICE_MS,
+});
+
+/// A vcpu waiting in a run queue.
+struct Vcpu {
+    zone_id: usize,
+    /// the vcpu's `PerCpu` state while another vcpu is running
+    data: PerCpu,
+    ctx: VcpuContext,
+    /// events sent to the vcpu while it was waiting
+    events: VecDeque<usize>,
+    /// whether `ctx` holds a guest context, a vcpu that never ran (or was
+    /// shut down while waiting) is parked when it is swapped in
+    started: bool,
+}
+
+impl Vcpu {
+    fn is_of(&self, zone_id: usize) -> bool {
+        self.data.zone.is_some() && self.zone_id == zone_id
+    }
+
+    fn runnable_priority(&self) -> Option<u8> {
+        runnable_priority(&self.data, !self.events.is_empty())
+    }
+}
+
+struct RunQueue {
+    /// vcpus placed on the cpu besides the running one
+    vcpus: Mutex<VecDeque<Box<Vcpu>>>,
+    need_resched: AtomicBool,
+}
+
+static RUN_QUEUES: Once<Vec<RunQueue>> = Once::new();
+
+fn run_queue<'a>(cpu_id: usize) -> &'a RunQueue {
+    &RUN_QUEUES.get().unwrap()[cpu_id]
+}
+
+pub fn init(max_cpus: usize) {
+    RUN_QUEUES.call_once(|| {
+        (0..max_cpus)
+            .map(|_| RunQueue {
+                vcpus: Mutex::new(VecDeque::new()),
+                need_resched: AtomicBool::new(false),
+            })
+            .collect()
+    });
+}
+
+/// Start the scheduler tick on this cpu.
+pub fn percpu_init() {
+    hyp_timer_arm(SCHED_CONFIG.read().time_slice_ms);
+}
+
+/// Change the policy and time slice, the new slice applies from each cpu's
+/// next tick.
+pub fn set_config(policy: SchedPolicy, time_slice_ms: usize) {
+    info!(
+        "vcpu scheduler: policy {:?}, time slice {}ms",
+        policy, time_slice_ms
+    );
+    let mut config = SCHED_CONFIG.write();
+    config.policy = policy;
+    config.time_slice_ms = time_slice_ms;
+}
+
+/// Place a vcpu of `zone` on `cpu_id` behind the vcpu running there.
+pub fn add_vcpu(
+    cpu_id: usize,
+    zone: Arc<RwLock<Zone>>,
+    boot_cpu: bool,
+    cpu_on_entry: usize,
+    dtb_ipa: usize,
+) {
+    let zone_id = zone.read().id;
+    let mut data = PerCpu::blank(cpu_id);
+    data.zone = Some(zone);
+    data.boot_cpu = boot_cpu;
+    data.cpu_on_entry = cpu_on_entry;
+    data.dtb_ipa = dtb_ipa;
+    info!("vcpu scheduler: zone {} waits on cpu {}", zone_id, cpu_id);
+    run_queue(cpu_id).vcpus.lock().push_back(Box::new(Vcpu {
+        zone_id,
+        data,
+        ctx: VcpuContext::default(),
+        events: VecDeque::new(),
+        started: false,
+    }));
+}
+
+/// The saved `PerCpu` of a waiting vcpu, its run queue stays locked while
+/// this is held.
+pub struct QueuedCpuData<'a> {
+    vcpus: MutexGuard<'a, VecDeque<Box<Vcpu>>>,
+    idx: usize,
+}
+
+impl Deref for QueuedCpuData<'_> {
+    type Target = PerCpu;
+
+    fn deref(&self) -> &PerCpu {
+        &self.vcpus[self.idx].data
+    }
+}
+
+impl DerefMut for QueuedCpuData<'_> {
+    fn deref_mut(&mut self) -> &mut PerCpu {
+        &mut self.vcpus[self.idx].data
+    }
+}
+
+/// The saved `PerCpu` of `zone_id`'s vcpu if it waits on `cpu_id`.
+pub fn queued_cpu_data<'a>(cpu_id: usize, zone_id: usize) -> Option<QueuedCpuData<'a>> {
+    let vcpus = run_queue(cpu_id).vcpus.lock();
+    let idx = vcpus.iter().position(|vcpu| vcpu.is_of(zone_id))?;
+    Some(QueuedCpuData { vcpus, idx })
+}
+
+/// Whether vcpus of other zones take turns on `cpu_id`.
+pub fn is_shared(cpu_id: usize) -> bool {
+    run_queue(cpu_id)
+        .vcpus
+        .lock()
+        .iter()
+        .any(|vcpu| vcpu.data.zone.is_some())
+}
+
+/// Deliver `event_id` to `zone_id`'s vcpu on `cpu_id`. Returns whether it
+/// went to the cpu's event queue, so the cpu has to be interrupted.
+pub fn queue_event(cpu_id: usize, zone_id: usize, event_id: usize) -> bool {
+    let mut vcpus = run_queue(cpu_id).vcpus.lock();
+    let vcpu = match vcpus.iter_mut().find(|vcpu| vcpu.is_of(zone_id)) {
+        Some(vcpu) => vcpu,
+        None => {
+            add_event(cpu_id, event_id);
+            return true;
+        }
+    };
+    match event_id {
+        // a waiting vcpu runs no guest code, it can be stopped right away
+        IPI_EVENT_SHUTDOWN => {
+            vcpu.data.arch_cpu.power_on = false;
+            vcpu.started = false;
+            vcpu.events.clear();
+        }
+        IPI_EVENT_PAUSE => vcpu.data.paused.store(true, Ordering::Release),
+        _ => vcpu.events.push_back(event_id),
+    }
+    false
+}
+
+/// Raise virtual irq `irq_id` for `zone_id`'s vcpu on `cpu_id` if it is
+/// waiting there, it is injected once the vcpu runs again.
+pub fn queue_irq(cpu_id: usize, zone_id: usize, irq_id: usize) -> bool {
+    let mut vcpus = run_queue(cpu_id).vcpus.lock();
+    match vcpus.iter_mut().find(|vcpu| vcpu.is_of(zone_id)) {
+        Some(vcpu) => {
+            vcpu.ctx.vgic.add_pending(irq_id, false);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Keep hardware irq `irq_id` for the waiting vcpu whose zone owns it.
+/// Returns false if it is the running zone's, or no vcpu on this cpu owns it.
+pub fn defer_irq(irq_id: usize) -> bool {
+    let cpu_data = this_cpu_data();
+    let rq = run_queue(cpu_data.id);
+    // zone locks are never taken with a run queue locked, the zone paths
+    // lock run queues while they hold their zone
+    let zones: Vec<(usize, Arc<RwLock<Zone>>)> = rq
+        .vcpus
+        .lock()
+        .iter()
+        .filter_map(|vcpu| Some((vcpu.zone_id, vcpu.data.zone.clone()?)))
+        .collect();
+    if zones.is_empty() {
+        return false;
+    }
+    let owns_irq = |zone: &RwLock<Zone>| zone.read().irq_in_zone(irq_id as _);
+    if cpu_data.zone.as_ref().map_or(false, |zone| owns_irq(zone)) {
+        return false;
+    }
+    let zone_id = match zones.iter().find(|(_, zone)| owns_irq(zone)) {
+        Some(&(zone_id, _)) => zone_id,
+        None => return false,
+    };
+    drop(zones);
+    // the vcpu may have been switched in or shut down meanwhile
+    match rq.vcpus.lock().iter_mut().find(|vcpu| vcpu.is_of(zone_id)) {
+        Some(vcpu) => {
+            vcpu.ctx.vgic.add_pending(irq_id, true);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Make this cpu pick the next vcpu before it returns to the guest.
+pub fn resched() {
+    run_queue(this_cpu_id())
+        .need_resched
+        .store(true, Ordering::Release);
+}
+
+pub fn timer_tick() {
+    hyp_timer_arm(SCHED_CONFIG.read().time_slice_ms);
+    resched();
+}
+
+/// The priority of the vcpu with state `data` if it has guest code to run.
+fn runnable_priority(data: &PerCpu, has_events: bool) -> Option<u8> {
+    if data.zone.is_none() || data.pause_req.load(Ordering::Acquire) {
+        return None;
+    }
+    // a resumed vcpu runs at least once to report it is no longer paused
+    let ready = data.arch_cpu.power_on || has_events || data.paused.load(Ordering::Acquire);
+    ready.then_some(data.sched_priority)
+}
+
+/// Index of the vcpu to run next, given the priority of each queued vcpu
+/// and of the running one, None for a vcpu that has nothing to run.
+/// Returns None to keep the running vcpu.
+fn pick_next(policy: SchedPolicy, queued: &[Option<u8>], running: Option<u8>) -> Option<usize> {
+    match policy {
+        SchedPolicy::RoundRobin => queued.iter().position(Option::is_some),
+        SchedPolicy::FixedPriority => {
+            let mut best: Option<(usize, u8)> = None;
+            for (idx, prio) in queued.iter().enumerate() {
+                if let Some(prio) = *prio {
+                    if best.map_or(true, |(_, best_prio)| prio > best_prio) {
+                        best = Some((idx, prio));
+                    }
+                }
+            }
+            let (idx, prio) = best?;
+            // the running vcpu only yields to an equal or higher priority
+            match running {
+                Some(running_prio) if running_prio > prio => None,
+                _ => Some(idx),
+            }
+        }
+    }
+}
+
+/// Called on this cpu's exit path right before returning to the guest in
+/// `regs`, switches to another vcpu if the tick or a pause asked for it.
+pub fn schedule(regs: &mut GeneralRegisters) {
+    let rq = run_queue(this_cpu_id());
+    while rq.need_resched.swap(false, Ordering::AcqRel) {
+        let cpu_data = this_cpu_data();
+        // read before the run queue is locked, see `defer_irq`
+        let running_zone_id = cpu_data.zone.as_ref().map(|zone| zone.read().id);
+        let lock = cpu_data.ctrl_lock.lock();
+        let mut vcpus = rq.vcpus.lock();
+        // drop the vcpus of zones shut down while they were waiting
+        vcpus.retain(|vcpu| vcpu.data.zone.is_some());
+        let queued: Vec<Option<u8>> = vcpus.iter().map(|vcpu| vcpu.runnable_priority()).collect();
+        let running = runnable_priority(cpu_data, false);
+        let switched_from = match pick_next(SCHED_CONFIG.read().policy, &queued, running) {
+            Some(idx) => {
+                let next = vcpus.remove(idx).unwrap();
+                let prev = switch_to(cpu_data, regs, next, running_zone_id);
+                let prev_zone_id = prev.as_ref().map(|prev| prev.zone_id);
+                if let Some(prev) = prev {
+                    vcpus.push_back(prev);
+                }
+                Some(prev_zone_id)
+            }
+            None if cpu_data.pause_req.load(Ordering::Acquire) => {
+                // the running vcpu is paused and no other one can run
+                drop(vcpus);
+                drop(lock);
+                wait_for_runnable(cpu_data, rq);
+                rq.need_resched.store(true, Ordering::Release);
+                continue;
+            }
+            None => None,
+        };
+        drop(vcpus);
+        drop(lock);
+        if !cpu_data.pause_req.load(Ordering::Acquire) {
+            cpu_data.paused.store(false, Ordering::Release);
+        }
+        if let Some(prev_zone_id) = switched_from {
+            stash_virtio_irqs(cpu_data.id, prev_zone_id);
+            // handle what was sent to the new vcpu while it was waiting
+            while check_events() {}
+        }
+    }
+}
+
+fn wait_for_runnable(cpu_data: &PerCpu, rq: &RunQueue) {
+    while cpu_data.pause_req.load(Ordering::Acquire)
+        && !rq
+            .vcpus
+            .lock()
+            .iter()
+            .any(|vcpu| vcpu.runnable_priority().is_some())
+    {
+        core::hint::spin_loop();
+    }
+}
+
+/// Swap `next` in as the vcpu running on this cpu and return the vcpu it
+/// replaced, unless that one has no zone any more. `running_zone_id` is the
+/// id of the running vcpu's zone.
+fn switch_to(
+    cpu_data: &mut PerCpu,
+    regs: &mut GeneralRegisters,
+    mut next: Box<Vcpu>,
+    running_zone_id: Option<usize>,
+) -> Option<Box<Vcpu>> {
+    let cpu_id = cpu_data.id;
+    let prev_zone_id = cpu_data.zone.as_ref().and(running_zone_id);
+    debug!(
+        "vcpu scheduler: cpu {} switch from zone {:?} to zone {}",
+        cpu_id, prev_zone_id, next.zone_id
+    );
+
+    let mut prev_ctx = VcpuContext::default();
+    prev_ctx.save(regs);
+    let mut prev_events = VecDeque::new();
+    while let Some(event) = fetch_event(cpu_id) {
+        prev_events.push_back(event);
+    }
+    while let Some(event) = next.events.pop_front() {
+        add_event(cpu_id, event);
+    }
+
+    cpu_data.swap_vcpu(&mut next.data);
+    if next.started {
+        next.ctx.restore(regs);
+    } else {
+        cpu_data.arch_cpu.park();
+    }
+
+    // `next` now carries the previous vcpu
+    let prev_zone_id = prev_zone_id?;
+    next.zone_id = prev_zone_id;
+    next.ctx = prev_ctx;
+    next.events = prev_events;
+    next.started = true;
+    Some(next)
+}
+
+/// Move the virtio irqs raised for the vcpu that was just switched out to
+/// its saved context (or drop them if its zone is gone), `VIRTIO_IRQS` only
+/// holds those of the running vcpu.
+fn stash_virtio_irqs(cpu_id: usize, prev_zone_id: Option<usize>) {
+    let mut map = VIRTIO_IRQS.lock();
+    let irq_list = match map.get_mut(&cpu_id) {
+        Some(irq_list) if irq_list[0] != 0 => irq_list,
+        _ => return,
+    };
+    if let Some(zone_id) = prev_zone_id {
+        let mut vcpus = run_queue(cpu_id).vcpus.lock();
+        if let Some(vcpu) = vcpus.iter_mut().find(|vcpu| vcpu.is_of(zone_id)) {
+            for irq_id in irq_list[1..=irq_list[0] as usize].iter() {
+                vcpu.ctx.vgic.add_pending(*irq_id as _, false);
+            }
+        }
+    }
+    irq_list[0] = 0;
+}
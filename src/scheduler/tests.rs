@@ -0,0 +1,64 @@
+use super::*;
+
+#[test_case]
+fn test_round_robin_pick() {
+    let policy = SchedPolicy::RoundRobin;
+    assert_eq!(pick_next(policy, &[Some(0), Some(0)], Some(0)), Some(0));
+    assert_eq!(pick_next(policy, &[None, Some(0)], Some(0)), Some(1));
+    assert_eq!(pick_next(policy, &[None, None], None), None);
+    // priorities don't matter
+    assert_eq!(pick_next(policy, &[Some(0), Some(9)], Some(9)), Some(0));
+}
+
+#[test_case]
+fn test_fixed_priority_pick() {
+    let policy = SchedPolicy::FixedPriority;
+    assert_eq!(
+        pick_next(policy, &[Some(1), Some(3), Some(3)], None),
+        Some(1)
+    );
+    assert_eq!(pick_next(policy, &[Some(1), Some(3)], Some(3)), Some(1));
+    assert_eq!(pick_next(policy, &[Some(1), Some(3)], Some(4)), None);
+    assert_eq!(pick_next(policy, &[None, Some(1)], Some(4)), None);
+    assert_eq!(pick_next(policy, &[None, None], Some(0)), None);
+}
+
+#[test_case]
+fn test_reboot_with_queued_vcpu() {
+    use crate::arch::cpu::time_ms;
+    use crate::config::CONFIG_NAME_MAXLEN;
+    use crate::consts::{INVALID_ADDRESS, MAX_CPU_NUM};
+    use crate::zone::zone_reboot;
+
+    // keep the other cpus from swapping the vcpu in while the test runs
+    let (policy, time_slice_ms) = {
+        let config = SCHED_CONFIG.read();
+        (config.policy, config.time_slice_ms)
+    };
+    set_config(policy, MAX_TIME_SLICE_MS);
+    let start = time_ms();
+    while time_ms() < start + 2 * time_slice_ms as u64 {
+        core::hint::spin_loop();
+    }
+
+    let (zone_id, cpu_id) = (46, (this_cpu_id() + 1) % MAX_CPU_NUM);
+    let mut zone = Zone::new(zone_id, &[0; CONFIG_NAME_MAXLEN]);
+    zone.cpu_set.set_bit(cpu_id);
+    zone.vcpus = vec![Some(cpu_id)];
+    zone.boot.entry_point = 0x4000_0000;
+    let zone = Arc::new(RwLock::new(zone));
+    add_vcpu(cpu_id, zone.clone(), true, INVALID_ADDRESS, 0);
+    queued_cpu_data(cpu_id, zone_id).unwrap().arch_cpu.power_on = true;
+
+    zone_reboot(zone.clone(), false).unwrap();
+    {
+        let mut data = queued_cpu_data(cpu_id, zone_id).unwrap();
+        // never let it run, it is dropped on the cpu's next tick
+        data.pause_req.store(true, Ordering::Release);
+        assert!(!data.arch_cpu.power_on);
+        assert_eq!(data.cpu_on_entry, 0x4000_0000);
+        data.zone = None;
+    }
+    assert!(!is_shared(cpu_id));
+    set_config(policy, time_slice_ms);
+}
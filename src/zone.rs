@@ -1,32 +1,82 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 // use psci::error::INVALID_ADDRESS;
 use crate::consts::INVALID_ADDRESS;
 use crate::pci::pci::PciRoot;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
+use crate::arch::cpu::{this_cpu_id, time_ms};
 use crate::arch::mm::new_s2_memory_set;
-use crate::arch::s2pt::Stage2PageTable;
-use crate::config::{HvZoneConfig, CONFIG_NAME_MAXLEN};
-use crate::consts::MAX_CPU_NUM;
+use crate::arch::s2pt::{Stage2PageTable, MAX_VMID_NUM};
+use crate::balloon::{balloon_zone_deflate, balloon_zone_remove, is_ballooned};
+use crate::config::{
+    HvConfigMemoryRegion, ZoneConfig, CONFIG_MAX_INTERRUPTS, CONFIG_MAX_IVC_CONGIGS,
+    CONFIG_MAX_MEMORY_REGIONS, CONFIG_MAX_PCI_DEV, CONFIG_NAME_MAXLEN, MEM_PHYS_AUTO, MEM_TYPE_IO,
+    MEM_TYPE_RAM, MEM_TYPE_VIRTIO, ZONE_ID_AUTO,
+};
+use crate::consts::{hv_phys_range, MAX_CPU_NUM, MAX_ZONE_NUM, PAGE_SIZE};
+use crate::device::irqchip::irqchip_zone_remove;
+use crate::device::virtio_trampoline::{virtio_zone_remove, VIRTIO_IRQS};
+use crate::device::watchdog::{watchdog_remove, watchdog_reset};
 
 use crate::error::HvResult;
-use crate::memory::addr::GuestPhysAddr;
-use crate::memory::{MMIOConfig, MMIOHandler, MMIORegion, MemorySet};
-use crate::percpu::{get_cpu_data, this_zone, CpuSet};
+use crate::event::{send_zone_event, IPI_EVENT_PAUSE, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP};
+use crate::hypercall::SGI_IPI_ID;
+use crate::memory::addr::{is_aligned, phys_to_virt, GuestPhysAddr};
+use crate::memory::mm::PARKING_VMID;
+use crate::memory::pool::{pool_overlaps, GuestRam};
+use crate::memory::{MMIOConfig, MMIOHandler, MMIORegion, MemFlags, MemoryRegion, MemorySet};
+use crate::notify::{notify_root, IRQ_ZONE_EVENT, ZONE_EVENT_CRASHED, ZONE_EVENT_STARTED};
+use crate::percpu::{get_cpu_data, this_cpu_data, this_zone, zone_cpu_data, CpuSet};
 use core::panic;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 #[cfg(test)]
 pub mod tests;
 
+/// What is needed to boot the zone again without its config.
+#[derive(Debug, Clone, Default)]
+pub struct ZoneBootInfo {
+    pub entry_point: usize,
+    pub dtb_ipa: usize,
+    /// guest RAM regions as (host physical start, size)
+    pub ram: Vec<(usize, usize)>,
+    /// images preloaded by the root zone (kernel, dtb) as (host physical start, size)
+    pub images: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneState {
+    Running,
+    Paused,
+    ShuttingDown,
+}
+
 pub struct Zone {
     pub name: [u8; CONFIG_NAME_MAXLEN],
     pub id: usize,
+    /// hardware VMID tagging the zone's stage-2 translations, independent of `id`
+    pub vmid: usize,
     pub mmio: Vec<MMIOConfig>,
     pub cpu_set: CpuSet,
+    /// physical cpu of each vcpu id, a hot-unplugged vcpu leaves a hole
+    pub vcpus: Vec<Option<usize>>,
     pub irq_bitmap: [u32; 1024 / 32],
     pub gpm: MemorySet<Stage2PageTable>,
     pub pciroot: PciRoot,
+    pub state: ZoneState,
+    pub boot: ZoneBootInfo,
+    /// memory regions and irqs from the zone's config, reported in its status
+    pub mem_regions: Vec<HvConfigMemoryRegion>,
+    pub irqs: Vec<u32>,
+    /// when the zone was last (re)started
+    pub start_time_ms: u64,
+    /// `ZONE_CRASH_*` reason once a guest fault stopped the zone, otherwise 0
+    pub fault_reason: AtomicU64,
+    /// RAM hvisor allocated for the zone, back to the pool with the zone
+    pub guest_ram: Vec<GuestRam>,
 }
 
 impl Zone {
@@ -34,34 +84,120 @@ impl Zone {
         Self {
             name: name.try_into().unwrap(),
             id: zoneid,
+            vmid: INVALID_ADDRESS,
             gpm: new_s2_memory_set(),
             cpu_set: CpuSet::new(MAX_CPU_NUM as usize, 0),
+            vcpus: Vec::new(),
             mmio: Vec::new(),
             irq_bitmap: [0; 1024 / 32],
             pciroot: PciRoot::new(),
+            state: ZoneState::Running,
+            boot: ZoneBootInfo::default(),
+            mem_regions: Vec::new(),
+            irqs: Vec::new(),
+            start_time_ms: time_ms(),
+            fault_reason: AtomicU64::new(0),
+            guest_ram: Vec::new(),
         }
     }
 
-    // pub fn suspend(&self) {
-    //     trace!("suspending cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to suspend cpu_id = {:#x?}", cpu_id);
-    //         suspend_cpu(cpu_id);
-    //     });
-    //     info!("send sgi done!");
-    // }
+    pub fn owns_cpu(&self, id: usize) -> bool {
+        self.cpu_set.contains_cpu(id)
+    }
+
+    /// Give physical cpu `cpu` to this zone as the lowest free vcpu id, so a
+    /// hot-plugged cpu fills the hole of an earlier unplugged one.
+    pub fn add_cpu(&mut self, cpu: usize) -> usize {
+        self.cpu_set.set_bit(cpu);
+        match self.vcpus.iter().position(|slot| slot.is_none()) {
+            Some(vcpu) => {
+                self.vcpus[vcpu] = Some(cpu);
+                vcpu
+            }
+            None => {
+                self.vcpus.push(Some(cpu));
+                self.vcpus.len() - 1
+            }
+        }
+    }
+
+    /// Take physical cpu `cpu` away from this zone, the other vcpus keep
+    /// their ids.
+    pub fn remove_cpu(&mut self, cpu: usize) {
+        self.cpu_set.clear_bit(cpu);
+        if let Some(vcpu) = self.cpu_to_vcpu(cpu) {
+            self.vcpus[vcpu] = None;
+        }
+    }
+
+    /// The vcpu id the guest sees for physical cpu `cpu`, vcpu 0 is the boot
+    /// cpu.
+    pub fn cpu_to_vcpu(&self, cpu: usize) -> Option<usize> {
+        self.vcpus.iter().position(|&slot| slot == Some(cpu))
+    }
+
+    /// The physical cpu backing vcpu `vcpu` of this zone.
+    pub fn vcpu_to_cpu(&self, vcpu: usize) -> Option<usize> {
+        self.vcpus.get(vcpu).copied().flatten()
+    }
+
+    /// The physical cpu the zone boots on.
+    pub fn boot_cpu(&self) -> usize {
+        self.vcpu_to_cpu(0).unwrap()
+    }
 
-    // pub fn resume(&self) {
-    //     trace!("resuming cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to resume cpu_id = {:#x?}", cpu_id);
-    //         resume_cpu(cpu_id);
-    //     });
-    // }
+    /// Zero the zone's RAM, leaving the pages holding the kernel and dtb intact.
+    pub fn zero_ram(&self) {
+        for &(start, size) in self.boot.ram.iter() {
+            for paddr in (start..start + size).step_by(PAGE_SIZE) {
+                if self
+                    .boot
+                    .images
+                    .iter()
+                    .any(|&(img, img_size)| paddr < img + img_size && img < paddr + PAGE_SIZE)
+                    || is_ballooned(self.id, paddr)
+                {
+                    continue;
+                }
+                let vaddr = phys_to_virt(paddr);
+                #[cfg(target_arch = "loongarch64")]
+                let vaddr = vaddr | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize;
+                unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE) };
+            }
+        }
+    }
 
-    // pub fn owns_cpu(&self, id: usize) -> bool {
-    //     self.cpu_set.contains_cpu(id)
-    // }
+    /// Allocate the `MEM_PHYS_AUTO` RAM of `config` from the guest memory pool
+    /// and copy the images the root zone staged into it. Returns `config` with
+    /// the allocated addresses filled in.
+    fn guest_ram_alloc(&mut self, config: &ZoneConfig) -> HvResult<ZoneConfig> {
+        let mut config = config.clone();
+        let mut staged = false;
+        for region in config.memory_regions_mut() {
+            if region.mem_type != MEM_TYPE_RAM || region.physical_start != MEM_PHYS_AUTO {
+                continue;
+            }
+            let ram = GuestRam::new(region.size as _)?;
+            region.physical_start = ram.start_paddr() as _;
+            self.guest_ram.push(ram);
+            staged = true;
+        }
+        if staged {
+            config.kernel_load_paddr = load_staged_image(
+                &config,
+                config.kernel_load_paddr,
+                config.kernel_load_ipa,
+                config.kernel_size,
+            )?;
+            config.dtb_load_paddr = load_staged_image(
+                &config,
+                config.dtb_load_paddr,
+                config.dtb_load_ipa,
+                config.dtb_size,
+            )?;
+        }
+        Ok(config)
+    }
 
     /// Register a mmio region and its handler.
     pub fn mmio_region_register(
@@ -119,6 +255,63 @@ impl Zone {
 
 static ZONE_LIST: RwLock<Vec<Arc<RwLock<Zone>>>> = RwLock::new(vec![]);
 
+/// Hands out the lowest free id below `limit`.
+pub struct IdAllocator {
+    used: BTreeSet<usize>,
+    limit: usize,
+}
+
+impl IdAllocator {
+    pub const fn new(limit: usize) -> Self {
+        Self {
+            used: BTreeSet::new(),
+            limit,
+        }
+    }
+
+    /// Take the id chosen by the caller.
+    pub fn reserve(&mut self, id: usize) -> HvResult {
+        if id >= self.limit {
+            return hv_result_err!(EINVAL, format!("id {} out of range", id));
+        }
+        if !self.used.insert(id) {
+            return hv_result_err!(EEXIST, format!("id {} in use", id));
+        }
+        Ok(())
+    }
+
+    pub fn alloc(&mut self) -> HvResult<usize> {
+        let id = (0..self.limit)
+            .find(|id| !self.used.contains(id))
+            .ok_or_else(|| hv_err!(ENOMEM, "no free id"))?;
+        self.used.insert(id);
+        Ok(id)
+    }
+
+    pub fn free(&mut self, id: usize) {
+        self.used.remove(&id);
+    }
+}
+
+/// Zone ids, bounded by the per-zone tables of the irqchip and the SMMU.
+static ZONE_IDS: Mutex<IdAllocator> = Mutex::new(IdAllocator::new(MAX_ZONE_NUM));
+/// Hardware VMIDs of the zones' stage-2 page tables.
+static VMIDS: Mutex<IdAllocator> = Mutex::new(IdAllocator::new(MAX_VMID_NUM));
+/// Held from the check that a host range belongs to no other zone until the
+/// range is recorded as the zone's, so it can't be given to two zones.
+static RAM_OWNER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Size the VMIDs to the hardware and keep the parking set's VMID from the
+/// zones, before the root zone is created.
+pub fn vmid_init() {
+    let mut vmids = VMIDS.lock();
+    #[cfg(target_arch = "riscv64")]
+    {
+        *vmids = IdAllocator::new(crate::arch::s2pt::vmid_probe());
+    }
+    vmids.reserve(PARKING_VMID).unwrap();
+}
+
 pub fn root_zone() -> Arc<RwLock<Zone>> {
     ZONE_LIST.read().get(0).cloned().unwrap()
 }
@@ -134,14 +327,32 @@ pub fn add_zone(zone: Arc<RwLock<Zone>>) {
 
 /// Remove zone from ZONE_LIST
 pub fn remove_zone(zone_id: usize) {
-    let mut zone_list = ZONE_LIST.write();
-    let (idx, _) = zone_list
-        .iter()
-        .enumerate()
-        .find(|(_, zone)| zone.read().id == zone_id)
-        .unwrap();
-    let removed_zone = zone_list.remove(idx);
+    let removed_zone = {
+        let mut zone_list = ZONE_LIST.write();
+        let (idx, _) = zone_list
+            .iter()
+            .enumerate()
+            .find(|(_, zone)| zone.read().id == zone_id)
+            .unwrap();
+        zone_list.remove(idx)
+    };
     assert_eq!(Arc::strong_count(&removed_zone), 1);
+
+    // release what the zone holds outside itself, the rest is freed with it
+    let mut zone = removed_zone.write();
+    watchdog_remove(zone_id);
+    #[cfg(target_arch = "aarch64")]
+    crate::ivc::ivc_zone_remove(zone_id);
+    zone.pci_remove();
+    irqchip_zone_remove(zone_id);
+    virtio_zone_remove(&zone.cpu_set);
+    balloon_zone_remove(&mut zone);
+    if zone.vmid != INVALID_ADDRESS {
+        // the next zone with this vmid must not hit stale translations
+        zone.gpm.flush(None);
+        VMIDS.lock().free(zone.vmid);
+    }
+    ZONE_IDS.lock().free(zone_id);
 }
 
 pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
@@ -172,30 +383,340 @@ pub fn this_zone_id() -> usize {
     this_zone().read().id
 }
 
-pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
-    // we create the new zone here
-    // TODO: create Zone with cpu_set
-    let zone_id = config.zone_id as usize;
+/// Undo log of a zone creation. Each step that changes state outside the
+/// new `Zone` records how to revert it. Dropping the log without `commit`
+/// runs the undo actions newest first and leaves the hypervisor as it was.
+#[derive(Default)]
+pub struct ZoneRollback {
+    undo: Vec<Box<dyn FnOnce()>>,
+}
 
-    if find_zone(zone_id).is_some() {
-        return hv_result_err!(EEXIST);
+impl ZoneRollback {
+    pub fn new() -> Self {
+        Self::default()
     }
 
+    pub fn push(&mut self, undo: impl FnOnce() + 'static) {
+        self.undo.push(Box::new(undo));
+    }
+
+    /// Keep every change made so far.
+    pub fn commit(mut self) {
+        self.undo.clear();
+    }
+}
+
+impl Drop for ZoneRollback {
+    fn drop(&mut self) {
+        while let Some(undo) = self.undo.pop() {
+            undo();
+        }
+    }
+}
+
+fn ranges_overlap(a: u64, a_size: u64, b: u64, b_size: u64) -> bool {
+    a < b + b_size && b < a + a_size
+}
+
+/// Refuse a config that is malformed or claims what the hypervisor or
+/// another zone owns, before anything is set up for the new zone.
+pub fn zone_config_check(config: &ZoneConfig) -> HvResult {
+    let zones = ZONE_LIST.read();
+
+    let cpus = config.cpus();
+    if cpus.is_empty() {
+        return hv_result_err!(EINVAL, "zone has no cpus");
+    }
+    for &cpu in cpus.iter() {
+        if cpu as usize >= MAX_CPU_NUM {
+            return hv_result_err!(EINVAL, format!("cpu {} doesn't exist", cpu));
+        }
+        for zone in zones.iter() {
+            let zone = zone.read();
+            if !zone.owns_cpu(cpu as _) {
+                continue;
+            }
+            // other zones take turns on a cpu, but the root zone's vcpus
+            // must never wait behind another zone
+            #[cfg(feature = "vcpu_sched")]
+            if zone.id != 0 {
+                continue;
+            }
+            return hv_result_err!(EBUSY, format!("cpu {} belongs to zone {}", cpu, zone.id));
+        }
+    }
+
+    let hv_mem = hv_phys_range();
+    let regions = config.memory_regions();
+    for (i, region) in regions.iter().enumerate() {
+        let (start, ipa, size) = (region.physical_start, region.virtual_start, region.size);
+        if ![MEM_TYPE_RAM, MEM_TYPE_IO, MEM_TYPE_VIRTIO].contains(&region.mem_type) {
+            return hv_result_err!(
+                EINVAL,
+                format!("unsupported memory type {}", region.mem_type)
+            );
+        }
+        // hvisor picks where RAM from the pool goes
+        let auto = region.mem_type == MEM_TYPE_RAM && start == MEM_PHYS_AUTO;
+        if size == 0
+            || (!auto && start.checked_add(size).is_none())
+            || ipa.checked_add(size).is_none()
+        {
+            return hv_result_err!(EINVAL, format!("bad memory region {:#x?}", region));
+        }
+        if regions[..i]
+            .iter()
+            .any(|other| ranges_overlap(ipa, size, other.virtual_start, other.size))
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("memory region {:#x?} overlaps another one", region)
+            );
+        }
+        // virtio regions are trapped, not mapped
+        if region.mem_type == MEM_TYPE_VIRTIO {
+            continue;
+        }
+        if (!auto && !is_aligned(start as _)) || !is_aligned(ipa as _) || !is_aligned(size as _) {
+            return hv_result_err!(
+                EINVAL,
+                format!("memory region {:#x?} isn't page aligned", region)
+            );
+        }
+        if auto {
+            if config.zone_id == 0 {
+                return hv_result_err!(EINVAL, "the root zone's RAM can't come from the pool");
+            }
+            continue;
+        }
+        if ranges_overlap(
+            start,
+            size,
+            hv_mem.start as _,
+            (hv_mem.end - hv_mem.start) as _,
+        ) {
+            return hv_result_err!(
+                EPERM,
+                format!("memory region {:#x?} overlaps the hypervisor", region)
+            );
+        }
+        if region.mem_type != MEM_TYPE_RAM {
+            continue;
+        }
+        if pool_overlaps(start as _, size as _) {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "memory region {:#x?} belongs to the guest memory pool",
+                    region
+                )
+            );
+        }
+        // the root zone's RAM is where the other zones' RAM is taken from
+        if let Some(owner) =
+            zones.iter().map(|zone| zone.read()).find(|zone| {
+                zone.id != 0
+                    && zone.boot.ram.iter().any(|&(ram, ram_size)| {
+                        ranges_overlap(start, size, ram as _, ram_size as _)
+                    })
+            })
+        {
+            return hv_result_err!(
+                EBUSY,
+                format!("memory region {:#x?} belongs to zone {}", region, owner.id)
+            );
+        }
+    }
+
+    let ram = || {
+        regions
+            .iter()
+            .filter(|region| region.mem_type == MEM_TYPE_RAM)
+    };
+    // with RAM from the pool the root zone stages the images in its own RAM,
+    // they are copied to their guest physical addresses
+    let staged = ram().any(|region| region.physical_start == MEM_PHYS_AUTO);
+    let in_ram = |paddr: u64, ipa: u64, size: u64| {
+        // the size of images the zone loads itself is unknown
+        let size = if size == INVALID_ADDRESS as u64 {
+            1
+        } else {
+            size
+        };
+        if staged {
+            return ram().any(|region| {
+                region.virtual_start <= ipa
+                    && ipa.saturating_add(size) <= region.virtual_start + region.size
+            });
+        }
+        ram().any(|region| {
+            region.physical_start <= paddr
+                && paddr.saturating_add(size) <= region.physical_start + region.size
+        })
+    };
+    let entry = config.entry_point;
+    #[cfg(target_arch = "loongarch64")]
+    let entry = entry & !crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX;
+    if !ram()
+        .any(|region| region.virtual_start <= entry && entry < region.virtual_start + region.size)
+    {
+        return hv_result_err!(
+            EINVAL,
+            format!("entry point {:#x} is outside the zone's RAM", entry)
+        );
+    }
+    if config.kernel_size != INVALID_ADDRESS as u64
+        && !in_ram(
+            config.kernel_load_paddr,
+            config.kernel_load_ipa,
+            config.kernel_size,
+        )
+    {
+        return hv_result_err!(EINVAL, "kernel image is outside the zone's RAM");
+    }
+    // loongarch guests find their device tree through the firmware tables
+    #[cfg(not(target_arch = "loongarch64"))]
+    if !in_ram(config.dtb_load_paddr, config.dtb_load_ipa, config.dtb_size) {
+        return hv_result_err!(EINVAL, "dtb is outside the zone's RAM");
+    }
+    if staged {
+        for (paddr, size) in [
+            (config.kernel_load_paddr, config.kernel_size),
+            (config.dtb_load_paddr, config.dtb_size),
+        ] {
+            if size == 0 || size == INVALID_ADDRESS as u64 {
+                continue;
+            }
+            if paddr.checked_add(size).is_none() {
+                return hv_result_err!(EINVAL, format!("bad staged image at {:#x}", paddr));
+            }
+            if ranges_overlap(
+                paddr,
+                size,
+                hv_mem.start as _,
+                (hv_mem.end - hv_mem.start) as _,
+            ) {
+                return hv_result_err!(
+                    EPERM,
+                    format!("staged image at {:#x} overlaps the hypervisor", paddr)
+                );
+            }
+            // nothing of another zone may be copied into this one
+            if pool_overlaps(paddr as _, size as _)
+                || zones.iter().map(|zone| zone.read()).any(|zone| {
+                    zone.id != 0
+                        && zone.boot.ram.iter().any(|&(ram, ram_size)| {
+                            ranges_overlap(paddr, size, ram as _, ram_size as _)
+                        })
+                })
+            {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("staged image at {:#x} is in another zone's RAM", paddr)
+                );
+            }
+        }
+    }
+
+    for &irq in config.interrupts() {
+        if irq >= 1024 {
+            return hv_result_err!(EINVAL, format!("irq {} doesn't exist", irq));
+        }
+        if config.zone_id == 0 && irq as usize == IRQ_ZONE_EVENT {
+            return hv_result_err!(
+                EINVAL,
+                format!("irq {} is the root zone's zone event irq", irq)
+            );
+        }
+        // SGIs and PPIs are banked per cpu, every zone has its own
+        #[cfg(target_arch = "aarch64")]
+        if irq < 32 {
+            continue;
+        }
+        if let Some(owner) = zones
+            .iter()
+            .map(|zone| zone.read())
+            .find(|zone| zone.irq_in_zone(irq))
+        {
+            return hv_result_err!(EBUSY, format!("irq {} belongs to zone {}", irq, owner.id));
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    crate::ivc::ivc_config_check(config.ivc_config())?;
+    Ok(())
+}
+
+/// Copy an image staged at `paddr` to `ipa` in the zone's RAM and return
+/// where it landed.
+fn load_staged_image(config: &ZoneConfig, paddr: u64, ipa: u64, size: u64) -> HvResult<u64> {
+    if size == 0 {
+        return Ok(paddr);
+    }
+    let hpa = match config.memory_regions().iter().find(|region| {
+        region.mem_type == MEM_TYPE_RAM
+            && region.virtual_start <= ipa
+            && ipa < region.virtual_start + region.size
+    }) {
+        Some(region) => region.physical_start + (ipa - region.virtual_start),
+        None => {
+            return hv_result_err!(
+                EINVAL,
+                format!("image at {:#x} is outside the zone's RAM", ipa)
+            )
+        }
+    };
+    // the zone loads an image of unknown size itself
+    if size != INVALID_ADDRESS as u64 {
+        let src = phys_to_virt(paddr as _);
+        let dst = phys_to_virt(hpa as _);
+        #[cfg(target_arch = "loongarch64")]
+        let (src, dst) = (
+            src | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize,
+            dst | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize,
+        );
+        unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, size as _) };
+    }
+    Ok(hpa)
+}
+
+/// Create the zone described by `config`, with the lowest free zone id if
+/// `config.zone_id` is `ZONE_ID_AUTO`. Side effects outside the zone are
+/// recorded in `rollback`, the caller commits it once the zone is started.
+pub fn zone_create(
+    config: &ZoneConfig,
+    rollback: &mut ZoneRollback,
+) -> HvResult<Arc<RwLock<Zone>>> {
+    let zone_id = if config.zone_id == ZONE_ID_AUTO {
+        ZONE_IDS.lock().alloc()?
+    } else {
+        let zone_id = config.zone_id as usize;
+        ZONE_IDS.lock().reserve(zone_id)?;
+        zone_id
+    };
+    rollback.push(move || ZONE_IDS.lock().free(zone_id));
+    // until the zone and its RAM are in the zone list
+    let _ram_lock = RAM_OWNER_LOCK.lock();
+    zone_config_check(config)?;
+
     let mut zone = Zone::new(zone_id, &config.name);
-    zone.pt_init(config.memory_regions()).unwrap();
+    zone.vmid = VMIDS.lock().alloc()?;
+    zone.gpm.set_vmid(zone.vmid);
+    let vmid = zone.vmid;
+    rollback.push(move || VMIDS.lock().free(vmid));
+    // from here on the config has the RAM hvisor allocated, freed with the zone
+    let config = &zone.guest_ram_alloc(config)?;
+    zone.pt_init(config.memory_regions())?;
     zone.mmio_init(&config.arch_config);
     zone.irq_bitmap_init(config.interrupts());
     #[cfg(target_arch = "aarch64")]
-    zone.ivc_init(config.ivc_config());
+    zone.ivc_init(config.ivc_config(), rollback)?;
     #[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
-    zone.pci_init(
-        &config.pci_config,
-        config.num_pci_devs as _,
-        &config.alloc_pci_devs,
-    );
+    zone.pci_init(&config.pci_config, config.alloc_pci_devs(), rollback);
 
+    // vcpu ids follow the physical cpu order
     config.cpus().iter().for_each(|cpu_id| {
-        zone.cpu_set.set_bit(*cpu_id as _);
+        zone.add_cpu(*cpu_id as _);
     });
 
     let mut dtb_ipa = INVALID_ADDRESS as u64;
@@ -207,27 +728,595 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
             dtb_ipa = region.virtual_start + config.dtb_load_paddr - region.physical_start;
         }
     }
+    zone.boot = ZoneBootInfo {
+        entry_point: config.entry_point as _,
+        dtb_ipa: dtb_ipa as _,
+        ram: config
+            .memory_regions()
+            .iter()
+            .filter(|region| region.mem_type == MEM_TYPE_RAM)
+            .map(|region| (region.physical_start as _, region.size as _))
+            .collect(),
+        images: vec![
+            (config.kernel_load_paddr as _, config.kernel_size as _),
+            (config.dtb_load_paddr as _, config.dtb_size as _),
+        ],
+    };
+    zone.mem_regions = config.memory_regions().to_vec();
+    zone.irqs = config.interrupts().to_vec();
     info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
     let cpu_set = zone.cpu_set;
+    let boot_cpu = zone.boot_cpu();
 
     let new_zone_pointer = Arc::new(RwLock::new(zone));
     {
         cpu_set.iter().for_each(|cpuid| {
             let cpu_data = get_cpu_data(cpuid);
+            // another zone is running on this cpu, take turns with it
+            #[cfg(feature = "vcpu_sched")]
+            if cpu_data.zone.is_some() {
+                crate::scheduler::add_vcpu(
+                    cpuid,
+                    new_zone_pointer.clone(),
+                    cpuid == boot_cpu,
+                    config.entry_point as _,
+                    dtb_ipa as _,
+                );
+                return;
+            }
             cpu_data.zone = Some(new_zone_pointer.clone());
             //chose boot cpu
-            if cpuid == cpu_set.first_cpu().unwrap() {
+            if cpuid == boot_cpu {
                 cpu_data.boot_cpu = true;
             }
             cpu_data.cpu_on_entry = config.entry_point as _;
             cpu_data.dtb_ipa = dtb_ipa as _;
+            #[cfg(feature = "vcpu_sched")]
+            {
+                cpu_data.sched_priority = 0;
+            }
         });
     }
+    rollback.push(move || {
+        cpu_set.iter().for_each(|cpuid| {
+            let _lock = get_cpu_data(cpuid).ctrl_lock.lock();
+            let mut cpu_data = zone_cpu_data(cpuid, zone_id);
+            if cpu_data
+                .zone
+                .as_ref()
+                .map_or(false, |zone| zone.read().id == zone_id)
+            {
+                cpu_data.zone = None;
+                cpu_data.boot_cpu = false;
+                cpu_data.cpu_on_entry = INVALID_ADDRESS;
+            }
+        });
+    });
     add_zone(new_zone_pointer.clone());
+    rollback.push(move || {
+        ZONE_LIST.write().retain(|zone| zone.read().id != zone_id);
+    });
 
     Ok(new_zone_pointer)
 }
 
+/// Send every cpu of `zone` but `except` back to the parking state and wait
+/// until all of them are powered off.
+fn zone_stop_cpus(zone: &Zone, except: Option<usize>) {
+    let zone_id = zone.id;
+    let cpus = || {
+        zone.cpu_set
+            .iter()
+            .filter(move |&cpu_id| Some(cpu_id) != except)
+    };
+    cpus().for_each(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        zone_cpu_data(cpu_id, zone_id).cpu_on_entry = INVALID_ADDRESS;
+        send_zone_event(cpu_id, zone_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+        // a parked cpu handles the shutdown as soon as it is released
+        zone_cpu_data(cpu_id, zone_id)
+            .pause_req
+            .store(false, Ordering::Release);
+    });
+    while cpus().any(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        zone_cpu_data(cpu_id, zone_id).arch_cpu.power_on
+    }) {}
+}
+
+/// Stop all cpus of `zone` and remove it. When called on a cpu of the zone
+/// itself (a guest-initiated power-off), that cpu is left to the caller,
+/// which must park it instead of going back to the guest.
+pub fn zone_shutdown(zone: Arc<RwLock<Zone>>) {
+    let cpu_data = this_cpu_data();
+    let in_zone = cpu_data
+        .zone
+        .as_ref()
+        .map_or(false, |running| Arc::ptr_eq(running, &zone));
+    zone.write().state = ZoneState::ShuttingDown;
+    let zone_r = zone.read();
+    let zone_id = zone_r.id;
+    info!("shutting down zone {}", zone_id);
+
+    zone_stop_cpus(&zone_r, Some(cpu_data.id).filter(|_| in_zone));
+    zone_r.cpu_set.iter().for_each(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        zone_cpu_data(cpu_id, zone_id).zone = None;
+    });
+    zone_r.arch_irqchip_reset();
+
+    drop(zone_r);
+    drop(zone);
+    remove_zone(zone_id);
+}
+
+/// Park every cpu of `zone` in `cpu_set` in the hypervisor and wait until
+/// all of them have stopped running guest code. Must be called without the
+/// zone lock, its cpus may need it to finish the trap they are handling.
+/// Powered-off cpus have nothing to stop. Fails with `EBUSY` if the zone
+/// leaves the paused state meanwhile, e.g. on a guest-initiated reset.
+pub fn zone_pause(zone: &RwLock<Zone>, cpu_set: CpuSet) -> HvResult {
+    let zone_id = zone.read().id;
+    trace!("pausing cpu_set = {:#x?}", cpu_set);
+    cpu_set.iter().for_each(|cpu_id| {
+        trace!("try to pause cpu_id = {:#x?}", cpu_id);
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        zone_cpu_data(cpu_id, zone_id)
+            .pause_req
+            .store(true, Ordering::Release);
+        send_zone_event(cpu_id, zone_id, SGI_IPI_ID as _, IPI_EVENT_PAUSE);
+    });
+    while cpu_set.iter().any(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        let cpu_data = zone_cpu_data(cpu_id, zone_id);
+        // a shutdown or reset releases the cpu again
+        cpu_data.arch_cpu.power_on
+            && cpu_data.pause_req.load(Ordering::Acquire)
+            && !cpu_data.paused.load(Ordering::Acquire)
+    }) {
+        if zone.read().state != ZoneState::Paused {
+            return hv_result_err!(EBUSY, format!("zone {} stopped pausing", zone_id));
+        }
+    }
+    Ok(())
+}
+
+/// Let the parked cpus of zone `zone_id` continue from their saved guest
+/// context, called without the zone lock as for `zone_pause`.
+pub fn zone_resume(zone_id: usize, cpu_set: CpuSet) {
+    trace!("resuming cpu_set = {:#x?}", cpu_set);
+    cpu_set.iter().for_each(|cpu_id| {
+        trace!("try to resume cpu_id = {:#x?}", cpu_id);
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        zone_cpu_data(cpu_id, zone_id)
+            .pause_req
+            .store(false, Ordering::Release);
+    });
+    while cpu_set.iter().any(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        zone_cpu_data(cpu_id, zone_id)
+            .paused
+            .load(Ordering::Acquire)
+    }) {}
+}
+
+/// Restart `zone` from its entry point, keeping its stage-2 mappings and
+/// assigned devices. All vcpus are sent back to the parking state, the
+/// virtual irqchip state is reset and the boot cpu is woken up again.
+/// When called on a cpu of the zone itself (a guest-initiated reset), the
+/// current cpu is parked as well and this function does not return.
+pub fn zone_reboot(zone: Arc<RwLock<Zone>>, zero_ram: bool) -> HvResult {
+    // the guest starts again with all of its RAM. This is the only step that
+    // can fail, it is done first so that an error leaves the zone running.
+    balloon_zone_deflate(&zone)?;
+
+    let cpuid = this_cpu_id();
+    let zone_r = zone.read();
+    let zone_id = zone_r.id;
+    info!("rebooting zone {}", zone_id);
+    let in_zone = zone_r.owns_cpu(cpuid);
+
+    zone_stop_cpus(&zone_r, Some(cpuid));
+    drop(zone_r);
+
+    let mut zone_w = zone.write();
+    zone_w.state = ZoneState::Running;
+    zone_w.start_time_ms = time_ms();
+    zone_w.fault_reason.store(0, Ordering::Release);
+    watchdog_reset(zone_id);
+    zone_w.arch_irqchip_reset();
+    if zero_ram {
+        zone_w.zero_ram();
+    }
+    let boot_cpu = zone_w.boot_cpu();
+    zone_w.cpu_set.iter().for_each(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        {
+            let mut cpu_data = zone_cpu_data(cpu_id, zone_id);
+            cpu_data.cpu_on_entry = if cpu_id == boot_cpu {
+                zone_w.boot.entry_point
+            } else {
+                INVALID_ADDRESS
+            };
+            cpu_data.dtb_ipa = zone_w.boot.dtb_ipa;
+        }
+        // the pending virtio irqs there belong to the zone running on the cpu
+        if get_cpu_data(cpu_id)
+            .zone
+            .as_ref()
+            .map_or(false, |running| Arc::ptr_eq(running, &zone))
+        {
+            VIRTIO_IRQS.lock().remove(&cpu_id);
+        }
+    });
+    drop(zone_w);
+    // don't keep a reference across the noreturn path below
+    drop(zone);
+
+    send_zone_event(boot_cpu, zone_id, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+    notify_root(zone_id, ZONE_EVENT_STARTED, 0);
+    if in_zone {
+        this_cpu_data().arch_cpu.idle();
+    }
+    Ok(())
+}
+
+/// Stop `zone` after a fault that can't be reflected back into the guest,
+/// and report `reason` to the root zone. The zone is kept, so the root zone
+/// can still reboot or shut it down. When called on a cpu of the zone, that
+/// cpu is parked as well and this function does not return. A fault of the
+/// root zone still brings the hypervisor down.
+pub fn zone_crash(zone: Arc<RwLock<Zone>>, reason: u64) {
+    let cpu_data = this_cpu_data();
+    let in_zone = cpu_data
+        .zone
+        .as_ref()
+        .map_or(false, |running| Arc::ptr_eq(running, &zone));
+    let zone_r = zone.read();
+    let zone_id = zone_r.id;
+    if zone_id == 0 {
+        panic!("root zone fault on cpu {}, reason {}", cpu_data.id, reason);
+    }
+    error!(
+        "zone {} faulted on cpu {}, reason {}, stopping it",
+        zone_id, cpu_data.id, reason
+    );
+    zone_r.fault_reason.store(reason, Ordering::Release);
+    zone_r.cpu_set.iter().for_each(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        zone_cpu_data(cpu_id, zone_id).cpu_on_entry = INVALID_ADDRESS;
+        if !(in_zone && cpu_id == cpu_data.id) {
+            send_zone_event(cpu_id, zone_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+        }
+    });
+    drop(zone_r);
+    // don't keep a reference across the noreturn path below
+    drop(zone);
+
+    notify_root(zone_id, ZONE_EVENT_CRASHED, reason);
+    if in_zone {
+        cpu_data.arch_cpu.idle();
+    }
+}
+
+/// Stop the zone running on this cpu after a guest fault, see `zone_crash`.
+pub fn zone_fault(reason: u64) -> ! {
+    zone_crash(this_zone(), reason);
+    unreachable!();
+}
+
+/// Move the powered-off physical cpu `cpu_id` to `target`, returning the vcpu
+/// id it gets there. The guest finds it as a hot-pluggable cpu and brings it
+/// up with its usual cpu-on call. A cpu without a zone can be handed out as
+/// well, a cpu of another zone must not be that zone's boot cpu.
+pub fn zone_move_cpu(cpu_id: usize, target: Arc<RwLock<Zone>>) -> HvResult<usize> {
+    let cpu_data = get_cpu_data(cpu_id);
+    let source = {
+        let _lock = cpu_data.ctrl_lock.lock();
+        cpu_data.zone.clone()
+    };
+    if source
+        .as_ref()
+        .map_or(false, |source| Arc::ptr_eq(source, &target))
+    {
+        return hv_result_err!(EINVAL, format!("cpu {} is already in the zone", cpu_id));
+    }
+    let target_dtb_ipa = target.read().boot.dtb_ipa;
+    // holding the source zone keeps its guest from translating new cpu-on
+    // requests until the cpu is gone from it
+    let mut source_w = source.as_ref().map(|zone| zone.write());
+    if let Some(source_w) = source_w.as_ref() {
+        if source_w.boot_cpu() == cpu_id {
+            return hv_result_err!(EBUSY, format!("cpu {} boots zone {}", cpu_id, source_w.id));
+        }
+    }
+
+    let lock = cpu_data.ctrl_lock.lock();
+    let unchanged = match (cpu_data.zone.as_ref(), source.as_ref()) {
+        (Some(now), Some(before)) => Arc::ptr_eq(now, before),
+        (None, None) => true,
+        _ => false,
+    };
+    if !unchanged {
+        return hv_result_err!(EBUSY, format!("cpu {} changed zones", cpu_id));
+    }
+    #[cfg(feature = "vcpu_sched")]
+    if crate::scheduler::is_shared(cpu_id) {
+        return hv_result_err!(EBUSY, format!("cpu {} is shared by several zones", cpu_id));
+    }
+    if cpu_data.arch_cpu.power_on || cpu_data.pause_req.load(Ordering::Acquire) {
+        return hv_result_err!(EBUSY, format!("cpu {} is not offline", cpu_id));
+    }
+    cpu_data.zone = Some(target.clone());
+    cpu_data.boot_cpu = false;
+    cpu_data.cpu_on_entry = INVALID_ADDRESS;
+    cpu_data.dtb_ipa = target_dtb_ipa;
+    #[cfg(feature = "vcpu_sched")]
+    {
+        cpu_data.sched_priority = 0;
+    }
+    drop(lock);
+
+    if let Some(source_w) = source_w.as_mut() {
+        source_w.remove_cpu(cpu_id);
+        // the irqs the guest routed to the cpu go to its boot cpu now
+        let boot_cpu = source_w.boot_cpu();
+        source_w.arch_irqchip_reroute(cpu_id, boot_cpu);
+        info!("cpu {} left zone {}", cpu_id, source_w.id);
+    }
+    drop(source_w);
+
+    let mut target_w = target.write();
+    let vcpu = target_w.add_cpu(cpu_id);
+    info!(
+        "cpu {} joined zone {} as vcpu {}",
+        cpu_id, target_w.id, vcpu
+    );
+    Ok(vcpu)
+}
+
+/// `HvZoneMemOp::flags`
+pub const ZONE_MEM_READ: u64 = 1 << 0;
+pub const ZONE_MEM_WRITE: u64 = 1 << 1;
+pub const ZONE_MEM_EXECUTE: u64 = 1 << 2;
+/// device memory, mapped uncached
+pub const ZONE_MEM_IO: u64 = 1 << 3;
+
+/// A change to a running zone's guest physical memory, passed by the root
+/// zone with `HvZoneMemMap`, `HvZoneMemUnmap` and `HvZoneMemProtect`.
+/// `physical_start` is only used by map, `flags` is ignored by unmap.
+/// `HvZoneBalloonInflate` and `HvZoneBalloonDeflate` only use the guest
+/// physical range.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneMemOp {
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub size: u64,
+    pub flags: u64,
+}
+
+impl HvZoneMemOp {
+    fn mem_flags(&self) -> HvResult<MemFlags> {
+        let access = ZONE_MEM_READ | ZONE_MEM_WRITE | ZONE_MEM_EXECUTE;
+        if self.flags & !(access | ZONE_MEM_IO) != 0 || self.flags & access == 0 {
+            return hv_result_err!(EINVAL, format!("bad memory flags {:#x}", self.flags));
+        }
+        let mut flags = MemFlags::empty();
+        for (bit, flag) in [
+            (ZONE_MEM_READ, MemFlags::READ),
+            (ZONE_MEM_WRITE, MemFlags::WRITE),
+            (ZONE_MEM_EXECUTE, MemFlags::EXECUTE),
+            (ZONE_MEM_IO, MemFlags::IO),
+        ] {
+            if self.flags & bit != 0 {
+                flags |= flag;
+            }
+        }
+        Ok(flags)
+    }
+
+    /// The guest physical range must be page aligned and not wrap.
+    fn check_range(&self) -> HvResult {
+        let (ipa, size) = (self.virtual_start, self.size);
+        if size == 0
+            || ipa.checked_add(size).is_none()
+            || !is_aligned(ipa as _)
+            || !is_aligned(size as _)
+        {
+            return hv_result_err!(EINVAL, format!("bad memory region {:#x?}", self));
+        }
+        Ok(())
+    }
+}
+
+/// Refuse a mapping into running zone `zone_id` that is malformed or takes
+/// memory of the hypervisor or of another zone. Called by `zone_mem_map`
+/// before the zone is locked for the change.
+pub fn zone_mem_map_check(zone_id: usize, op: &HvZoneMemOp) -> HvResult {
+    op.check_range()?;
+    op.mem_flags()?;
+    let (hpa, size) = (op.physical_start, op.size);
+    if hpa.checked_add(size).is_none() || !is_aligned(hpa as _) {
+        return hv_result_err!(EINVAL, format!("bad memory region {:#x?}", op));
+    }
+    let hv_mem = hv_phys_range();
+    if ranges_overlap(
+        hpa,
+        size,
+        hv_mem.start as _,
+        (hv_mem.end - hv_mem.start) as _,
+    ) {
+        return hv_result_err!(
+            EPERM,
+            format!("memory region {:#x?} overlaps the hypervisor", op)
+        );
+    }
+    // device mappings must not reach RAM of other zones either
+    if pool_overlaps(hpa as _, size as _) {
+        return hv_result_err!(
+            EBUSY,
+            format!("memory region {:#x?} belongs to the guest memory pool", op)
+        );
+    }
+    if let Some(owner) = ZONE_LIST
+        .read()
+        .iter()
+        .map(|zone| zone.read())
+        .find(|zone| {
+            zone.id != 0
+                && zone.id != zone_id
+                && zone
+                    .boot
+                    .ram
+                    .iter()
+                    .any(|&(ram, ram_size)| ranges_overlap(hpa, size, ram as _, ram_size as _))
+        })
+    {
+        return hv_result_err!(
+            EBUSY,
+            format!("memory region {:#x?} belongs to zone {}", op, owner.id)
+        );
+    }
+    Ok(())
+}
+
+/// Map `op` into running zone `zone` if `zone_mem_map_check` allows it.
+pub fn zone_mem_map(zone: &RwLock<Zone>, op: &HvZoneMemOp) -> HvResult {
+    let _ram_lock = RAM_OWNER_LOCK.lock();
+    let zone_id = zone.read().id;
+    zone_mem_map_check(zone_id, op)?;
+    zone.write().mem_map(op)
+}
+
+impl Zone {
+    /// Map `op` into the zone while it runs, after `zone_mem_map_check`.
+    /// Mapped RAM is owned by the zone until it is unmapped.
+    pub fn mem_map(&mut self, op: &HvZoneMemOp) -> HvResult {
+        self.map_ipa(op)?;
+        if op.flags & ZONE_MEM_IO == 0 {
+            self.boot.ram.push((op.physical_start as _, op.size as _));
+        }
+        Ok(())
+    }
+
+    /// Unmap `op` from the zone while it runs, the range may be part of a
+    /// larger mapping. The zone gives up the RAM unmapped.
+    pub fn mem_unmap(&mut self, op: &HvZoneMemOp) -> HvResult {
+        for (start, size) in self.unmap_ipa(op)? {
+            self.forget_ram(start, size);
+        }
+        Ok(())
+    }
+
+    /// `mem_map` without taking ownership of the RAM, for the balloon,
+    /// which hands the zone's own RAM back.
+    pub fn map_ipa(&mut self, op: &HvZoneMemOp) -> HvResult {
+        let flags = op.mem_flags()?;
+        let (ipa, size) = (op.virtual_start, op.size);
+        if self
+            .mmio
+            .iter()
+            .any(|mmio| ranges_overlap(ipa, size, mmio.region.start as _, mmio.region.size as _))
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("memory region {:#x?} overlaps an emulated device", op)
+            );
+        }
+        // fails on any overlap with what is mapped already
+        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+            ipa as GuestPhysAddr,
+            op.physical_start as _,
+            size as _,
+            flags,
+        ))?;
+        self.mem_regions.push(HvConfigMemoryRegion {
+            mem_type: if flags.contains(MemFlags::IO) {
+                MEM_TYPE_IO
+            } else {
+                MEM_TYPE_RAM
+            },
+            physical_start: op.physical_start,
+            virtual_start: ipa,
+            size,
+        });
+        Ok(())
+    }
+
+    /// `mem_unmap` keeping ownership of the RAM, for the balloon, which
+    /// only lends it out. Returns the host RAM ranges unmapped.
+    pub fn unmap_ipa(&mut self, op: &HvZoneMemOp) -> HvResult<Vec<(usize, usize)>> {
+        op.check_range()?;
+        let (ipa, size) = (op.virtual_start, op.size);
+        self.gpm.unmap_range(ipa as _, size as _)?;
+        let end = ipa + size;
+        let mut regions = Vec::new();
+        let mut ram = Vec::new();
+        for region in self.mem_regions.drain(..) {
+            let region_end = region.virtual_start + region.size;
+            if region.mem_type == MEM_TYPE_VIRTIO
+                || !ranges_overlap(ipa, size, region.virtual_start, region.size)
+            {
+                regions.push(region);
+                continue;
+            }
+            if region.mem_type == MEM_TYPE_RAM {
+                let from = ipa.max(region.virtual_start);
+                let to = end.min(region_end);
+                ram.push((
+                    (region.physical_start + (from - region.virtual_start)) as usize,
+                    (to - from) as usize,
+                ));
+            }
+            if region.virtual_start < ipa {
+                regions.push(HvConfigMemoryRegion {
+                    size: ipa - region.virtual_start,
+                    ..region
+                });
+            }
+            if end < region_end {
+                regions.push(HvConfigMemoryRegion {
+                    physical_start: region.physical_start + (end - region.virtual_start),
+                    virtual_start: end,
+                    size: region_end - end,
+                    ..region
+                });
+            }
+        }
+        self.mem_regions = regions;
+        Ok(ram)
+    }
+
+    /// Drop host RAM `[start, start + size)` from what the zone owns.
+    fn forget_ram(&mut self, start: usize, size: usize) {
+        let end = start + size;
+        let mut ram = Vec::new();
+        for &(ram_start, ram_size) in self.boot.ram.iter() {
+            let ram_end = ram_start + ram_size;
+            if end <= ram_start || ram_end <= start {
+                ram.push((ram_start, ram_size));
+                continue;
+            }
+            if ram_start < start {
+                ram.push((ram_start, start - ram_start));
+            }
+            if end < ram_end {
+                ram.push((end, ram_end - end));
+            }
+        }
+        self.boot.ram = ram;
+    }
+
+    /// Replace the flags of `op` in the zone while it runs, the range may be
+    /// part of a larger mapping.
+    pub fn mem_protect(&mut self, op: &HvZoneMemOp) -> HvResult {
+        op.check_range()?;
+        let flags = op.mem_flags()?;
+        self.gpm.protect(op.virtual_start as _, op.size as _, flags)
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ZoneInfo {
@@ -235,3 +1324,142 @@ pub struct ZoneInfo {
     cpus: u64,
     name: [u8; CONFIG_NAME_MAXLEN],
 }
+
+/// Layout version of `HvZoneStatus`, later versions only append fields.
+/// 2: `fault_reason`
+/// 3: `total_memory_regions`, `total_interrupts`, `total_pci_devs`, `total_ivcs`
+pub const ZONE_STATUS_VERSION: u32 = 3;
+pub const ZONE_STATUS_MAX_CPUS: usize = 64;
+
+/// run states in `HvZoneStatus::state`
+pub const ZONE_STATUS_BOOTING: u32 = 0;
+pub const ZONE_STATUS_RUNNING: u32 = 1;
+pub const ZONE_STATUS_PAUSED: u32 = 2;
+pub const ZONE_STATUS_SHUTTING_DOWN: u32 = 3;
+/// a guest fault stopped the zone, see `fault_reason`
+pub const ZONE_STATUS_CRASHED: u32 = 4;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HvCpuStatus {
+    pub cpu_id: u32,
+    pub vcpu_id: u32,
+    pub power_on: u32,
+    pub paused: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct HvZoneStatus {
+    /// set by the hypervisor to the layout version it filled in
+    pub version: u32,
+    /// size of the caller's buffer on input, bytes filled in on output
+    pub size: u32,
+    pub zone_id: u32,
+    pub state: u32,
+    pub name: [u8; CONFIG_NAME_MAXLEN],
+    pub uptime_ms: u64,
+    pub num_cpus: u32,
+    pub num_memory_regions: u32,
+    pub num_interrupts: u32,
+    pub num_pci_devs: u32,
+    pub num_ivcs: u32,
+    pub reserved: u32,
+    pub cpus: [HvCpuStatus; ZONE_STATUS_MAX_CPUS],
+    pub memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
+    pub interrupts: [u32; CONFIG_MAX_INTERRUPTS],
+    pub pci_devs: [u64; CONFIG_MAX_PCI_DEV],
+    pub ivc_ids: [u32; CONFIG_MAX_IVC_CONGIGS],
+    pub fault_reason: u64,
+    /// the zone's real counts, the arrays above only hold the first
+    /// `num_*` of them
+    pub total_memory_regions: u32,
+    pub total_interrupts: u32,
+    pub total_pci_devs: u32,
+    pub total_ivcs: u32,
+}
+
+impl Zone {
+    /// Snapshot the zone for `HvZoneStatus`.
+    pub fn status(&self) -> HvZoneStatus {
+        let mut status = HvZoneStatus {
+            version: ZONE_STATUS_VERSION,
+            size: core::mem::size_of::<HvZoneStatus>() as _,
+            zone_id: self.id as _,
+            state: match self.state {
+                ZoneState::Running => ZONE_STATUS_RUNNING,
+                ZoneState::Paused => ZONE_STATUS_PAUSED,
+                ZoneState::ShuttingDown => ZONE_STATUS_SHUTTING_DOWN,
+            },
+            name: self.name,
+            uptime_ms: time_ms() - self.start_time_ms,
+            num_cpus: 0,
+            num_memory_regions: 0,
+            num_interrupts: 0,
+            num_pci_devs: 0,
+            num_ivcs: 0,
+            reserved: 0,
+            cpus: [HvCpuStatus::default(); ZONE_STATUS_MAX_CPUS],
+            memory_regions: [HvConfigMemoryRegion::new_empty(); CONFIG_MAX_MEMORY_REGIONS],
+            interrupts: [0; CONFIG_MAX_INTERRUPTS],
+            pci_devs: [0; CONFIG_MAX_PCI_DEV],
+            ivc_ids: [0; CONFIG_MAX_IVC_CONGIGS],
+            fault_reason: self.fault_reason.load(Ordering::Acquire),
+            total_memory_regions: self.mem_regions.len() as _,
+            total_interrupts: self.irqs.len() as _,
+            total_pci_devs: 0,
+            total_ivcs: 0,
+        };
+        if status.fault_reason != 0 {
+            status.state = ZONE_STATUS_CRASHED;
+        }
+        for (slot, cpu_id) in status.cpus.iter_mut().zip(self.cpu_set.iter()) {
+            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+            let cpu_data = zone_cpu_data(cpu_id, self.id);
+            *slot = HvCpuStatus {
+                cpu_id: cpu_id as _,
+                vcpu_id: self.cpu_to_vcpu(cpu_id).unwrap() as _,
+                power_on: cpu_data.arch_cpu.power_on as _,
+                paused: cpu_data.paused.load(Ordering::Acquire) as _,
+            };
+            status.num_cpus += 1;
+        }
+        // the boot cpu has not entered the guest yet
+        if status.state == ZONE_STATUS_RUNNING
+            && status
+                .cpus
+                .iter()
+                .any(|cpu| cpu.vcpu_id == 0 && cpu.power_on == 0)
+        {
+            status.state = ZONE_STATUS_BOOTING;
+        }
+        for (slot, region) in status
+            .memory_regions
+            .iter_mut()
+            .zip(self.mem_regions.iter())
+        {
+            *slot = *region;
+            status.num_memory_regions += 1;
+        }
+        for (slot, irq) in status.interrupts.iter_mut().zip(self.irqs.iter()) {
+            *slot = *irq;
+            status.num_interrupts += 1;
+        }
+        let pci_devs = self.pciroot.alloc_devs();
+        status.total_pci_devs = pci_devs.len() as _;
+        for (slot, bdf) in status.pci_devs.iter_mut().zip(pci_devs.iter()) {
+            *slot = *bdf as _;
+            status.num_pci_devs += 1;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            let ivc_ids = crate::ivc::zone_ivc_ids(self.id);
+            status.total_ivcs = ivc_ids.len() as _;
+            for (slot, ivc_id) in status.ivc_ids.iter_mut().zip(ivc_ids) {
+                *slot = ivc_id;
+                status.num_ivcs += 1;
+            }
+        }
+        status
+    }
+}
@@ -1,4 +1,6 @@
 use super::*;
+use crate::error::HvErrorNum;
+use crate::notify::ZONE_CRASH_UNHANDLED_TRAP;
 use alloc::sync::Arc;
 use spin::RwLock;
 
@@ -16,3 +18,310 @@ fn test_add_and_remove_zone() {
     }
     assert_eq!(ZONE_LIST.read().len(), zone_count_before);
 }
+
+#[test_case]
+fn test_vcpu_mapping() {
+    let mut zone = Zone::new(0, &[0; CONFIG_NAME_MAXLEN]);
+    zone.add_cpu(1);
+    zone.add_cpu(3);
+    assert_eq!(zone.cpu_to_vcpu(1), Some(0));
+    assert_eq!(zone.cpu_to_vcpu(3), Some(1));
+    assert_eq!(zone.cpu_to_vcpu(2), None);
+    assert_eq!(zone.vcpu_to_cpu(0), Some(1));
+    assert_eq!(zone.vcpu_to_cpu(1), Some(3));
+    assert_eq!(zone.vcpu_to_cpu(2), None);
+}
+
+#[test_case]
+fn test_vcpu_hotplug() {
+    let mut zone = Zone::new(0, &[0; CONFIG_NAME_MAXLEN]);
+    zone.add_cpu(1);
+    zone.add_cpu(3);
+    zone.remove_cpu(1);
+    assert!(!zone.owns_cpu(1));
+    assert_eq!(zone.vcpu_to_cpu(0), None);
+    assert_eq!(zone.cpu_to_vcpu(3), Some(1));
+    // the new cpu fills the hole, cpu 3 keeps its vcpu id
+    assert_eq!(zone.add_cpu(2), 0);
+    assert_eq!(zone.vcpu_to_cpu(0), Some(2));
+    assert_eq!(zone.vcpu_to_cpu(1), Some(3));
+}
+
+#[test_case]
+fn test_zone_status() {
+    let mut zone = Zone::new(7, &[0; CONFIG_NAME_MAXLEN]);
+    zone.irqs = vec![33, 34];
+    zone.mem_regions = vec![HvConfigMemoryRegion::new_empty()];
+    let status = zone.status();
+    assert_eq!(status.version, ZONE_STATUS_VERSION);
+    assert_eq!(status.zone_id, 7);
+    assert_eq!(status.state, ZONE_STATUS_RUNNING);
+    assert_eq!(status.num_cpus, 0);
+    assert_eq!(status.num_memory_regions, 1);
+    assert_eq!(status.num_interrupts, 2);
+    assert_eq!(&status.interrupts[..2], &[33, 34]);
+    assert_eq!(status.fault_reason, 0);
+    zone.fault_reason
+        .store(ZONE_CRASH_UNHANDLED_TRAP, Ordering::Relaxed);
+    let status = zone.status();
+    assert_eq!(status.state, ZONE_STATUS_CRASHED);
+    assert_eq!(status.fault_reason, ZONE_CRASH_UNHANDLED_TRAP);
+}
+
+#[test_case]
+fn test_zone_status_counts_past_arrays() {
+    let mut zone = Zone::new(7, &[0; CONFIG_NAME_MAXLEN]);
+    zone.mem_regions = vec![HvConfigMemoryRegion::new_empty(); CONFIG_MAX_MEMORY_REGIONS + 4];
+    zone.irqs = (0..CONFIG_MAX_INTERRUPTS as u32 + 2).collect();
+    let status = zone.status();
+    assert_eq!(status.num_memory_regions, CONFIG_MAX_MEMORY_REGIONS as u32);
+    assert_eq!(
+        status.total_memory_regions,
+        CONFIG_MAX_MEMORY_REGIONS as u32 + 4
+    );
+    assert_eq!(status.num_interrupts, CONFIG_MAX_INTERRUPTS as u32);
+    assert_eq!(status.total_interrupts, CONFIG_MAX_INTERRUPTS as u32 + 2);
+    assert_eq!(status.total_pci_devs, 0);
+}
+
+fn test_config(cpus: u64, region: HvConfigMemoryRegion, irq: u32) -> ZoneConfig {
+    use crate::config::{HvIvcConfig, HvPciConfig, HvZoneConfig};
+    let mut regions = [HvConfigMemoryRegion::new_empty(); CONFIG_MAX_MEMORY_REGIONS];
+    regions[0] = region;
+    let mut irqs = [0; CONFIG_MAX_INTERRUPTS];
+    irqs[0] = irq;
+    let config = HvZoneConfig::new(
+        42,
+        cpus,
+        1,
+        regions,
+        1,
+        irqs,
+        0,
+        [HvIvcConfig::default(); CONFIG_MAX_IVC_CONGIGS],
+        0x5000_0000,
+        0x5000_0000,
+        0x1000,
+        0x5080_0000,
+        0x1000,
+        [0; CONFIG_NAME_MAXLEN],
+        unsafe { core::mem::zeroed() },
+        HvPciConfig::new_empty(),
+        0,
+        [0; CONFIG_MAX_PCI_DEV],
+    );
+    ZoneConfig::from_legacy(&config).unwrap()
+}
+
+fn ram(start: u64, size: u64) -> HvConfigMemoryRegion {
+    HvConfigMemoryRegion {
+        mem_type: MEM_TYPE_RAM,
+        physical_start: start,
+        virtual_start: start,
+        size,
+    }
+}
+
+fn check_errno(config: &ZoneConfig, num: HvErrorNum) {
+    assert_eq!(
+        zone_config_check(config).unwrap_err().code(),
+        -(num as isize)
+    );
+}
+
+#[test_case]
+fn test_zone_config_check() {
+    let good = ram(0x5000_0000, 0x100_0000);
+    assert!(zone_config_check(&test_config(1 << 1, good, 40)).is_ok());
+
+    check_errno(&test_config(0, good, 40), HvErrorNum::EINVAL);
+    check_errno(&test_config(1 << MAX_CPU_NUM, good, 40), HvErrorNum::EINVAL);
+    check_errno(
+        &test_config(1 << 1, ram(0x5000_0000, 0x100_0800), 40),
+        HvErrorNum::EINVAL,
+    );
+    // kernel, dtb and entry point outside RAM
+    check_errno(
+        &test_config(1 << 1, ram(0x5100_0000, 0x100_0000), 40),
+        HvErrorNum::EINVAL,
+    );
+    let hv_start = hv_phys_range().start as u64 & !(PAGE_SIZE as u64 - 1);
+    check_errno(
+        &test_config(1 << 1, ram(hv_start, 0x100_0000), 40),
+        HvErrorNum::EPERM,
+    );
+    // the root zone's zone event irq is only ever injected
+    let mut root = test_config(1 << 1, good, IRQ_ZONE_EVENT as _);
+    root.zone_id = 0;
+    check_errno(&root, HvErrorNum::EINVAL);
+
+    let mut owner = Zone::new(43, &[0; CONFIG_NAME_MAXLEN]);
+    owner.irq_bitmap[1] |= 1 << 8;
+    owner.boot.ram = vec![(0x5000_0000, 0x1000)];
+    ZONE_LIST.write().push(Arc::new(RwLock::new(owner)));
+    check_errno(&test_config(1 << 1, good, 40), HvErrorNum::EBUSY);
+    check_errno(
+        &test_config(1 << 1, ram(0x5000_0000, 0x100_0000), 41),
+        HvErrorNum::EBUSY,
+    );
+    remove_zone(43);
+}
+
+#[test_case]
+fn test_zone_config_check_pool_ram() {
+    let auto = HvConfigMemoryRegion {
+        physical_start: MEM_PHYS_AUTO,
+        ..ram(0x4000_0000, 0x100_0000)
+    };
+    let mut config = test_config(1 << 1, auto, 40);
+    // entry point and images are placed by ipa, the images are staged
+    // at their load paddrs
+    config.entry_point = 0x4000_0000;
+    config.kernel_load_ipa = 0x4000_0000;
+    config.dtb_load_ipa = 0x4080_0000;
+    assert!(zone_config_check(&config).is_ok());
+
+    #[cfg(not(target_arch = "loongarch64"))]
+    {
+        config.dtb_load_ipa = 0x4100_0000;
+        check_errno(&config, HvErrorNum::EINVAL);
+        config.dtb_load_ipa = 0x4080_0000;
+    }
+
+    config.dtb_load_paddr = hv_phys_range().start as _;
+    check_errno(&config, HvErrorNum::EPERM);
+    config.dtb_load_paddr = 0x5080_0000;
+
+    config.zone_id = 0;
+    check_errno(&config, HvErrorNum::EINVAL);
+}
+
+#[test_case]
+fn test_zone_rollback() {
+    static UNDONE: spin::Mutex<Vec<usize>> = spin::Mutex::new(Vec::new());
+    let mut rollback = ZoneRollback::new();
+    for step in 0..3 {
+        rollback.push(move || UNDONE.lock().push(step));
+    }
+    drop(rollback);
+    assert_eq!(*UNDONE.lock(), [2, 1, 0]);
+
+    let mut rollback = ZoneRollback::new();
+    rollback.push(|| UNDONE.lock().push(3));
+    rollback.commit();
+    assert_eq!(UNDONE.lock().len(), 3);
+}
+
+#[test_case]
+fn test_id_allocator() {
+    let mut ids = IdAllocator::new(3);
+    assert!(ids.reserve(1).is_ok());
+    assert_eq!(
+        ids.reserve(1).unwrap_err().code(),
+        -(HvErrorNum::EEXIST as isize)
+    );
+    assert_eq!(
+        ids.reserve(3).unwrap_err().code(),
+        -(HvErrorNum::EINVAL as isize)
+    );
+    assert_eq!(ids.alloc().unwrap(), 0);
+    assert_eq!(ids.alloc().unwrap(), 2);
+    assert_eq!(
+        ids.alloc().unwrap_err().code(),
+        -(HvErrorNum::ENOMEM as isize)
+    );
+    ids.free(1);
+    ids.free(1);
+    assert_eq!(ids.alloc().unwrap(), 1);
+}
+
+#[test_case]
+fn test_gpm_frees_page_tables() {
+    use crate::memory::{MemFlags, MemoryRegion};
+    let mut gpm = new_s2_memory_set();
+    let empty = gpm.page_table_frames();
+    gpm.insert(MemoryRegion::new_with_offset_mapper(
+        0x4000_1000,
+        0x5000_1000,
+        PAGE_SIZE,
+        MemFlags::READ | MemFlags::WRITE,
+    ))
+    .unwrap();
+    assert!(gpm.page_table_frames() > empty);
+    gpm.delete(0x4000_1000).unwrap();
+    assert_eq!(gpm.page_table_frames(), empty);
+}
+
+// loongarch64 maps 4K pages only
+#[cfg(not(target_arch = "loongarch64"))]
+#[test_case]
+fn test_gpm_split_and_merge() {
+    use crate::arch::paging::PageSize;
+    use crate::memory::{MemFlags, MemoryRegion};
+    let rw = MemFlags::READ | MemFlags::WRITE;
+    let mut gpm = new_s2_memory_set();
+    gpm.insert(MemoryRegion::new_with_offset_mapper(
+        0x4020_0000,
+        0x5020_0000,
+        0x20_0000,
+        rw,
+    ))
+    .unwrap();
+    let query = |gpm: &MemorySet<Stage2PageTable>, ipa: usize| unsafe {
+        gpm.page_table_query(ipa).unwrap()
+    };
+    assert_eq!(query(&gpm, 0x4020_1000).2, PageSize::Size2M);
+
+    gpm.protect(0x4020_1000, PAGE_SIZE, MemFlags::READ).unwrap();
+    let (paddr, flags, size) = query(&gpm, 0x4020_1000);
+    assert_eq!((paddr, size), (0x5020_1000, PageSize::Size4K));
+    assert!(!flags.contains(MemFlags::WRITE));
+    assert!(query(&gpm, 0x4020_2000).1.contains(MemFlags::WRITE));
+
+    gpm.protect(0x4020_1000, PAGE_SIZE, rw).unwrap();
+    assert_eq!(query(&gpm, 0x4020_1000).2, PageSize::Size2M);
+    gpm.delete(0x4020_0000).unwrap();
+    assert!(gpm.delete(0x4020_1000).is_err());
+}
+
+#[test_case]
+fn test_zone_mem_ops() {
+    let op = |hpa: u64, ipa: u64, size: u64, flags: u64| HvZoneMemOp {
+        physical_start: hpa,
+        virtual_start: ipa,
+        size,
+        flags,
+    };
+    let rw = ZONE_MEM_READ | ZONE_MEM_WRITE;
+    let check = |op: HvZoneMemOp, num: HvErrorNum| {
+        assert_eq!(
+            zone_mem_map_check(44, &op).unwrap_err().code(),
+            -(num as isize)
+        );
+    };
+    check(op(0x5000_0000, 0x4000_0000, 0x1800, rw), HvErrorNum::EINVAL);
+    check(op(0x5000_0000, 0x4000_0000, 0x1000, 0), HvErrorNum::EINVAL);
+    let hv_start = hv_phys_range().start as u64 & !(PAGE_SIZE as u64 - 1);
+    check(op(hv_start, 0x4000_0000, 0x1000, rw), HvErrorNum::EPERM);
+
+    let mut zone = Zone::new(44, &[0; CONFIG_NAME_MAXLEN]);
+    zone.mem_map(&op(0x5000_0000, 0x4000_0000, 0x4000, rw))
+        .unwrap();
+    assert!(zone
+        .mem_map(&op(0x5100_0000, 0x4000_2000, 0x1000, rw))
+        .is_err());
+    assert_eq!(zone.boot.ram, [(0x5000_0000, 0x4000)]);
+    zone.mem_unmap(&op(0, 0x4000_1000, 0x1000, 0)).unwrap();
+    assert_eq!(zone.mem_regions.len(), 2);
+    assert_eq!(
+        zone.boot.ram,
+        [(0x5000_0000, 0x1000), (0x5000_2000, 0x2000)]
+    );
+    assert!(unsafe { zone.gpm.page_table_query(0x4000_1000) }.is_err());
+    let (paddr, _, _) = unsafe { zone.gpm.page_table_query(0x4000_2000) }.unwrap();
+    assert_eq!(paddr, 0x5000_2000);
+    zone.mem_protect(&op(0, 0x4000_2000, 0x1000, ZONE_MEM_READ))
+        .unwrap();
+    let (_, flags, _) = unsafe { zone.gpm.page_table_query(0x4000_2000) }.unwrap();
+    assert!(!flags.contains(MemFlags::WRITE));
+}